@@ -0,0 +1,8 @@
+include("IncludedChild.lua")
+root=
+{
+	foo=
+	{
+		bar=1.0,
+	},
+}
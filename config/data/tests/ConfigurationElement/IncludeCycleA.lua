@@ -0,0 +1,5 @@
+include("IncludeCycleB.lua")
+root=
+{
+	foo=true,
+}
@@ -0,0 +1,5 @@
+include("IncludeCycleA.lua")
+root=
+{
+	bar=true,
+}
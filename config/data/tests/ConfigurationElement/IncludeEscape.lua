@@ -0,0 +1,8 @@
+include("../Outside.lua")
+root=
+{
+	foo=
+	{
+		bar=1.0,
+	},
+}
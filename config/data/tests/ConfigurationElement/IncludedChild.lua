@@ -0,0 +1,9 @@
+root=
+{
+	foo=
+	{
+		bar=3.0,
+		baz="wibble",
+	},
+	qux=2,
+}
@@ -0,0 +1,6 @@
+while true do
+end
+root=
+{
+	foo=true,
+}
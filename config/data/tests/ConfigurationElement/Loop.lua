@@ -0,0 +1,8 @@
+local total = 0
+for i = 1, 10000 do
+	total = total + i
+end
+root=
+{
+	total=total,
+}
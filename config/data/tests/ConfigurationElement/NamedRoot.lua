@@ -0,0 +1,8 @@
+config=
+{
+	foo=
+	{
+		bar=1.0,
+		baz="wibble",
+	}
+}
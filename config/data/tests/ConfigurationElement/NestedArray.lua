@@ -0,0 +1,14 @@
+root=
+{
+	foo=
+	{
+		true,
+		{
+			{
+				10,
+				20
+			},
+			"spoo"
+		}
+	}
+}
@@ -0,0 +1,9 @@
+root=
+{
+	foo=
+	{
+		bar=2.0,
+		baz="wibble",
+	},
+	qux=1,
+}
@@ -0,0 +1 @@
+dofile("NestedElement.lua")
@@ -0,0 +1,4 @@
+root=
+{
+	foo=io.open("NestedElement.lua"):read("a"),
+}
@@ -0,0 +1,10 @@
+root=
+{
+	threads=4,
+	mode="fast",
+	limits=
+	{
+		retries=50,
+		level="extreme",
+	},
+}
@@ -0,0 +1,4 @@
+root=
+{
+	outside=3,
+}
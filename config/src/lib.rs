@@ -1,12 +1,14 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fs;
-use std::fs::exists;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::rc::Weak;
 use rstest;
 use mlua::prelude::*;
 
+#[derive(PartialEq, Debug, Clone)]
 enum VariantType {
     Nil,
     Integer(i64),
@@ -15,28 +17,271 @@ enum VariantType {
     String(String),
 }
 
+// A Lua-free copy of a scalar configuration value; None for values such as tables
+// and functions that have no VariantType.
+#[derive(PartialEq, Debug, Clone)]
 struct Variant {
     value : Option<VariantType>
 }
 
+impl From<&mlua::Value> for Variant {
+    fn from(value: &mlua::Value) -> Variant {
+        let value = match value {
+            mlua::Value::Nil => Some(VariantType::Nil),
+            mlua::Value::Integer(value) => Some(VariantType::Integer(*value)),
+            mlua::Value::Number(value) => Some(VariantType::Float(*value)),
+            mlua::Value::Boolean(value) => Some(VariantType::Boolean(*value)),
+            mlua::Value::String(value) => value.to_str().ok().map(|value| VariantType::String(value.to_string())),
+            _ => None,
+        };
+        Variant { value }
+    }
+}
+
+impl Variant {
+    pub fn to_lua_value(&self, lua: &Lua) -> mlua::Value {
+        match &self.value {
+            Some(VariantType::Integer(value)) => mlua::Value::Integer(*value),
+            Some(VariantType::Float(value)) => mlua::Value::Number(*value),
+            Some(VariantType::Boolean(value)) => mlua::Value::Boolean(*value),
+            Some(VariantType::String(value)) => lua.create_string(value).map(mlua::Value::String).unwrap_or(mlua::Value::Nil),
+            Some(VariantType::Nil) | None => mlua::Value::Nil,
+        }
+    }
+}
+
+impl PartialEq<mlua::Value> for VariantType {
+    fn eq(&self, other: &mlua::Value) -> bool {
+        match (self, other) {
+            (VariantType::Nil, mlua::Value::Nil) => true,
+            (VariantType::Integer(value), mlua::Value::Integer(other)) => value == other,
+            (VariantType::Float(value), mlua::Value::Number(other)) => value == other,
+            (VariantType::Boolean(value), mlua::Value::Boolean(other)) => value == other,
+            (VariantType::String(value), mlua::Value::String(other)) => other.to_str().map(|other| *value == *other).unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ConfigError {
+    MalformedPath,
+    IndexOutOfRange { index: usize, len: usize },
+    NotFound { remaining: String },
+    HasChildren { name: String },
+    LoadFailed { reason: String },
+    WrongType { path: String, expected: String },
+    OutOfRange { path: String, value: i64, min: i64, max: i64 },
+    UnknownValue { path: String, value: String, allowed: Vec<String> },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::MalformedPath => write!(f, "malformed path"),
+            ConfigError::IndexOutOfRange { index, len } => write!(f, "index {} out of range for {} children", index, len),
+            ConfigError::NotFound { remaining } => write!(f, "no element found for {}", remaining),
+            ConfigError::HasChildren { name } => write!(f, "cannot set a value on {} because it has children", name),
+            ConfigError::LoadFailed { reason } => write!(f, "cannot load configuration: {}", reason),
+            ConfigError::WrongType { path, expected } => write!(f, "{} is not {}", path, expected),
+            ConfigError::OutOfRange { path, value, min, max } => write!(f, "{} is {}, outside {} to {}", path, value, min, max),
+            ConfigError::UnknownValue { path, value, allowed } => write!(f, "{} is {:?}, not one of {}", path, value, allowed.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// What sort of value an element holds. Lua 5.4 keeps integers and floats apart, so 1 is an
+// Integer and 1.0 a Float. Elements with children, or no value, are tables.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ValueKind {
+    Integer,
+    Float,
+    Boolean,
+    String,
+    Table,
+    // Anything else set with set_value, such as a function.
+    Other,
+}
+
+// One difference between two configuration trees, located by absolute path.
+#[derive(PartialEq, Debug, Clone)]
+pub enum ConfigDiff {
+    Added(String),
+    Removed(String),
+    Changed { path: String, old: mlua::Value, new: mlua::Value },
+}
+
+#[derive(Clone, Copy)]
+enum PathStep<'a> {
+    Name(&'a str),
+    Wildcard,
+    Index(usize),
+}
+
+#[derive(Default)]
+struct IncludeState {
+    // Files currently being loaded, used to detect include cycles.
+    active: HashSet<PathBuf>,
+    directories: Vec<PathBuf>,
+    pending: Vec<Vec<mlua::Table>>,
+    // The global each loaded file is expected to define.
+    root_name: String,
+    // When set, files can only be included from this directory and those below it.
+    sandbox: Option<PathBuf>,
+}
+
 #[derive(Clone)]
 struct ConfigurationElement {
     name: String,
     index: i64,
     children : Vec<Rc<RefCell<ConfigurationElement>>>,
     parent : Weak<RefCell<ConfigurationElement>>,
+    // The Rc this element lives in, so lookups can hand back the live node.
+    this : Weak<RefCell<ConfigurationElement>>,
     value : mlua::Value,
 }
 
 impl ConfigurationElement {
     pub fn from_file(lua: &Lua, filename:&str) -> Option<Rc<RefCell<ConfigurationElement>>> {
-        if let Ok(_) = exists(filename) {
-            let code = fs::read_to_string(filename);
-            if let Ok(code) = code {
-                return ConfigurationElement::from_string(lua, code.as_str());
+        Self::from_file_with_root(lua, filename, "root")
+    }
+
+    // Like from_file, but builds the tree from the global table named root_name.
+    pub fn from_file_with_root(lua: &Lua, filename:&str, root_name:&str) -> Option<Rc<RefCell<ConfigurationElement>>> {
+        match Self::load_file(lua, filename, root_name, false) {
+            Ok(root) => root,
+            Err(e) => {
+                eprintln!("Error loading configuration element: {}", e);
+                None
             }
         }
-        None
+    }
+
+    // A sandboxed load only includes files from the root file's directory or below it.
+    fn load_file(lua: &Lua, filename:&str, root_name:&str, sandboxed:bool) -> LuaResult<Option<Rc<RefCell<ConfigurationElement>>>> {
+        let sandbox = match sandboxed {
+            true => fs::canonicalize(filename).ok().and_then(|path| path.parent().map(|p| p.to_path_buf())),
+            false => None,
+        };
+        let state = Rc::new(RefCell::new(IncludeState{ root_name:String::from(root_name), sandbox, ..IncludeState::default() }));
+        let result = Self::register_include(lua, &state)
+            .and_then(|_| Self::load_with_includes(lua, Path::new(filename), &state));
+        let _ = lua.globals().set("include", mlua::Value::Nil);
+        let root = result?;
+        lua.globals().set(root_name, root)?;
+        Ok(ConfigurationElement::build_tree(lua, root_name))
+    }
+
+    pub fn from_file_sandboxed(filename:&str) -> Result<(Lua, Rc<RefCell<ConfigurationElement>>), ConfigError> {
+        Self::from_file_sandboxed_with_limit(filename, 1_000_000)
+    }
+
+    // Like from_file, but in a fresh Lua with only the table, string, math and utf8 libraries and
+    // without dofile or loadfile, which gives up after running max_instructions instructions.
+    // include() still works, but only for files in the directory of filename or below it.
+    // The tree's values belong to the returned Lua, so keep it alongside.
+    pub fn from_file_sandboxed_with_limit(filename:&str, max_instructions:u32) -> Result<(Lua, Rc<RefCell<ConfigurationElement>>), ConfigError> {
+        let failed = |e:LuaError| ConfigError::LoadFailed { reason: e.to_string() };
+        let lua = Lua::new_with(LuaStdLib::TABLE | LuaStdLib::STRING | LuaStdLib::MATH | LuaStdLib::UTF8, LuaOptions::default()).map_err(failed)?;
+        for name in ["dofile", "loadfile"] {
+            lua.globals().set(name, mlua::Value::Nil).map_err(failed)?;
+        }
+        // The hook only runs every step instructions, so the limit is only checked that often.
+        let step = max_instructions.clamp(1, 1000);
+        let executed = Cell::new(0u64);
+        lua.set_hook(mlua::HookTriggers::new().every_nth_instruction(step), move |_, _| {
+            executed.set(executed.get() + step as u64);
+            if executed.get() >= max_instructions as u64 {
+                return Err(LuaError::runtime(format!("gave up after {} instructions", max_instructions)));
+            }
+            Ok(mlua::VmState::Continue)
+        }).map_err(failed)?;
+        let root = Self::load_file(&lua, filename, "root", true);
+        lua.remove_hook();
+        match root.map_err(failed)? {
+            Some(root) => Ok((lua, root)),
+            None => Err(ConfigError::LoadFailed { reason: format!("no configuration in {}", filename) }),
+        }
+    }
+
+    // Makes include("file.lua") available to chunks loaded by from_file, resolving the
+    // file relative to the directory of the file currently being loaded.
+    fn register_include(lua: &Lua, state: &Rc<RefCell<IncludeState>>) -> LuaResult<()> {
+        let state = state.clone();
+        let include = lua.create_function(move |lua, filename:String| {
+            let path = match state.borrow().directories.last() {
+                Some(directory) => directory.join(&filename),
+                None => PathBuf::from(&filename),
+            };
+            let included = Self::load_with_includes(lua, &path, &state)?;
+            if let Some(pending) = state.borrow_mut().pending.last_mut() {
+                pending.push(included);
+            }
+            Ok(())
+        })?;
+        lua.globals().set("include", include)
+    }
+
+    // Runs a file and returns its root table with the roots of any included files merged in.
+    // Values defined by the including file take precedence over included ones.
+    fn load_with_includes(lua: &Lua, path: &Path, state: &Rc<RefCell<IncludeState>>) -> LuaResult<mlua::Table> {
+        let canonical = fs::canonicalize(path).map_err(|e| LuaError::runtime(format!("cannot open {}: {}", path.display(), e)))?;
+        if let Some(sandbox) = &state.borrow().sandbox && !canonical.starts_with(sandbox) {
+            return Err(LuaError::runtime(format!("cannot include {} from outside {}", canonical.display(), sandbox.display())));
+        }
+        if state.borrow().active.contains(&canonical) {
+            return Err(LuaError::runtime(format!("include cycle detected at {}", canonical.display())));
+        }
+        let code = fs::read_to_string(&canonical).map_err(|e| LuaError::runtime(format!("cannot read {}: {}", canonical.display(), e)))?;
+        {
+            let mut state = state.borrow_mut();
+            state.active.insert(canonical.clone());
+            state.directories.push(canonical.parent().map(|p| p.to_path_buf()).unwrap_or_default());
+            state.pending.push(Vec::new());
+        }
+        let root_name = state.borrow().root_name.clone();
+        let globals = lua.globals();
+        let previous_root: mlua::Value = globals.get(root_name.as_str())?;
+        globals.set(root_name.as_str(), mlua::Value::Nil)?;
+        let result = lua.load(code.as_str()).set_name(canonical.display().to_string()).exec();
+        let root: mlua::Value = globals.get(root_name.as_str())?;
+        globals.set(root_name.as_str(), previous_root)?;
+        let included = {
+            let mut state = state.borrow_mut();
+            state.active.remove(&canonical);
+            state.directories.pop();
+            state.pending.pop().unwrap_or_default()
+        };
+        result?;
+        let root = match root {
+            mlua::Value::Table(table) => table,
+            _ => lua.create_table()?,
+        };
+        for table in included {
+            Self::merge_missing(&root, &table)?;
+        }
+        Ok(root)
+    }
+
+    fn merge_missing(target: &mlua::Table, source: &mlua::Table) -> LuaResult<()> {
+        for pair in source.pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            let existing: mlua::Value = target.raw_get(key.clone())?;
+            match (existing, value) {
+                (mlua::Value::Nil, value) => {
+                    target.raw_set(key, value)?;
+                }
+                (mlua::Value::Table(existing), mlua::Value::Table(value)) => {
+                    Self::merge_missing(&existing, &value)?;
+                }
+                _ => {
+                    // The including file wins.
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn from_string(lua : &Lua, string: &str) -> Option<Rc<RefCell<ConfigurationElement>>> {
@@ -44,7 +289,7 @@ impl ConfigurationElement {
         let result = chunk.exec();
         match result {
             Ok(()) => {
-                return ConfigurationElement::build_tree(lua);
+                return ConfigurationElement::build_tree(lua, "root");
             }
             Err(e) => {
                 eprintln!("Error loading configuration element: {}", e);
@@ -54,20 +299,24 @@ impl ConfigurationElement {
     }
 
     pub fn new(name:String, index:i64, value:mlua::Value) -> Rc<RefCell<ConfigurationElement>> {
-        let this = ConfigurationElement{
+        Rc::new_cyclic(|this| RefCell::new(ConfigurationElement{
             name,
             index,
             value,
             parent:Weak::new(),
+            this:this.clone(),
             children:Vec::new()
-        };
-        Rc::new(RefCell::new(this))
+        }))
+    }
+
+    fn self_rc(&self) -> Rc<RefCell<ConfigurationElement>> {
+        self.this.upgrade().unwrap_or_else(|| Rc::new(RefCell::new(self.clone())))
     }
     
-    pub fn build_tree(lua: &Lua) -> Option<Rc<RefCell<ConfigurationElement>>> {
-        let table:Result<mlua::Table,LuaError>  = lua.globals().get("root");
+    pub fn build_tree(lua: &Lua, root_name:&str) -> Option<Rc<RefCell<ConfigurationElement>>> {
+        let table:Result<mlua::Table,LuaError>  = lua.globals().get(root_name);
         let mut parent_stack:Vec<Rc<RefCell<ConfigurationElement>>> = vec![];
-        let parent = ConfigurationElement::new(String::from("root"), -1, mlua::Value::Nil);
+        let parent = ConfigurationElement::new(String::from(root_name), -1, mlua::Value::Nil);
         parent_stack.push(parent.clone());
         let level:u32 = 0;
         if let Ok(table) = table {
@@ -78,6 +327,7 @@ impl ConfigurationElement {
     }
 
     fn build_tree_element(lua: &Lua, name:String, index:i64, value:mlua::Value, parent_stack:&mut Vec<Rc<RefCell<ConfigurationElement>>>, level:u32) -> () {
+        // The value is stored as Lua gave it, so integers and floats are never converted.
         if value.is_string() || value.is_integer() || value.is_number() || value.is_boolean() {
             let element =  ConfigurationElement::new(name, index, value.clone());
 
@@ -135,7 +385,7 @@ impl ConfigurationElement {
     //     return nullptr;
     // }
 
-    fn find_in_array(&self, start_index:usize, path:&str) -> Option<Rc<RefCell<ConfigurationElement>>> {
+    fn find_in_array(&self, start_index:usize, path:&str) -> Result<Rc<RefCell<ConfigurationElement>>, ConfigError> {
         let sliced_path = &path[start_index..];
         let mut index:usize = 0;
         for c in sliced_path.chars() {
@@ -147,56 +397,67 @@ impl ConfigurationElement {
             }
         }
         let child_index = sliced_path[0..index].parse::<usize>();
-        if let Ok(child_index) = child_index {
-            if child_index < self.children.len() && index<sliced_path.len() && sliced_path[index..].starts_with(']') {
-                let child = self.children[child_index].clone();
-                index+=1;
-                if index < sliced_path.len() - 1 {
-                    return child.borrow().find_in_children(&sliced_path[index+1..]);
-                }
-                else {
-                    return Some(child);
-                }
+        if let Ok(child_index) = child_index && index<sliced_path.len() && sliced_path[index..].starts_with(']') {
+            if child_index >= self.children.len() {
+                return Err(ConfigError::IndexOutOfRange { index: child_index, len: self.children.len() });
+            }
+            let child = self.children[child_index].clone();
+            let rest = &sliced_path[index+1..];
+            if rest.is_empty() {
+                return Ok(child);
+            }
+            // A further subscript indexes into the child, as in foo[4][0].
+            if rest.starts_with('[') {
+                return child.borrow().find_in_array(1, rest);
+            }
+            if let Some(rest) = rest.strip_prefix('.') {
+                return child.borrow().try_find_in_children(rest);
             }
         }
-        None
+        Err(ConfigError::MalformedPath)
     }
+
     pub fn find_element(&self, path: &str) -> Option<Rc<RefCell<ConfigurationElement>>> {
+        self.try_find_element(path).ok()
+    }
+
+    pub fn try_find_element(&self, path: &str) -> Result<Rc<RefCell<ConfigurationElement>>, ConfigError> {
         if path.starts_with("$") {
-            let self_rc = Rc::new(RefCell::new(self.clone()));
-            let mut root = Rc::downgrade(&self_rc);
-            let mut parent = root.clone();
-            while let Some(some_parent) = parent.upgrade() {
-                root = Rc::downgrade(&some_parent);
-                parent = some_parent.borrow().parent.clone();
+            let mut root = self.self_rc();
+            let mut parent = self.parent.upgrade();
+            while let Some(some_parent) = parent {
+                parent = some_parent.borrow().parent.upgrade();
+                root = some_parent;
             }
             if path.eq("$") {
-                return Some(self_rc);
+                return Ok(root);
             }
             let relative_path = path.strip_prefix("$").unwrap();
 
             if path.len()>=4 && path[1..2].eq("[") {
                 let array_path = &path[2..];
-                return self.find_in_array(0, array_path);
+                return root.borrow().find_in_array(0, array_path);
             }
 
-            let dot_pos = relative_path.find('.');
-
-            if let Some(pos) = dot_pos {
-
-                return root.upgrade().unwrap().borrow().find_element(&relative_path[pos+1..]);
-            }
-            else {
-                return Some(root.upgrade().unwrap());
+            if let Some(rest) = relative_path.strip_prefix('.') {
+                return root.borrow().try_find_element(rest);
             }
+            return Err(ConfigError::MalformedPath);
         }
 
-        return self.find_in_children(path);
+        self.try_find_in_children(path)
     }
 
     pub fn find_in_children(&self, path: &str) -> Option<Rc<RefCell<ConfigurationElement>>> {
+        self.try_find_in_children(path).ok()
+    }
+
+    pub fn try_find_in_children(&self, path: &str) -> Result<Rc<RefCell<ConfigurationElement>>, ConfigError> {
+        if path.is_empty() || path.starts_with('.') || path.ends_with('.') || path.contains("..") {
+            return Err(ConfigError::MalformedPath);
+        }
         if self.name == path {
-            return Some(Rc::new(RefCell::new(self.clone())));
+            return Ok(self.self_rc());
         }
         // auto dotPos = path.find('.');
         // // Find position of subscript.
@@ -251,31 +512,109 @@ impl ConfigurationElement {
 
                     for child in &self.children {
                         if child.borrow().name == first {
-                            return child.borrow().find_in_children(rest);
+                            return child.borrow().try_find_in_children(rest);
                         }
                     }
                 }
+                else {
+                    return Err(ConfigError::MalformedPath);
+                }
             }
         }
         if !dot_pos.is_empty() {
             let name = &path[0..dot_pos[0]];
+            let mut result = Err(ConfigError::NotFound { remaining: path.to_string() });
             for child in &self.children {
                 if name == child.borrow().name {
-                    let candidate = child.borrow().find_in_children(&path[dot_pos[0]+1..]);
-                    if let Some(candidate) = candidate {
-                        return Some(candidate.clone());
+                    match child.borrow().try_find_in_children(&path[dot_pos[0]+1..]) {
+                        Ok(candidate) => {
+                            return Ok(candidate.clone());
+                        }
+                        Err(e) => {
+                            result = Err(e);
+                        }
                     }
                 }
             }
+            return result;
         }
         else {
             for child in &self.children {
                 if child.borrow().name == path {
-                    return Some(child.clone());
+                    return Ok(child.clone());
                 }
             }
         }
-        None
+        Err(ConfigError::NotFound { remaining: path.to_string() })
+    }
+
+    // Like find_element, but a * segment matches every child and all matches are returned.
+    pub fn find_all_elements(&self, path: &str) -> Vec<Rc<RefCell<ConfigurationElement>>> {
+        let mut current = vec![self.self_rc()];
+        let mut relative_path = path;
+        if let Some(rest) = path.strip_prefix("$") {
+            if let Ok(root) = self.try_find_element("$") {
+                current = vec![root];
+            }
+            relative_path = rest.strip_prefix('.').unwrap_or(rest);
+            if relative_path.is_empty() {
+                return current;
+            }
+        }
+        let steps = match Self::parse_path_steps(relative_path) {
+            Some(steps) => steps,
+            None => return Vec::new(),
+        };
+        for step in steps {
+            let mut next:Vec<Rc<RefCell<ConfigurationElement>>> = Vec::new();
+            for element in &current {
+                let element = element.borrow();
+                match step {
+                    PathStep::Name(name) => {
+                        next.extend(element.children.iter().filter(|child| child.borrow().name == name).cloned());
+                    }
+                    PathStep::Wildcard => {
+                        next.extend(element.children.iter().cloned());
+                    }
+                    PathStep::Index(index) => {
+                        if let Some(child) = element.children.get(index) {
+                            next.push(child.clone());
+                        }
+                    }
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    fn parse_path_steps(path: &str) -> Option<Vec<PathStep<'_>>> {
+        let mut steps = Vec::new();
+        for part in path.split('.') {
+            let (name, mut subscripts) = match part.find('[') {
+                Some(pos) => (&part[0..pos], &part[pos..]),
+                None => (part, ""),
+            };
+            if name == "*" {
+                steps.push(PathStep::Wildcard);
+            }
+            else if !name.is_empty() {
+                steps.push(PathStep::Name(name));
+            }
+            else if subscripts.is_empty() {
+                return None;
+            }
+            while !subscripts.is_empty() {
+                let close = subscripts.find(']')?;
+                let index = subscripts[1..close].parse::<usize>().ok()?;
+                steps.push(PathStep::Index(index));
+                subscripts = &subscripts[close+1..];
+                if !subscripts.is_empty() && !subscripts.starts_with('[') {
+                    return None;
+                }
+            }
+        }
+        Some(steps)
     }
 
     pub fn add_child(&mut self, self_rc:&Rc<RefCell<ConfigurationElement>>, child:Rc<RefCell<ConfigurationElement>>) {
@@ -285,6 +624,337 @@ impl ConfigurationElement {
     pub fn get_value(&self) -> &mlua::Value {
         &self.value
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn index(&self) -> i64 {
+        self.index
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Rc<RefCell<ConfigurationElement>>> + '_ {
+        self.children.iter().cloned()
+    }
+
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    // The path from the root in the form accepted by find_element, e.g. $.foo[3].bar
+    pub fn absolute_path(&self) -> String {
+        let mut segments:Vec<String> = Vec::new();
+        let mut parent = self.parent.upgrade();
+        if let Some(some_parent) = &parent {
+            segments.push(self.path_segment(&some_parent.borrow()));
+        }
+        while let Some(current) = parent {
+            let next = current.borrow().parent.upgrade();
+            if let Some(some_next) = &next {
+                segments.push(current.borrow().path_segment(&some_next.borrow()));
+            }
+            parent = next;
+        }
+        segments.reverse();
+        let mut path = String::from("$");
+        for segment in segments {
+            path.push_str(&segment);
+        }
+        path
+    }
+
+    fn path_segment(&self, parent:&ConfigurationElement) -> String {
+        if self.is_array_element() {
+            // Subscripts are positions among the parent's children, not Lua keys.
+            let position = parent.children.iter().position(|child| std::ptr::eq(child.as_ptr(), self))
+                .or_else(|| parent.children.iter().position(|child| {
+                    let child = child.borrow();
+                    child.name == self.name && child.index == self.index
+                }));
+            format!("[{}]", position.unwrap_or(0))
+        }
+        else {
+            format!(".{}", self.name)
+        }
+    }
+
+    pub fn set_value(&mut self, value: mlua::Value) {
+        self.value = value;
+    }
+
+    // Replaces the value of the element at path, refusing to clobber a subtree.
+    pub fn set_by_path(root: &Rc<RefCell<ConfigurationElement>>, path: &str, value: mlua::Value) -> Result<(), ConfigError> {
+        let element = root.borrow().try_find_element(path)?;
+        let mut element = element.borrow_mut();
+        if !element.children.is_empty() {
+            return Err(ConfigError::HasChildren { name: element.name.clone() });
+        }
+        element.set_value(value);
+        Ok(())
+    }
+
+    pub fn value_kind(&self) -> ValueKind {
+        if !self.children.is_empty() {
+            return ValueKind::Table;
+        }
+        match self.value {
+            mlua::Value::Nil | mlua::Value::Table(_) => ValueKind::Table,
+            mlua::Value::Integer(_) => ValueKind::Integer,
+            mlua::Value::Number(_) => ValueKind::Float,
+            mlua::Value::Boolean(_) => ValueKind::Boolean,
+            mlua::Value::String(_) => ValueKind::String,
+            _ => ValueKind::Other,
+        }
+    }
+
+    pub fn get_integer(&self) -> Option<i64> {
+        self.value.as_integer()
+    }
+
+    // Integers widen to floats, but nothing else is coerced.
+    pub fn get_float(&self) -> Option<f64> {
+        match self.value {
+            mlua::Value::Number(value) => Some(value),
+            mlua::Value::Integer(value) => Some(value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self) -> Option<bool> {
+        self.value.as_boolean()
+    }
+
+    pub fn get_string(&self) -> Option<String> {
+        self.value.as_string().and_then(|s| s.to_str().ok().map(|s| s.to_string()))
+    }
+
+    // The value at path, or default if there is no element there.
+    pub fn get_value_or(&self, path:&str, default:&mlua::Value) -> mlua::Value {
+        match self.find_element(path) {
+            Some(element) => element.borrow().get_value().clone(),
+            None => default.clone(),
+        }
+    }
+
+    // The typed lookups also fall back to default when the element holds a value of another type.
+    pub fn get_integer_or(&self, path:&str, default:i64) -> i64 {
+        self.find_element(path).and_then(|element| element.borrow().get_integer()).unwrap_or(default)
+    }
+
+    pub fn get_float_or(&self, path:&str, default:f64) -> f64 {
+        self.find_element(path).and_then(|element| element.borrow().get_float()).unwrap_or(default)
+    }
+
+    pub fn get_bool_or(&self, path:&str, default:bool) -> bool {
+        self.find_element(path).and_then(|element| element.borrow().get_bool()).unwrap_or(default)
+    }
+
+    pub fn get_string_or(&self, path:&str, default:&str) -> String {
+        self.find_element(path).and_then(|element| element.borrow().get_string()).unwrap_or_else(|| String::from(default))
+    }
+
+    // The integer at path, if it lies between min and max inclusive.
+    pub fn get_integer_in_range(&self, path:&str, min:i64, max:i64) -> Result<i64, ConfigError> {
+        let element = self.try_find_element(path)?;
+        let element = element.borrow();
+        let value = element.get_integer().ok_or_else(|| ConfigError::WrongType { path: element.absolute_path(), expected: String::from("an integer") })?;
+        if value < min || value > max {
+            return Err(ConfigError::OutOfRange { path: element.absolute_path(), value, min, max });
+        }
+        Ok(value)
+    }
+
+    // The string at path, if it is one of allowed. Matching is case sensitive.
+    pub fn get_enum(&self, path:&str, allowed:&[&str]) -> Result<String, ConfigError> {
+        let element = self.try_find_element(path)?;
+        let element = element.borrow();
+        let value = element.get_string().ok_or_else(|| ConfigError::WrongType { path: element.absolute_path(), expected: String::from("a string") })?;
+        if !allowed.contains(&value.as_str()) {
+            return Err(ConfigError::UnknownValue { path: element.absolute_path(), value, allowed: allowed.iter().map(|name| name.to_string()).collect() });
+        }
+        Ok(value)
+    }
+
+    // The values of the array elements of the table at path in index order, or None if there is no
+    // table there or any element has another type. Named children of the table are ignored.
+    pub fn get_int_array(&self, path:&str) -> Option<Vec<i64>> {
+        self.get_array(path, ConfigurationElement::get_integer)
+    }
+
+    // Integers widen to floats, as in get_float.
+    pub fn get_float_array(&self, path:&str) -> Option<Vec<f64>> {
+        self.get_array(path, ConfigurationElement::get_float)
+    }
+
+    pub fn get_string_array(&self, path:&str) -> Option<Vec<String>> {
+        self.get_array(path, ConfigurationElement::get_string)
+    }
+
+    fn get_array<T>(&self, path:&str, get:impl Fn(&ConfigurationElement) -> Option<T>) -> Option<Vec<T>> {
+        let element = self.find_element(path)?;
+        let element = element.borrow();
+        if element.children.is_empty() && !element.value.is_nil() {
+            return None;
+        }
+        let mut items:Vec<Rc<RefCell<ConfigurationElement>>> = element.children().filter(|child| child.borrow().is_array_element()).collect();
+        items.sort_by_key(|child| child.borrow().index);
+        items.iter().map(|child| get(&child.borrow())).collect()
+    }
+
+    // Loads filename again into current, keeping current's Rc so references to the root stay valid,
+    // and returns what changed. Nodes below the root are replaced, so references to them keep the
+    // old values. If the file can't be loaded current is left alone and nothing is reported.
+    pub fn reload_from_file(lua: &Lua, current:&Rc<RefCell<ConfigurationElement>>, filename:&str) -> Vec<ConfigDiff> {
+        let root_name = current.borrow().name.clone();
+        let Some(fresh) = Self::from_file_with_root(lua, filename, &root_name) else {
+            return Vec::new();
+        };
+        let diffs = current.borrow().diff(&fresh.borrow());
+        let mut fresh = fresh.borrow_mut();
+        let mut current_mut = current.borrow_mut();
+        current_mut.value = fresh.value.clone();
+        current_mut.children = std::mem::take(&mut fresh.children);
+        for child in &current_mut.children {
+            child.borrow_mut().parent = Rc::downgrade(current);
+        }
+        diffs
+    }
+
+    // What has to change to turn this tree into other. Children are matched by name, so array
+    // elements pair up by Lua index. A new or removed subtree is reported once at its root, and
+    // values are compared as Variants, so an integer is not the same as the equal float.
+    pub fn diff(&self, other:&ConfigurationElement) -> Vec<ConfigDiff> {
+        let mut diffs = Vec::new();
+        self.diff_helper(other, &mut diffs);
+        diffs
+    }
+
+    fn diff_helper(&self, other:&ConfigurationElement, diffs:&mut Vec<ConfigDiff>) {
+        if Variant::from(&self.value) != Variant::from(&other.value) {
+            diffs.push(ConfigDiff::Changed { path: self.absolute_path(), old: self.value.clone(), new: other.value.clone() });
+        }
+        // Sorted so the result doesn't depend on Lua's table order.
+        let sorted = |element:&ConfigurationElement| {
+            let mut children:Vec<Rc<RefCell<ConfigurationElement>>> = element.children().collect();
+            children.sort_by(|a, b| a.borrow().name.cmp(&b.borrow().name));
+            children
+        };
+        let others = sorted(other);
+        for child in sorted(self) {
+            let child = child.borrow();
+            match others.iter().find(|other_child| other_child.borrow().name == child.name) {
+                Some(other_child) => child.diff_helper(&other_child.borrow(), diffs),
+                None => diffs.push(ConfigDiff::Removed(child.absolute_path())),
+            }
+        }
+        for other_child in others {
+            let other_child = other_child.borrow();
+            if !self.children.iter().any(|child| child.borrow().name == other_child.name) {
+                diffs.push(ConfigDiff::Added(other_child.absolute_path()));
+            }
+        }
+    }
+
+    // Emits a chunk that from_string will load back into an equivalent tree.
+    pub fn to_lua_string(&self) -> String {
+        let mut output = String::from("root=\n");
+        self.write_lua(&mut output, 0);
+        output.push('\n');
+        output
+    }
+
+    fn write_lua(&self, output: &mut String, level: usize) {
+        if !self.children.is_empty() || self.value.is_nil() {
+            output.push_str(&"\t".repeat(level));
+            output.push_str("{\n");
+            let mut next_position: i64 = 1;
+            for child in &self.children {
+                let child = child.borrow();
+                output.push_str(&"\t".repeat(level + 1));
+                if child.is_array_element() {
+                    if child.index == next_position {
+                        next_position += 1;
+                    }
+                    else {
+                        output.push_str(&format!("[{}]=", child.index));
+                    }
+                }
+                else {
+                    output.push_str(&Self::lua_key(&child.name));
+                    output.push('=');
+                }
+                if !child.children.is_empty() || child.value.is_nil() {
+                    output.push('\n');
+                    child.write_lua(output, level + 1);
+                }
+                else {
+                    output.push_str(&Self::lua_literal(&child.value));
+                }
+                output.push_str(",\n");
+            }
+            output.push_str(&"\t".repeat(level));
+            output.push('}');
+        }
+        else {
+            output.push_str(&Self::lua_literal(&self.value));
+        }
+    }
+
+    fn is_array_element(&self) -> bool {
+        self.name == format!("[{}]", self.index)
+    }
+
+    fn lua_key(name: &str) -> String {
+        let mut chars = name.chars();
+        let is_identifier = match chars.next() {
+            Some(c) => (c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            None => false,
+        };
+        if is_identifier {
+            String::from(name)
+        }
+        else {
+            format!("[{}]", Self::lua_quote(name.as_bytes()))
+        }
+    }
+
+    fn lua_literal(value: &mlua::Value) -> String {
+        match value {
+            mlua::Value::Boolean(value) => value.to_string(),
+            mlua::Value::Integer(value) => value.to_string(),
+            mlua::Value::Number(value) => {
+                if value.is_nan() {
+                    String::from("(0/0)")
+                }
+                else if value.is_infinite() {
+                    String::from(if *value > 0.0 { "math.huge" } else { "-math.huge" })
+                }
+                else {
+                    // Debug formatting keeps the fractional part so Lua reads a float back.
+                    format!("{:?}", value)
+                }
+            }
+            mlua::Value::String(value) => Self::lua_quote(&value.as_bytes()),
+            _ => String::from("nil"),
+        }
+    }
+
+    fn lua_quote(bytes: &[u8]) -> String {
+        let mut quoted = String::from("\"");
+        for byte in bytes {
+            match byte {
+                b'"' => quoted.push_str("\\\""),
+                b'\\' => quoted.push_str("\\\\"),
+                b'\n' => quoted.push_str("\\n"),
+                b'\r' => quoted.push_str("\\r"),
+                b'\t' => quoted.push_str("\\t"),
+                0x20..=0x7e => quoted.push(*byte as char),
+                _ => quoted.push_str(&format!("\\{:03}", byte)),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
 }
 
 #[cfg(test)]
@@ -292,27 +962,6 @@ mod tests {
     use super::*;
     use rstest::rstest;
 
-    fn assert_comparison(value: VariantType, actual:&mlua::Value) {
-        match value {
-            VariantType::Nil => { assert!(actual.is_nil()); }
-            VariantType::Boolean(value) => {
-                assert!(actual.is_boolean());
-                assert_eq!(value, actual.as_boolean().unwrap())
-            }
-            VariantType::Integer(value) => {
-                assert!(actual.is_integer());
-                assert_eq!(value, actual.as_integer().unwrap())
-            },
-            VariantType::Float(value) => {
-                assert!(actual.is_number());
-                assert_eq!(value, actual.as_number().unwrap())
-            },
-            VariantType::String(value) => {
-                assert!(actual.is_string());
-                assert_eq!(value, *actual.as_string().unwrap().to_str().unwrap())
-            },
-        }
-    }
     #[rstest]
     #[case("data/tests/ConfigurationElement/Empty.lua", "foo", false, "", VariantType::Nil)]
     #[case("data/tests/ConfigurationElement/OneElement.lua", "$", true, "root", VariantType::Nil)]
@@ -327,6 +976,15 @@ mod tests {
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[1]", true, "[2]", VariantType::Float(2.0))]
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[2]", true, "[3]", VariantType::String(String::from("wibble")))]
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].bar", true, "bar", VariantType::Float(1.5))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[4]", true, "flibble", VariantType::Nil)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[4][0]", true, "[1]", VariantType::String(String::from("tribble")))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$[1]", true, "foo", VariantType::Nil)]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][0][1]", true, "[2]", VariantType::Integer(20))]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][1]", true, "[2]", VariantType::String(String::from("spoo")))]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "$.foo[1][0][0]", true, "[1]", VariantType::Integer(10))]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][0][2]", false, "", VariantType::Nil)]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][2]", false, "", VariantType::Nil)]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[0][0]", false, "", VariantType::Nil)]
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$[0]", true, "[1]", VariantType::Integer(2))]
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.flibble[0]", true, "[1]", VariantType::String(String::from("tribble")))]
     #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$.foo.flibble[0]", true, "[1]", VariantType::String(String::from("tribble")))]
@@ -339,8 +997,7 @@ mod tests {
         if let Some(actual) = actual {
             let actual = actual.deref().borrow();
             assert_eq!(name, actual.name);
-            assert_comparison(value, actual.get_value());
-            //assert_eq!(value, *actual.unwrap().borrow().get_value());
+            assert_eq!(value, *actual.get_value());
         }
     }
 
@@ -364,6 +1021,8 @@ mod tests {
     #[case("root = { wibble={ { foo=true } }, { tribble=1.0 } }", "wibble[0].foo", "foo")]
     #[case("root = { { foo=true }, { tribble=1.0 } }", "$[1].tribble", "tribble")]
     #[case("root = { { foo=true }, { tribble=1.0 } }", "[1].tribble", "tribble")]
+    #[case("root = { { { foo=true } } }", "$[0][0].foo", "foo")]
+    #[case("root = { grid={ { 1, 2 }, { 3, { 4 } } } }", "grid[1][1][0]", "[1]")]
     #[case("root = { wibble={ { foo=true }, { tribble=1.0 }, } }", "wibble[1].tribble", "tribble")]
     fn test_create_from_string(#[case] input: &str, #[case] path:&str, #[case] name:&str) {
         let lua = Lua::new();
@@ -388,7 +1047,549 @@ mod tests {
         let location = location.unwrap();
         let actual = location.as_ref().borrow().find_element(absolute_path);
         assert!(actual.is_some());
-        assert_comparison(value, actual.unwrap().deref().borrow().get_value());
+        assert_eq!(value, *actual.unwrap().deref().borrow().get_value());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/OneElement.lua", "foo", None, None, Some(true), None)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.bar", None, Some(1.0), None, None)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo", None, None, None, None)]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "qux", Some(1), Some(1.0), None, None)]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "baz", None, None, None, Some(String::from("wibble")))]
+    fn test_typed_accessors(#[case] filename:&str, #[case] path:&str, #[case] integer:Option<i64>, #[case] float:Option<f64>, #[case] boolean:Option<bool>, #[case] string:Option<String>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().as_ref().borrow().find_element(path);
+        assert!(actual.is_some());
+        let actual = actual.unwrap();
+        let actual = actual.deref().borrow();
+        assert_eq!(integer, actual.get_integer());
+        assert_eq!(float, actual.get_float());
+        assert_eq!(boolean, actual.get_bool());
+        assert_eq!(string, actual.get_string());
+    }
+
+    #[rstest]
+    #[case("foo.bar", VariantType::Float(1.0))]
+    #[case("foo.baz", VariantType::Integer(7))]
+    #[case("$.qux", VariantType::Integer(7))]
+    fn test_get_value_or(#[case] path:&str, #[case] expected:VariantType) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua");
+        assert!(sut.is_some());
+        let default = mlua::Value::Integer(7);
+        assert_eq!(expected, sut.unwrap().borrow().get_value_or(path, &default));
+    }
+
+    #[rstest]
+    #[case("foo.bar", 7, 1.0, true, "default")]
+    #[case("foo.baz", 7, 2.5, true, "default")]
+    #[case("foo", 7, 2.5, true, "default")]
+    fn test_typed_lookups_with_default(#[case] path:&str, #[case] integer:i64, #[case] float:f64, #[case] boolean:bool, #[case] string:&str) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua");
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let sut = sut.borrow();
+        assert_eq!(integer, sut.get_integer_or(path, 7));
+        assert_eq!(float, sut.get_float_or(path, 2.5));
+        assert_eq!(boolean, sut.get_bool_or(path, true));
+        assert_eq!(string, sut.get_string_or(path, "default"));
+    }
+
+    #[test]
+    fn test_typed_lookups_with_default_present() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedMultipleChildren.lua");
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let sut = sut.borrow();
+        assert_eq!(1, sut.get_integer_or("qux", 7));
+        assert_eq!(String::from("wibble"), sut.get_string_or("baz", "default"));
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/OneElement.lua").unwrap();
+        assert!(!sut.borrow().get_bool_or("bar", false));
+        assert!(sut.borrow().get_bool_or("foo", false));
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.flibble", None, None, Some(vec![String::from("tribble")]))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$", Some(vec![2]), Some(vec![2.0]), None)]
+    // true, 2.0, "wibble" and a table have no single type.
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo", None, None, None)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].bar", None, None, None)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.missing", None, None, None)]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][0]", Some(vec![10, 20]), Some(vec![10.0, 20.0]), None)]
+    #[case("data/tests/ConfigurationElement/Empty.lua", "$", Some(vec![]), Some(vec![]), Some(vec![]))]
+    fn test_typed_arrays(#[case] filename:&str, #[case] path:&str, #[case] integers:Option<Vec<i64>>, #[case] floats:Option<Vec<f64>>, #[case] strings:Option<Vec<String>>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let sut = sut.borrow();
+        assert_eq!(integers, sut.get_int_array(path));
+        assert_eq!(floats, sut.get_float_array(path));
+        assert_eq!(strings, sut.get_string_array(path));
+    }
+
+    #[test]
+    fn test_diff() {
+        let lua = Lua::new();
+        let one = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/OneElement.lua").unwrap();
+        let nested = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua").unwrap();
+        let expected = vec![
+            ConfigDiff::Changed { path: String::from("$.foo"), old: mlua::Value::Boolean(true), new: mlua::Value::Nil },
+            ConfigDiff::Added(String::from("$.foo.bar")),
+        ];
+        assert_eq!(expected, one.borrow().diff(&nested.borrow()));
+        let expected = vec![
+            ConfigDiff::Changed { path: String::from("$.foo"), old: mlua::Value::Nil, new: mlua::Value::Boolean(true) },
+            ConfigDiff::Removed(String::from("$.foo.bar")),
+        ];
+        assert_eq!(expected, nested.borrow().diff(&one.borrow()));
+    }
+
+    #[rstest]
+    #[case("root = { qux=1, foo={ bar=1.0 }, baz=\"wibble\" }", vec![])]
+    #[case("root = { qux=2, foo={ bar=1.0 }, baz=\"wibble\" }", vec![ConfigDiff::Changed { path: String::from("$.qux"), old: mlua::Value::Integer(1), new: mlua::Value::Integer(2) }])]
+    #[case("root = { qux=1.0, foo={ bar=1.0 }, baz=\"wibble\" }", vec![ConfigDiff::Changed { path: String::from("$.qux"), old: mlua::Value::Integer(1), new: mlua::Value::Number(1.0) }])]
+    #[case("root = { foo={ bar=1.0, spoo=true }, baz=\"wibble\", wibble={ 1 } }", vec![ConfigDiff::Added(String::from("$.foo.spoo")), ConfigDiff::Removed(String::from("$.qux")), ConfigDiff::Added(String::from("$.wibble"))])]
+    fn test_diff_from_file(#[case] input:&str, #[case] expected:Vec<ConfigDiff>) {
+        let lua = Lua::new();
+        let current = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedMultipleChildren.lua").unwrap();
+        let other_lua = Lua::new();
+        let other = ConfigurationElement::from_string(&other_lua, input).unwrap();
+        assert_eq!(expected, current.borrow().diff(&other.borrow()));
+    }
+
+    #[test]
+    fn test_reload_from_file() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua").unwrap();
+        let held = sut.clone();
+        let diffs = ConfigurationElement::reload_from_file(&lua, &sut, "data/tests/ConfigurationElement/NestedElementModified.lua");
+        let expected = vec![
+            ConfigDiff::Changed { path: String::from("$.foo.bar"), old: mlua::Value::Number(1.0), new: mlua::Value::Number(2.0) },
+            ConfigDiff::Added(String::from("$.foo.baz")),
+            ConfigDiff::Added(String::from("$.qux")),
+        ];
+        assert_eq!(expected, diffs);
+        // The root is the same node, now holding the new contents.
+        assert!(Rc::ptr_eq(&held, &sut));
+        assert_eq!(2.0, held.borrow().get_float_or("foo.bar", 0.0));
+        assert_eq!(1, held.borrow().get_integer_or("qux", 0));
+        let bar = held.borrow().find_element("foo.bar").unwrap();
+        assert_eq!("$.foo.bar", bar.borrow().absolute_path());
+        // Reloading the same file again changes nothing.
+        assert!(ConfigurationElement::reload_from_file(&lua, &sut, "data/tests/ConfigurationElement/NestedElementModified.lua").is_empty());
+    }
+
+    #[test]
+    fn test_reload_from_missing_file() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua").unwrap();
+        assert!(ConfigurationElement::reload_from_file(&lua, &sut, "data/tests/ConfigurationElement/Missing.lua").is_empty());
+        assert_eq!(1.0, sut.borrow().get_float_or("foo.bar", 0.0));
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.bar", ValueKind::Float)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo", ValueKind::Table)]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "qux", ValueKind::Integer)]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "baz", ValueKind::String)]
+    #[case("data/tests/ConfigurationElement/OneElement.lua", "foo", ValueKind::Boolean)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[1]", ValueKind::Float)]
+    #[case("data/tests/ConfigurationElement/Empty.lua", "$", ValueKind::Table)]
+    fn test_value_kind(#[case] filename:&str, #[case] path:&str, #[case] kind:ValueKind) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let actual = sut.borrow().find_element(path);
+        assert!(actual.is_some());
+        assert_eq!(kind, actual.unwrap().borrow().value_kind());
+        // Writing the tree out and reading it back keeps the kind.
+        let reloaded_lua = Lua::new();
+        let reloaded = ConfigurationElement::from_string(&reloaded_lua, &sut.borrow().to_lua_string()).unwrap();
+        assert_eq!(kind, reloaded.borrow().find_element(path).unwrap().borrow().value_kind());
     }
 
+    #[rstest]
+    #[case("root = { foo = 2^53 }", ValueKind::Float)]
+    #[case("root = { foo = 7 / 7 }", ValueKind::Float)]
+    #[case("root = { foo = 3 // 1 }", ValueKind::Integer)]
+    #[case("root = { foo = math.tointeger(2.0) }", ValueKind::Integer)]
+    #[case("root = { foo = {} }", ValueKind::Table)]
+    fn test_value_kind_from_string(#[case] input:&str, #[case] kind:ValueKind) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_string(&lua, input);
+        assert!(sut.is_some());
+        assert_eq!(kind, sut.unwrap().borrow().find_element("foo").unwrap().borrow().value_kind());
+    }
+
+    #[test]
+    fn test_value_kind_other() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::new(String::from("foo"), -1, mlua::Value::Function(lua.create_function(|_, ()| Ok(())).unwrap()));
+        assert_eq!(ValueKind::Other, sut.borrow().value_kind());
+    }
+
+    #[test]
+    fn test_from_file_sandboxed() {
+        let sut = ConfigurationElement::from_file_sandboxed("data/tests/ConfigurationElement/NestedElement.lua");
+        assert!(sut.is_ok());
+        let (_lua, sut) = sut.unwrap();
+        assert_eq!(1.0, sut.borrow().get_float_or("foo.bar", 0.0));
+        let sut = ConfigurationElement::from_file_sandboxed("data/tests/ConfigurationElement/Include.lua");
+        assert_eq!(2, sut.unwrap().1.borrow().get_integer_or("qux", 0));
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/InfiniteLoop.lua")]
+    #[case("data/tests/ConfigurationElement/UsesIo.lua")]
+    #[case("data/tests/ConfigurationElement/UsesDofile.lua")]
+    #[case("data/tests/ConfigurationElement/Missing.lua")]
+    #[case("data/tests/ConfigurationElement/IncludeEscape.lua")]
+    fn test_from_file_sandboxed_errors(#[case] filename:&str) {
+        let actual = ConfigurationElement::from_file_sandboxed(filename);
+        assert!(matches!(actual, Err(ConfigError::LoadFailed { .. })));
+    }
+
+    #[rstest]
+    #[case(1_000_000, Some(50005000))]
+    #[case(1000, None)]
+    fn test_from_file_sandboxed_with_limit(#[case] max_instructions:u32, #[case] total:Option<i64>) {
+        let actual = ConfigurationElement::from_file_sandboxed_with_limit("data/tests/ConfigurationElement/Loop.lua", max_instructions);
+        assert_eq!(total, actual.ok().and_then(|(_lua, sut)| sut.borrow().find_element("total")).and_then(|total| total.borrow().get_integer()));
+    }
+
+    #[rstest]
+    #[case("threads", Ok(4))]
+    #[case("limits.retries", Err(ConfigError::OutOfRange { path: String::from("$.limits.retries"), value: 50, min: 1, max: 8 }))]
+    #[case("mode", Err(ConfigError::WrongType { path: String::from("$.mode"), expected: String::from("an integer") }))]
+    #[case("spoo", Err(ConfigError::NotFound { remaining: String::from("spoo") }))]
+    fn test_get_integer_in_range(#[case] path:&str, #[case] expected:Result<i64, ConfigError>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/Validation.lua");
+        assert!(sut.is_some());
+        assert_eq!(expected, sut.unwrap().borrow().get_integer_in_range(path, 1, 8));
+    }
+
+    #[rstest]
+    #[case("mode", Ok(String::from("fast")))]
+    #[case("limits.level", Err(ConfigError::UnknownValue { path: String::from("$.limits.level"), value: String::from("extreme"), allowed: vec![String::from("fast"), String::from("slow")] }))]
+    #[case("threads", Err(ConfigError::WrongType { path: String::from("$.threads"), expected: String::from("a string") }))]
+    fn test_get_enum(#[case] path:&str, #[case] expected:Result<String, ConfigError>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/Validation.lua");
+        assert!(sut.is_some());
+        assert_eq!(expected, sut.unwrap().borrow().get_enum(path, &["fast", "slow"]));
+    }
+
+    #[test]
+    fn test_validation_error_messages() {
+        assert_eq!("$.limits.retries is 50, outside 1 to 8", ConfigError::OutOfRange { path: String::from("$.limits.retries"), value: 50, min: 1, max: 8 }.to_string());
+        assert_eq!("$.mode is \"fastest\", not one of fast, slow", ConfigError::UnknownValue { path: String::from("$.mode"), value: String::from("fastest"), allowed: vec![String::from("fast"), String::from("slow")] }.to_string());
+    }
+
+    #[rstest]
+    #[case("root = { foo = \"1\" }", "foo")]
+    #[case("root = { foo = \"1.5\" }", "foo")]
+    fn test_typed_accessors_do_not_coerce_strings(#[case] input:&str, #[case] path:&str) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_string(&lua, input);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().as_ref().borrow().find_element(path);
+        assert!(actual.is_some());
+        let actual = actual.unwrap();
+        let actual = actual.deref().borrow();
+        assert_eq!(None, actual.get_integer());
+        assert_eq!(None, actual.get_float());
+        assert!(actual.get_string().is_some());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[99]", ConfigError::IndexOutOfRange { index: 99, len: 5 })]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$[99]", ConfigError::IndexOutOfRange { index: 99, len: 2 })]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][0][2]", ConfigError::IndexOutOfRange { index: 2, len: 2 })]
+    #[case("data/tests/ConfigurationElement/NestedArray.lua", "foo[1][0", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[0]x", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3]xbar", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$[]", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo..bar", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[1", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "$foo", ConfigError::MalformedPath)]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.baz", ConfigError::NotFound { remaining: String::from("baz") })]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "$.qux", ConfigError::NotFound { remaining: String::from("qux") })]
+    fn test_try_find_element_errors(#[case] filename:&str, #[case] path:&str, #[case] error:ConfigError) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().as_ref().borrow().try_find_element(path);
+        assert_eq!(Err(error), actual.map(|element| element.borrow().name.clone()));
+    }
+
+    #[test]
+    fn test_set_by_path() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua");
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        assert_eq!(Ok(()), ConfigurationElement::set_by_path(&sut, "foo.bar", mlua::Value::Number(2.0)));
+        let actual = sut.borrow().find_element("foo.bar");
+        assert!(actual.is_some());
+        assert_eq!(Some(2.0), actual.unwrap().borrow().get_float());
+    }
+
+    #[rstest]
+    #[case("foo", ConfigError::HasChildren { name: String::from("foo") })]
+    #[case("foo.baz", ConfigError::NotFound { remaining: String::from("baz") })]
+    fn test_set_by_path_errors(#[case] path:&str, #[case] error:ConfigError) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/NestedElement.lua");
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        assert_eq!(Err(error), ConfigurationElement::set_by_path(&sut, path, mlua::Value::Number(2.0)));
+        let actual = sut.borrow().find_element("foo");
+        assert_eq!(1, actual.unwrap().borrow().children.len());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "qux", VariantType::Integer(1))]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "foo.bar", VariantType::Float(1.0))]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "baz", VariantType::String(String::from("wibble")))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[0]", VariantType::Boolean(true))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[2]", VariantType::String(String::from("wibble")))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].bar", VariantType::Float(1.5))]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.flibble[0]", VariantType::String(String::from("tribble")))]
+    fn test_to_lua_string_round_trip(#[case] filename:&str, #[case] path:&str, #[case] value:VariantType) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let output = sut.unwrap().borrow().to_lua_string();
+        let reloaded_lua = Lua::new();
+        let reloaded = ConfigurationElement::from_string(&reloaded_lua, &output);
+        assert!(reloaded.is_some());
+        let actual = reloaded.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        assert_eq!(value, *actual.unwrap().borrow().get_value());
+    }
+
+    #[rstest]
+    #[case(r#"root = { foo = "say \"hello\"" }"#, "foo", "say \"hello\"")]
+    #[case(r#"root = { foo = "one\ntwo" }"#, "foo", "one\ntwo")]
+    #[case(r#"root = { foo = "back\\slash" }"#, "foo", "back\\slash")]
+    #[case(r#"root = { ["not an identifier"] = "x" }"#, "not an identifier", "x")]
+    fn test_to_lua_string_escapes_strings(#[case] input:&str, #[case] path:&str, #[case] value:&str) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_string(&lua, input);
+        assert!(sut.is_some());
+        let output = sut.unwrap().borrow().to_lua_string();
+        let reloaded_lua = Lua::new();
+        let reloaded = ConfigurationElement::from_string(&reloaded_lua, &output);
+        assert!(reloaded.is_some());
+        let actual = reloaded.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        assert_eq!(Some(String::from(value)), actual.unwrap().borrow().get_string());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "foo", vec!["bar"])]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "$", vec!["baz", "foo", "qux"])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo", vec!["[1]", "[2]", "[3]", "[4]", "flibble"])]
+    fn test_children(#[case] filename:&str, #[case] path:&str, #[case] names:Vec<&str>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        let actual = actual.unwrap();
+        let actual = actual.borrow();
+        let mut actual_names:Vec<String> = actual.children().map(|child| child.borrow().name().to_string()).collect();
+        actual_names.sort();
+        assert_eq!(names.len(), actual.child_count());
+        assert_eq!(names, actual_names);
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[1]", "[2]", 2)]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.flibble", "flibble", -1)]
+    fn test_name_and_index(#[case] filename:&str, #[case] path:&str, #[case] name:&str, #[case] index:i64) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        let actual = actual.unwrap();
+        assert_eq!(name, actual.borrow().name());
+        assert_eq!(index, actual.borrow().index());
+    }
+
+    #[rstest]
+    #[case("qux", VariantType::Integer(2))]
+    #[case("foo.baz", VariantType::String(String::from("wibble")))]
+    #[case("foo.bar", VariantType::Float(1.0))]
+    fn test_include(#[case] path:&str, #[case] value:VariantType) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/Include.lua");
+        assert!(sut.is_some());
+        let actual = sut.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        assert_eq!(value, *actual.unwrap().borrow().get_value());
+    }
+
+    #[rstest]
+    #[case("$", VariantType::Nil)]
+    #[case("foo.bar", VariantType::Float(1.0))]
+    #[case("foo.baz", VariantType::String(String::from("wibble")))]
+    fn test_create_from_file_with_root(#[case] path:&str, #[case] value:VariantType) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file_with_root(&lua, "data/tests/ConfigurationElement/NamedRoot.lua", "config");
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        assert_eq!("config", sut.borrow().name());
+        let actual = sut.borrow().find_element(path);
+        assert!(actual.is_some());
+        assert_eq!(value, *actual.unwrap().borrow().get_value());
+    }
+
+    #[test]
+    fn test_create_from_file_with_wrong_root() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file_with_root(&lua, "data/tests/ConfigurationElement/NamedRoot.lua", "root");
+        assert!(sut.is_some());
+        assert_eq!(0, sut.unwrap().borrow().child_count());
+    }
+
+    #[test]
+    fn test_include_escape() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/IncludeEscape.lua");
+        assert_eq!(3, sut.unwrap().borrow().get_integer_or("outside", 0));
+        let actual = ConfigurationElement::from_file_sandboxed("data/tests/ConfigurationElement/IncludeEscape.lua");
+        assert!(matches!(actual, Err(ConfigError::LoadFailed { reason }) if reason.contains("from outside")));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, "data/tests/ConfigurationElement/IncludeCycleA.lua");
+        assert!(sut.is_none());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "$", "$")]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.bar", "$.foo.bar")]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].bar", "$.foo[3].bar")]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.flibble[0]", "$.foo.flibble[0]")]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "$[0]", "$[0]")]
+    fn test_absolute_path(#[case] filename:&str, #[case] path:&str, #[case] absolute_path:&str) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let actual = sut.borrow().find_element(path);
+        assert!(actual.is_some());
+        let actual = actual.unwrap();
+        assert_eq!(absolute_path, actual.borrow().absolute_path());
+        let found = sut.borrow().find_element(&actual.borrow().absolute_path());
+        assert!(found.is_some());
+        assert_eq!(actual.borrow().name(), found.unwrap().borrow().name());
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.bar", "$", "$")]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo.bar", "$.foo", "foo")]
+    #[case("data/tests/ConfigurationElement/NestedElement.lua", "foo", "foo", "foo")]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].bar", "$[0]", "$[0]")]
+    fn test_find_element_stays_attached(#[case] filename:&str, #[case] path_to_location:&str, #[case] path:&str, #[case] path_from_root:&str) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let sut = sut.unwrap();
+        let location = sut.borrow().find_element(path_to_location);
+        assert!(location.is_some());
+        let actual = location.unwrap().borrow().find_element(path);
+        assert!(actual.is_some());
+        let expected = sut.borrow().find_element(path_from_root);
+        assert!(expected.is_some());
+        assert!(Rc::ptr_eq(&expected.unwrap(), &actual.unwrap()));
+    }
+
+    #[rstest]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "foo.*", vec!["bar"])]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "*", vec!["baz", "foo", "qux"])]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "$.*", vec!["baz", "foo", "qux"])]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "*.bar", vec!["bar"])]
+    #[case("data/tests/ConfigurationElement/NestedMultipleChildren.lua", "foo.*.bar", vec![])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.*", vec!["[1]", "[2]", "[3]", "[4]", "flibble"])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo.*.bar", vec!["bar"])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo[3].*", vec!["bar"])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "*[0]", vec!["[1]"])]
+    #[case("data/tests/ConfigurationElement/IntegerIndex.lua", "foo..*", vec![])]
+    fn test_find_all_elements(#[case] filename:&str, #[case] path:&str, #[case] names:Vec<&str>) {
+        let lua = Lua::new();
+        let sut = ConfigurationElement::from_file(&lua, filename);
+        assert!(sut.is_some());
+        let actual = sut.unwrap().borrow().find_all_elements(path);
+        let mut actual_names:Vec<String> = actual.iter().map(|element| element.borrow().name().to_string()).collect();
+        actual_names.sort();
+        assert_eq!(names.len(), actual.len());
+        assert_eq!(names, actual_names);
+    }
+
+    #[rstest]
+    #[case(mlua::Value::Nil, Some(VariantType::Nil))]
+    #[case(mlua::Value::Integer(42), Some(VariantType::Integer(42)))]
+    #[case(mlua::Value::Number(1.5), Some(VariantType::Float(1.5)))]
+    #[case(mlua::Value::Boolean(false), Some(VariantType::Boolean(false)))]
+    fn test_variant_from_lua_value(#[case] input:mlua::Value, #[case] expected:Option<VariantType>) {
+        let lua = Lua::new();
+        let actual = Variant::from(&input);
+        assert_eq!(expected, actual.value);
+        if let Some(value) = &actual.value {
+            assert_eq!(*value, input);
+        }
+        assert_eq!(input, actual.to_lua_value(&lua));
+    }
+
+    #[rstest]
+    #[case(VariantType::Nil)]
+    #[case(VariantType::Integer(-7))]
+    #[case(VariantType::Float(2.0))]
+    #[case(VariantType::Boolean(true))]
+    #[case(VariantType::String(String::from("wibble")))]
+    fn test_variant_round_trip(#[case] value:VariantType) {
+        let lua = Lua::new();
+        let sut = Variant { value: Some(value.clone()) };
+        let actual = sut.to_lua_value(&lua);
+        assert_eq!(value, actual);
+        assert_eq!(sut, Variant::from(&actual));
+    }
+
+    #[test]
+    fn test_variant_string_from_lua_value() {
+        let lua = Lua::new();
+        let input = mlua::Value::String(lua.create_string("wibble").unwrap());
+        assert_eq!(Some(VariantType::String(String::from("wibble"))), Variant::from(&input).value);
+    }
+
+    #[rstest]
+    #[case(VariantType::Integer(1), mlua::Value::Number(1.0))]
+    #[case(VariantType::Float(1.0), mlua::Value::Integer(1))]
+    #[case(VariantType::Nil, mlua::Value::Boolean(false))]
+    #[case(VariantType::Boolean(true), mlua::Value::Integer(1))]
+    fn test_variant_type_mismatch(#[case] value:VariantType, #[case] input:mlua::Value) {
+        assert_ne!(value, input);
+    }
+
+    #[test]
+    fn test_variant_from_table_is_none() {
+        let lua = Lua::new();
+        let input = mlua::Value::Table(lua.create_table().unwrap());
+        assert_eq!(None, Variant::from(&input).value);
+        assert!(Variant::from(&input).to_lua_value(&lua).is_nil());
+    }
 }
@@ -1,5 +1,6 @@
 mod math;
 
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub struct RoadID {
     major:i16,
     minor:i16,
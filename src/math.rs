@@ -1,9 +1,12 @@
 use std::cell::{RefCell};
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::ops::{Deref};
 use std::rc::Weak;
 use rusqlite::{Connection, Result, Error, Row};
+use rusqlite::functions::FunctionFlags;
 use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 
 pub enum ParsingState {
     Initial,
@@ -31,6 +34,14 @@ impl Identifier {
         }
     }
 
+    // Compares only the fields selected by the mask.
+    pub fn matches(&self, other:&Identifier, mask:&Mask) -> bool {
+        (!mask.link || self.link == other.link)
+            && (!mask.tile || self.tile == other.tile)
+            && (!mask.segment || self.segment == other.segment)
+            && (!mask.lane || self.lane == other.lane)
+    }
+
     pub fn parse(str:&str) -> Result<Identifier, &str> {
         let mut link:u16 = 0;
         let mut tile:u16 = 0;
@@ -63,16 +74,16 @@ impl Identifier {
                         digits = &str[digits_start..digits_end];
                         if i<4 {
                             if i==0 {
-                                link = digits.parse::<u16>().unwrap_or(0);
+                                link = digits.parse::<u16>().map_err(|_| "link out of range")?;
                             }
                             else if i==1 {
-                                tile = digits.parse::<u16>().unwrap_or(0);
+                                tile = digits.parse::<u16>().map_err(|_| "tile out of range")?;
                             }
                             else if i==2 {
-                                segment = digits.parse::<u16>().unwrap_or(0);
+                                segment = digits.parse::<u16>().map_err(|_| "segment out of range")?;
                             }
                             else if i==3 {
-                                lane = digits.parse::<i16>().unwrap_or(0);
+                                lane = digits.parse::<i16>().map_err(|_| "lane out of range")?;
                             }
                             i+=1;
                             if i == 3 {
@@ -95,7 +106,7 @@ impl Identifier {
         }
         if let ParsingState::FoundDigit = state && i==3 {
             digits = &str[digits_start..digits_end];
-            lane = digits.parse::<i16>().unwrap();
+            lane = digits.parse::<i16>().map_err(|_| "lane out of range")?;
         }
         Ok(Identifier {
             link,
@@ -106,6 +117,20 @@ impl Identifier {
     }
 }
 
+impl std::fmt::Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.link, self.tile, self.segment, self.lane)
+    }
+}
+
+impl FromStr for Identifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Identifier::parse(s).map_err(|msg| msg.to_string())
+    }
+}
+
 // An indication of which fields of an Identifier are relevant for a query
 #[derive(PartialEq,Debug,Copy,Clone)]
 #[derive(Eq, Hash)]
@@ -166,6 +191,25 @@ impl Mask {
 }
 
 
+impl std::fmt::Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.link as u8, self.tile as u8, self.segment as u8, self.lane as u8)
+    }
+}
+
+// Unlike Mask::parse, only four dotted fields of 0 or 1 are accepted.
+impl FromStr for Mask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields:Vec<&str> = s.split('.').collect();
+        if fields.len() != 4 || fields.iter().any(|field| *field != "0" && *field != "1") {
+            return Err(format!("invalid mask {}", s));
+        }
+        Ok(Mask::parse(s))
+    }
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 #[derive(Eq, Hash)]
 pub struct LogicalAddress {
@@ -181,10 +225,15 @@ impl LogicalAddress {
         }
     }
 
+    // The address of a whole link, ignoring tile, segment and lane.
+    pub fn for_link(link:u16) -> LogicalAddress {
+        LogicalAddress::new(Identifier::new(link, 0, 0, 0), Mask::new(true, false, false, false))
+    }
+
     pub fn parse(id:&str) -> Result<LogicalAddress,&str> {
         let mut iter = id.split('/').enumerate();
         let id = iter.next().unwrap_or((0,"")).1;
-        if id == "" {
+        if id.is_empty() {
             return Err("Expected some content before the '/'");
         }
         let mask = iter.next().unwrap_or((0,"1.1.1.1")).1;
@@ -203,14 +252,134 @@ impl LogicalAddress {
     }
 }
 
+// Always writes the mask, even when it is the default, so the output parses back to the same address.
+impl std::fmt::Display for LogicalAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.id, self.mask)
+    }
+}
+
+impl FromStr for LogicalAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogicalAddress::parse(s).map_err(|msg| msg.to_string())
+    }
+}
+
+// Addresses serialize as their dotted strings rather than as structs of fields.
+#[cfg(feature = "serde")]
+mod dotted_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T:Display, S:Serializer>(value:&T, serializer:S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer:D) -> Result<T, D::Error>
+    where T:FromStr, T::Err:Display, D:Deserializer<'de> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Identifier {
+    fn serialize<S:serde::Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        dotted_serde::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Identifier {
+    fn deserialize<D:serde::Deserializer<'de>>(deserializer:D) -> Result<Identifier, D::Error> {
+        dotted_serde::deserialize(deserializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Mask {
+    fn serialize<S:serde::Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        dotted_serde::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Mask {
+    fn deserialize<D:serde::Deserializer<'de>>(deserializer:D) -> Result<Mask, D::Error> {
+        dotted_serde::deserialize(deserializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for LogicalAddress {
+    fn serialize<S:serde::Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+        dotted_serde::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for LogicalAddress {
+    fn deserialize<D:serde::Deserializer<'de>>(deserializer:D) -> Result<LogicalAddress, D::Error> {
+        dotted_serde::deserialize(deserializer)
+    }
+}
+
 // A high-level description of a place on the road network
-struct Place {
+#[derive(Clone)]
+pub struct Place {
     name: String,
+    // The link the place lies on.
+    link: u16,
     offset: f64,
     distance: f64,
     loft: f64,
 }
 
+impl Place {
+    pub fn new(name:&str, link:u16, offset:f64, distance:f64, loft:f64) -> Place {
+        Place {
+            name: String::from(name),
+            link,
+            offset,
+            distance,
+            loft
+        }
+    }
+
+    fn from_query(row:&Row) -> Place {
+        Place {
+            name: row.get("name").unwrap_or_default(),
+            link: row.get("link_id").unwrap_or(0),
+            offset: row.get("offset").unwrap_or(0.0),
+            distance: row.get("distance").unwrap_or(0.0),
+            loft: row.get("loft").unwrap_or(0.0)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn link(&self) -> u16 {
+        self.link
+    }
+
+    pub fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    pub fn loft(&self) -> f64 {
+        self.loft
+    }
+}
+
 pub struct InertialCoord {
     pub x: f64,
     pub y: f64,
@@ -251,83 +420,192 @@ impl LogicalCoord {
     }
 }
 
-// Currently an infinite straight
+// Either an infinite straight through the first two points, or along the y axis if there aren't two,
+// or a circular arc. Positive offsets are to the right of the direction of travel.
 pub struct Curve {
     points : Vec<InertialCoord>,
+    kind : CurveKind,
+}
+
+enum CurveKind {
+    Straight,
+    // Angles are in degrees counterclockwise from the x axis, and a positive sweep runs counterclockwise.
+    Arc { radius: f64, start_angle: f64, sweep: f64 },
 }
 
 impl Curve {
     pub fn new() -> Curve {
         Curve {
             points: Vec::new(),
+            kind: CurveKind::Straight,
+        }
+    }
+
+    pub fn straight(start:InertialCoord, end:InertialCoord) -> Curve {
+        Curve {
+            points: vec![start, end],
+            kind: CurveKind::Straight,
+        }
+    }
+
+    pub fn arc(center:InertialCoord, radius:f64, start_angle:f64, sweep:f64) -> Curve {
+        Curve {
+            points: vec![center],
+            kind: CurveKind::Arc { radius, start_angle, sweep },
+        }
+    }
+
+    // The start point and unit direction of the straight.
+    fn frame(&self) -> ((f64, f64, f64), (f64, f64)) {
+        if self.points.len() >= 2 {
+            let start = &self.points[0];
+            let end = &self.points[1];
+            let (dx, dy) = (end.x - start.x, end.y - start.y);
+            let length = (dx * dx + dy * dy).sqrt();
+            if length > 0.0 {
+                return ((start.x, start.y, start.z), (dx / length, dy / length));
+            }
         }
+        ((0.0, 0.0, 0.0), (0.0, 1.0))
     }
 
     pub fn logical_to_inertial(&self, logical: &LogicalCoord, inertial: &mut InertialCoord) {
-        inertial.x = logical.offset;
-        inertial.y = logical.distance;
-        inertial.z = logical.loft;
+        match self.kind {
+            CurveKind::Straight => {
+                let ((x, y, z), (dx, dy)) = self.frame();
+                inertial.x = x + dx * logical.distance + dy * logical.offset;
+                inertial.y = y + dy * logical.distance - dx * logical.offset;
+                inertial.z = z + logical.loft;
+            }
+            CurveKind::Arc { radius, start_angle, sweep } => {
+                let center = &self.points[0];
+                let sign = if sweep < 0.0 { -1.0 } else { 1.0 };
+                // Going counterclockwise the right-hand side is away from the centre.
+                let angle = start_angle.to_radians() + sign * logical.distance / radius;
+                let r = radius + sign * logical.offset;
+                inertial.x = center.x + r * angle.cos();
+                inertial.y = center.y + r * angle.sin();
+                inertial.z = center.z + logical.loft;
+            }
+        }
     }
 
     pub fn inertial_to_logical(&self, inertial: &InertialCoord, logical: &mut LogicalCoord) {
-        logical.offset = inertial.x;
-        logical.distance = inertial.y;
-        logical.loft = inertial.z;
+        match self.kind {
+            CurveKind::Straight => {
+                let ((x, y, z), (dx, dy)) = self.frame();
+                let (rx, ry) = (inertial.x - x, inertial.y - y);
+                logical.offset = rx * dy - ry * dx;
+                logical.distance = rx * dx + ry * dy;
+                logical.loft = inertial.z - z;
+            }
+            CurveKind::Arc { radius, start_angle, sweep } => {
+                let center = &self.points[0];
+                let sign = if sweep < 0.0 { -1.0 } else { 1.0 };
+                let (rx, ry) = (inertial.x - center.x, inertial.y - center.y);
+                let mut angle = (sign * (ry.atan2(rx) - start_angle.to_radians())).rem_euclid(std::f64::consts::TAU);
+                // Points outside the arc belong to whichever end they are nearer.
+                if angle > (std::f64::consts::TAU + sweep.abs().to_radians()) / 2.0 {
+                    angle -= std::f64::consts::TAU;
+                }
+                logical.offset = sign * ((rx * rx + ry * ry).sqrt() - radius);
+                logical.distance = angle * radius;
+                logical.loft = inertial.z - center.z;
+            }
+        }
     }
 }
 
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum SegmentType {
     Unknown,
-    Straight
+    Straight,
+    Arc { radius: f64 }
 }
 pub struct Segment {
+    id:u16,
     tile:u16,
+    // Orders the segment along its tile.
+    seq:u16,
     x:f64,
     y:f64,
     z:f64,
     h:f64,
-    p:f64,
-    r:f64,
-    segment_type:SegmentType
+    length:f64,
+    segment_type:SegmentType,
+    // How far an arc turns, in degrees, if the database records it.
+    sweep:Option<f64>
 }
 
 impl Segment {
     pub fn new() -> Segment {
         Segment {
+            id:0,
             tile:0,
+            seq:0,
             x:0.0,
             y:0.0,
             z:0.0,
             h:0.0,
-            p:0.0,
-            r:0.0,
-            segment_type:SegmentType::Straight
+            length:0.0,
+            segment_type:SegmentType::Straight,
+            sweep:None
         }
     }
 
-    pub fn from_query(row:&Row) -> Segment {
-        Segment {
-            tile:row.get("tile_id").unwrap(),
-            x:row.get("x").unwrap(),
-            y:row.get("y").unwrap(),
-            z:row.get("z").unwrap(),
-            h:row.get("h").unwrap(),
-            p:row.get("p").unwrap(),
-            r:row.get("r").unwrap(),
-            segment_type:Segment::segment_type_from_field(row.get("type").unwrap())
+    pub fn from_query(row:&Row) -> Result<Segment, Error> {
+        let id = row.get("id").unwrap_or(0);
+        Ok(Segment {
+            id,
+            tile:row.get("tile_id")?,
+            // Databases without a seq column are in id order.
+            seq:row.get("seq").unwrap_or(id),
+            x:row.get("x")?,
+            y:row.get("y")?,
+            z:row.get("z")?,
+            h:row.get("h")?,
+            // Older databases have no length column.
+            length:row.get("length").unwrap_or(0.0),
+            // Only arcs carry a radius, and older databases have no radius column.
+            segment_type:Segment::segment_type_from_field(row.get("type")?, row.get("radius").unwrap_or(0.0)),
+            sweep:row.get("sweep").ok()
+        })
+    }
+
+    // Straights store the distance from their start to their end. Arcs with a sweep are as long as
+    // the stretch of circle it covers, and fall back to the stored length without one.
+    pub fn length(&self) -> f64 {
+        match (self.segment_type, self.sweep) {
+            (SegmentType::Arc { radius }, Some(sweep)) => radius * sweep.abs().to_radians(),
+            _ => self.length
+        }
+    }
+
+    // The heading the given distance along the segment. Arcs turn left unless their sweep is negative,
+    // like segment_curve takes them to.
+    pub fn heading_at(&self, distance:f64) -> f64 {
+        match self.segment_type {
+            SegmentType::Arc { radius } if radius > 0.0 => {
+                let sign = self.sweep.map_or(1.0, f64::signum);
+                Heading::new(self.h + sign * (distance / radius).to_degrees()).degrees()
+            }
+            _ => self.h
         }
     }
 
-    pub fn segment_type_from_field(field:i32) -> SegmentType {
-        if field == 0 {
-            return SegmentType::Straight
+    pub fn segment_type_from_field(field:i32, radius:f64) -> SegmentType {
+        match field {
+            0 => SegmentType::Straight,
+            1 => SegmentType::Arc { radius },
+            _ => SegmentType::Unknown
         }
-        SegmentType::Unknown
     }
 }
 pub struct Tile {
     id:u16,
     link:u16,
+    // Orders the tile along its link.
+    seq:u16,
     segments: Vec<Box<Segment>>
 }
 
@@ -336,6 +614,7 @@ impl Tile {
         Tile {
             id,
             link,
+            seq:id,
             segments: Vec::new()
         }
     }
@@ -345,50 +624,55 @@ impl Tile {
 #[derive(Copy,Clone)]
 pub struct Exit {
     link_id: u16,
-    exit: u32
+    exit: u32,
+    // The lane of the arriving link that feeds this exit, or 0 for any lane.
+    lane: i16
+}
+
+// Which way round a junction relative exits are counted from the entry.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub enum ExitOrdering {
+    // Exit 1 is the first on the left.
+    #[default]
+    Clockwise,
+    // Exit 1 is the first on the right.
+    CounterClockwise
 }
 
 #[derive(Clone)]
 pub struct Junction {
     id:u32,
-    links: Vec<Rc<RefCell<Exit>>>
+    links: Vec<Exit>,
+    ordering: ExitOrdering,
+    // Turns from the first link onto the second that are banned here.
+    restricted_turns: HashSet<(u16, u16)>
 }
 
 impl Junction {
     pub fn reciprocal(entry: u32) -> u32 {
-        let mut value = entry + 180;
-
-        while value>=360 {
-            value -= 360
-        }
-        return value;
+        Heading::new(entry as f64).reciprocal().degrees() as u32
     }
 
     pub fn normalise_exit(input: i32) -> u32 {
-        let mut value = input;
-        while value<0 {
-            value += 360;
-        }
-        while value >= 360 {
-            value -= 360;
-        }
-        value as u32
+        Heading::new(input as f64).degrees() as u32
     }
 
     pub fn new(id:u32) -> Junction {
         Junction {
             id,
-            links: Vec::new()
+            links: Vec::new(),
+            ordering: ExitOrdering::default(),
+            restricted_turns: HashSet::new()
         }
     }
 
     pub fn find_entry(&self, heading: f64) -> usize {
-        let reciprocal_heading = find_reciprocal_heading(heading);
+        let reciprocal_heading = Heading::new(heading).reciprocal();
         let mut  closest_index = 0;
         let mut closest_delta = f64::MAX;
         for i in 0..self.links.len() {
-            let exit = self.links[i].borrow().exit;
-            let delta = f64::abs(exit as f64 - reciprocal_heading);
+            let exit = self.links[i].exit;
+            let delta = Heading::new(exit as f64).circular_delta(reciprocal_heading);
             if delta < closest_delta {
                 closest_delta = delta;
                 closest_index = i;
@@ -398,15 +682,22 @@ impl Junction {
     }
 
     pub fn find_exit_from_heading(&self, heading: f64) -> usize {
+        self.find_allowed_exit_from_heading(heading, |_| true)
+    }
+
+    fn find_allowed_exit_from_heading(&self, heading: f64, allowed: impl Fn(usize) -> bool) -> usize {
         let mut closest_delta = f64::MAX;
         let mut exit_index:usize = usize::MAX;
-        let heading_hemi = hemisphere(heading as u32);
         for i in 0..self.links.len() {
-            let exit = self.links[i].borrow().exit;
-            let delta = f64::abs(exit as f64 - heading);
-            let exit_hemi = hemisphere(exit);
+            if !allowed(i) {
+                continue;
+            }
+            let exit = self.links[i].exit;
+            let delta = Heading::new(exit as f64).circular_delta(Heading::new(heading));
 
-            if delta < closest_delta && exit_hemi == heading_hemi {
+            // Exits more than a quarter turn away don't count as going that way at all.
+            // Ties go to the later exit.
+            if delta <= closest_delta && delta < 90.0 {
                 closest_delta = delta;
                 exit_index = i;
             }
@@ -414,44 +705,90 @@ impl Junction {
         exit_index
     }
 
-    pub fn find_relative_exit(&self, entry_index:usize, relative_exit:usize) -> usize {
+    // The exit marked as fed by the given lane, failing that the first fed by any lane, or usize::MAX
+    // if there is neither.
+    pub fn find_exit_for_lane(&self, entry_index:usize, lane:i16) -> usize {
+        let find = |lane:i16| (0..self.links.len()).find(|i| *i != entry_index && self.links[*i].lane == lane);
+        find(lane).or_else(|| find(0)).unwrap_or(usize::MAX)
+    }
+
+    // Counts exits round the junction from the entry in the junction's ordering, so with the default
+    // clockwise ordering exit 1 is the first on the left; a negative count goes the other way.
+    // Exits are stored in heading order either way, so find_entry's index doesn't depend on it.
+    pub fn find_relative_exit(&self, entry_index:usize, relative_exit:i32) -> usize {
+        let step = match self.ordering {
+            ExitOrdering::Clockwise => -relative_exit,
+            ExitOrdering::CounterClockwise => relative_exit
+        };
+        (entry_index as i32 + step).rem_euclid(self.links.len() as i32) as usize
+    }
+
+    pub fn exit_ordering(&self) -> ExitOrdering {
+        self.ordering
+    }
+
+    pub fn set_exit_ordering(&mut self, ordering:ExitOrdering) {
+        self.ordering = ordering;
+    }
+
+    pub fn restrict_turn(&mut self, entry_link:u16, exit_link:u16) {
+        self.restricted_turns.insert((entry_link, exit_link));
+    }
 
-        let mut exit_index:i32 = (entry_index as i32 - relative_exit as i32) % self.links.len() as i32;
-        while exit_index<0 {
-            exit_index += self.links.len() as i32;
+    pub fn is_turn_restricted(&self, entry_index:usize, exit_index:usize) -> bool {
+        match (self.links.get(entry_index), self.links.get(exit_index)) {
+            (Some(entry), Some(exit)) => self.restricted_turns.contains(&(entry.link_id, exit.link_id)),
+            _ => false
         }
-        exit_index as usize
     }
 
+    // Restricted exits are passed over, so a banned turn falls back to the next closest exit.
     pub fn find_exit_from_turn_direction(&self, entry_index:usize, turn_dir: TurnDirection) -> usize {
-        let entry = find_reciprocal_heading(self.links[entry_index].borrow().exit as f64);
-        let mut heading = match turn_dir {
+        let entry = Heading::new(self.links[entry_index].exit as f64).reciprocal().degrees();
+        let heading = match turn_dir {
             TurnDirection::Straight => entry,
             TurnDirection::Left => entry + 90.0,
             TurnDirection::Right => entry - 90.0,
-            TurnDirection::UTurn => entry + 180.0
+            TurnDirection::UTurn => entry + 180.0,
+            TurnDirection::BearLeft | TurnDirection::BearRight => return self.find_fork_exit(entry_index, entry, turn_dir == TurnDirection::BearLeft)
         };
-        while heading>=360.0 {
-            heading -= 360.0;
-        }
-        while heading < 0.0 {
-            heading += 360.0;
+
+        self.find_allowed_exit_from_heading(Heading::new(heading).degrees(), |exit_index| !self.is_turn_restricted(entry_index, exit_index))
+    }
+
+    // Of the exits within 45 degrees of straight ahead, the one furthest to the left or right.
+    fn find_fork_exit(&self, entry_index:usize, ahead:f64, left:bool) -> usize {
+        let mut exit_index = usize::MAX;
+        let mut furthest = f64::MIN;
+        for i in 0..self.links.len() {
+            if i == entry_index || self.is_turn_restricted(entry_index, i) {
+                continue;
+            }
+            // Signed so that anticlockwise, to the left, is positive.
+            let delta = Heading::new(self.links[i].exit as f64 - ahead + 180.0).degrees() - 180.0;
+            let side = if left { delta } else { -delta };
+            if delta.abs() <= 45.0 && side > furthest {
+                furthest = side;
+                exit_index = i;
+            }
         }
+        exit_index
+    }
 
-        self.find_exit_from_heading(heading as f64)
+    // The inverse of find_exit_from_turn_direction, snapping to the nearest quarter turn.
+    pub fn turn_direction_to_exit(&self, entry_index:usize, exit_index:usize) -> TurnDirection {
+        let entry = Heading::new(self.links[entry_index].exit as f64).reciprocal();
+        let exit = self.links[exit_index].exit as f64;
+        let delta = Heading::new(exit - entry.degrees()).degrees();
+        match ((delta / 90.0).round() as u32) % 4 {
+            0 => TurnDirection::Straight,
+            1 => TurnDirection::Left,
+            2 => TurnDirection::UTurn,
+            _ => TurnDirection::Right
+        }
     }
     pub fn find_exit_from_compass(&self, dir: CompassDirection) -> usize {
-        let heading:u32 = match dir {
-            CompassDirection::North => 0,
-            CompassDirection::NorthEast => 315,
-            CompassDirection::East => 270,
-            CompassDirection::SouthEast => 270-45,
-            CompassDirection::South => 180,
-            CompassDirection::SouthWest => 180 - 45,
-            CompassDirection::West => 90,
-            CompassDirection::NorthWest => 45
-        };
-        self.find_exit_from_heading(heading as f64)
+        self.find_exit_from_heading(dir.heading() as f64)
     }
 
     // fn build_routes(&self, network:& Network, routing:&mut Routing) -> () {
@@ -506,7 +843,9 @@ impl Junction {
     fn from_query(id:u32) -> Junction {
         Junction {
             id,
-            links:Vec::new()
+            links:Vec::new(),
+            ordering:ExitOrdering::default(),
+            restricted_turns:HashSet::new()
         }
     }
 
@@ -514,16 +853,41 @@ impl Junction {
         self.links.len()
     }
 
+    // The number of exits, counting each link once per end that meets the junction.
+    pub fn degree(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // (link id, exit heading) for each exit, in exit index order.
+    pub fn exits(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        self.links.iter().map(|exit| (exit.link_id, exit.exit))
+    }
+
+    pub fn exit_heading(&self, index:usize) -> Option<u32> {
+        self.links.get(index).map(|exit| exit.exit)
+    }
 
-    pub fn add_link(&mut self, id:u16, exit_id:u32) {
-        self.links.push(Rc::new(RefCell::new(Exit{link_id:id,exit:exit_id})));
+    pub fn add_link(&mut self, id:u16, exit_id:u32, lane:i16) {
+        self.links.push(Exit{link_id:id,exit:exit_id,lane});
     }
 }
+#[derive(Clone)]
 pub struct Link {
     id:u16,
     tiles: Vec<u16>,
     origin: Option<u32>,
-    destination: Option<u32>
+    destination: Option<u32>,
+    // The road this link is part of, if any.
+    road: Option<RoadID>,
+    lane_count: i16,
+    // One-way links may only be travelled from origin to destination.
+    one_way: bool,
+    // Scales the length of the link when searching for paths, so busy links can be avoided.
+    cost_multiplier: f64
 }
 
 impl<'a> Link {
@@ -532,30 +896,89 @@ impl<'a> Link {
             id,
             tiles:Vec::new(),
             origin:None,
-            destination:None
+            destination:None,
+            road:None,
+            lane_count:1,
+            one_way:false,
+            cost_multiplier:1.0
         }
     }
 
-    fn from_query(id: u16, origin:u32, destination:u32) -> Link {
+    fn from_query(id: u16, origin:u32, destination:u32, road:Option<RoadID>, lane_count:i16, one_way:bool) -> Link {
         Link {
             id,
             tiles:Vec::new(),
             origin:Some(origin),
-            destination:Some(destination)
+            destination:Some(destination),
+            road,
+            lane_count,
+            one_way,
+            cost_multiplier:1.0
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn road(&self) -> Option<RoadID> {
+        self.road
+    }
+
+    pub fn lane_count(&self) -> i16 {
+        self.lane_count
+    }
+
+    pub fn is_one_way(&self) -> bool {
+        self.one_way
+    }
+
+    // Whether the link can be entered from the junction, which must be one of its ends.
+    pub fn can_leave(&self, junc:u32) -> bool {
+        !self.one_way || self.origin == Some(junc)
+    }
+
+    pub fn cost_multiplier(&self) -> f64 {
+        self.cost_multiplier
+    }
+
+    pub fn origin(&self) -> Option<u32> {
+        self.origin
+    }
+
+    pub fn destination(&self) -> Option<u32> {
+        self.destination
+    }
+
+    // The junction at the opposite end from junc, whichever way round the link was defined.
+    pub fn other_end(&self, junc:u32) -> Option<u32> {
+        if self.origin == Some(junc) {
+            self.destination
+        }
+        else if self.destination == Some(junc) {
+            self.origin
+        }
+        else {
+            None
         }
     }
 }
 
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TurnDirection {
     Left,
     Right,
     Straight,
-    UTurn
+    UTurn,
+    // Take the left or right branch of a fork where more than one exit is roughly ahead
+    BearLeft,
+    BearRight
 }
 
 
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CompassDirection {
     North,
     NorthEast,
@@ -568,14 +991,16 @@ pub enum CompassDirection {
 }
 
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Turn {
     Relative(TurnDirection),
     Compass(CompassDirection),
-    Exit(u8),
-    Heading(u32)
+    Exit(i16),
+    Heading(f64)
 }
 
 use std::str::FromStr;
+use crate::RoadID;
 
 impl FromStr for TurnMultiplicity {
     type Err = String;
@@ -585,12 +1010,19 @@ impl FromStr for TurnMultiplicity {
 
         match parts.as_slice() {
             ["Count", count] => {
-                let count:u32 = count.parse().unwrap();
+                let count:u32 = count.parse().map_err(|_| format!("invalid turn count {}", s))?;
                 Ok(TurnMultiplicity::Count(count))
             }
             ["Always"] => {
                 Ok(TurnMultiplicity::Always)
             }
+            ["Distance", distance] => {
+                let distance:f64 = distance.parse().map_err(|_| format!("invalid turn distance {}", s))?;
+                if !distance.is_finite() || distance < 0.0 {
+                    return Err(format!("invalid turn distance {}", s));
+                }
+                Ok(TurnMultiplicity::Distance(distance))
+            }
             _ => Err(format!("invalid turn multiplicity {}", s)),
         }
     }
@@ -604,11 +1036,43 @@ impl FromStr for TurnDirection {
             "Right" => Ok(TurnDirection::Right),
             "Straight" => Ok(TurnDirection::Straight),
             "UTurn" => Ok(TurnDirection::UTurn),
+            "BearLeft" => Ok(TurnDirection::BearLeft),
+            "BearRight" => Ok(TurnDirection::BearRight),
             _ => Err(format!("invalid turn direction: {}", s))
         }
     }
 }
 
+impl std::fmt::Display for TurnDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TurnDirection::Left => "Left",
+            TurnDirection::Right => "Right",
+            TurnDirection::Straight => "Straight",
+            TurnDirection::UTurn => "UTurn",
+            TurnDirection::BearLeft => "BearLeft",
+            TurnDirection::BearRight => "BearRight"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl CompassDirection {
+    // Headings increase anticlockwise from north, so the eastern half of the compass is above 180.
+    pub fn heading(&self) -> u32 {
+        match self {
+            CompassDirection::North => 0,
+            CompassDirection::NorthWest => 45,
+            CompassDirection::West => 90,
+            CompassDirection::SouthWest => 135,
+            CompassDirection::South => 180,
+            CompassDirection::SouthEast => 225,
+            CompassDirection::East => 270,
+            CompassDirection::NorthEast => 315
+        }
+    }
+}
+
 impl FromStr for CompassDirection {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -625,6 +1089,33 @@ impl FromStr for CompassDirection {
         }
     }
 }
+impl std::fmt::Display for CompassDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CompassDirection::North => "North",
+            CompassDirection::NorthEast => "NorthEast",
+            CompassDirection::East => "East",
+            CompassDirection::SouthEast => "SouthEast",
+            CompassDirection::South => "South",
+            CompassDirection::SouthWest => "SouthWest",
+            CompassDirection::West => "West",
+            CompassDirection::NorthWest => "NorthWest"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for Turn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Turn::Relative(dir) => write!(f, "Relative:{}", dir),
+            Turn::Compass(dir) => write!(f, "Compass:{}", dir),
+            Turn::Exit(exit) => write!(f, "Exit:{}", exit),
+            Turn::Heading(heading) => write!(f, "Heading:{}", heading),
+        }
+    }
+}
+
 impl FromStr for Turn {
     type Err = String;  // or use a custom error type
 
@@ -636,19 +1127,22 @@ impl FromStr for Turn {
 
                 match which {
                     &"Relative" => {
-                        let dir = direction.parse().unwrap();
+                        let dir = direction.parse()?;
                         Ok(Turn::Relative(dir))
                     }
                     &"Compass" => {
-                        let dir:CompassDirection = direction.parse().unwrap();
+                        let dir:CompassDirection = direction.parse()?;
                         Ok(Turn::Compass(dir))
                     }
                     &"Exit" => {
-                        let dir:u8 = direction.parse().unwrap();
+                        let dir:i16 = direction.parse().map_err(|_| format!("invalid exit {}", s))?;
                         Ok(Turn::Exit(dir))
                     }
                     &"Heading" => {
-                        let dir:u32 = direction.parse().unwrap();
+                        let dir:f64 = direction.parse().map_err(|_| format!("invalid heading {}", s))?;
+                        if !dir.is_finite() {
+                            return Err(format!("invalid heading {}", s));
+                        }
                         Ok(Turn::Heading(dir))
                     }
                     _ => {
@@ -661,17 +1155,37 @@ impl FromStr for Turn {
     }
 }
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TurnMultiplicity {
     Count(u32),
-    Always
+    Always,
+    // Turn once, at the first junction reached after travelling this far
+    Distance(f64)
+}
+
+impl std::fmt::Display for TurnMultiplicity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TurnMultiplicity::Count(count) => write!(f, "Count:{}", count),
+            TurnMultiplicity::Always => write!(f, "Always"),
+            TurnMultiplicity::Distance(distance) => write!(f, "Distance:{}", distance),
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TurningPattern {
     turn:Turn,
     count:TurnMultiplicity
 }
 
+impl std::fmt::Display for TurningPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.turn, self.count)
+    }
+}
+
 impl FromStr for TurningPattern {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -679,171 +1193,471 @@ impl FromStr for TurningPattern {
 
         match parts.as_slice() {
             [turn, multiplicity] => {
-                Ok(TurningPattern { turn:turn.parse().unwrap(), count: multiplicity.parse().unwrap() })
+                Ok(TurningPattern { turn:turn.parse()?, count: multiplicity.parse()? })
             }
             _ => Err(format!("invalid turn pattern: {}", s))
         }
     }
 }
+// Where a route begins: a link, or a named Place that is looked up when the route is evaluated.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RouteStart {
+    Link(u16),
+    Place(String)
+}
+
+impl std::fmt::Display for RouteStart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteStart::Link(link) => write!(f, "{}", link),
+            RouteStart::Place(name) => write!(f, "\"{}\"", name),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Route {
-    start_link:u16,
+    start:RouteStart,
+    // Relative to the place when starting from one.
     offset:f64,
     distance:f64,
     trav_dir:i32,
+    // An optional trailing field after the patterns.
+    lane:i16,
     patterns:Vec<TurningPattern>
 }
 
-#[derive(Copy, Clone)]
-pub enum RouteParsing {
-    ParsingStartLink,
-    ParsingSpace,
-    ParsingOffset,
-    ParsingDistance,
-    ParsingTravDir,
-    ParsingTurnPattern,
-    ParsingFinished
-}
-impl Route {
-    pub fn empty() -> Route {
-        Route {
-            start_link:0,
-            offset:0.0,
-            distance:0.0,
-            trav_dir:1,
-            patterns:vec![]
+// The inverse of Route::parse; the lane is only written when it isn't 0.
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {} {}", self.start, self.offset, self.distance, self.trav_dir)?;
+        for pattern in &self.patterns {
+            write!(f, " {}", pattern)?;
         }
+        if self.lane != 0 {
+            write!(f, " {}", self.lane)?;
+        }
+        Ok(())
     }
-    pub fn parse(input:&str) -> Route {
-        let mut start = 0;
-        let mut end = 0;
-        let input = input.trim_start();
-        let mut state = RouteParsing::ParsingStartLink;
-        let mut retval : Route = Route::empty();
-        let mut next_state : RouteParsing = RouteParsing::ParsingStartLink;
-        for c in input.chars() {
-            match state {
-                RouteParsing::ParsingStartLink => {
-                    if !c.is_whitespace() {
-                        end += 1;
-                    }
-                    else {
-                        retval.start_link = input[0..end].parse::<u16>().unwrap_or(0);
-                        start = end+1;
-                        end = start;
-                        state = RouteParsing::ParsingSpace;
-                        next_state = RouteParsing::ParsingOffset;
-                    }
-                }
-                RouteParsing::ParsingSpace => {
-                    if c.is_whitespace() {
-                        start += 1;
-                    }
-                    else {
-                        state = next_state;
-                        end = start;
-                    }
-                }
-                RouteParsing::ParsingOffset => {
-                    if !c.is_whitespace() {
-                        end+=1;
-                    }
-                    else {
-                        retval.offset = input[start..=end].trim_start().parse::<f64>().unwrap_or(0.0);
-                        start = end+2;
-                        end = start;
-                        state = RouteParsing::ParsingSpace;
-                        next_state = RouteParsing::ParsingDistance;
-                    }
-                }
-                RouteParsing::ParsingDistance => {
-                    if !c.is_whitespace() {
-                        end+=1;
-                    }
-                    else {
-                        retval.distance = input[start..=end].trim_start().parse::<f64>().unwrap_or(0.0);
-                        start = end+2;
-                        state = RouteParsing::ParsingSpace;
-                        next_state = RouteParsing::ParsingTravDir;
-                    }
-                }
-                RouteParsing::ParsingTravDir => {
-                    if !c.is_whitespace() {
-                        end+=1;
-                    }
-                    else {
-                        retval.trav_dir = input[start..=end].trim_start().parse::<i32>().unwrap_or(0);
-                        start = end+2;
-                        state = RouteParsing::ParsingSpace;
-                        next_state = RouteParsing::ParsingTurnPattern;
-                    }
-                }
-                RouteParsing::ParsingTurnPattern => {
-                    let parts = input[start..].split_whitespace().collect::<Vec<_>>();
-                    for chunk in parts.chunks(2) {
-                        println!("{:?}",chunk);
-                        let input = chunk.join(" ");
-                        println!("{}",input);
-                        let turn  = input.parse::<TurningPattern>();
-                        if let Ok(turn) = turn {
-                            retval.patterns.push(turn);
-                        }
+}
 
-                    }
-                    state = RouteParsing::ParsingFinished;
+// Strict, like Route::try_parse.
+impl FromStr for Route {
+    type Err = RouteParseError;
 
-                }
-                RouteParsing::ParsingFinished => {
-                    // Do nothing.
-                }
-            }
-        }
-        match state {
-            RouteParsing::ParsingDistance => {
-                retval.distance = input[start..=end].trim_start().parse::<f64>().unwrap_or(0.0);
+    fn from_str(input:&str) -> Result<Route, RouteParseError> {
+        Route::try_parse(input)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum RouteParseError {
+    MissingStartLink,
+    UnterminatedPlace,
+    BadFloat { field:&'static str, value:String },
+    BadTravDir { value:String },
+    BadPattern { token:String }
+}
+
+impl std::fmt::Display for RouteParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteParseError::MissingStartLink => write!(f, "route has no start link"),
+            RouteParseError::UnterminatedPlace => write!(f, "route start place has no closing quote"),
+            RouteParseError::BadFloat { field, value } => write!(f, "invalid {} {}", field, value),
+            RouteParseError::BadTravDir { value } => write!(f, "invalid travel direction {}", value),
+            RouteParseError::BadPattern { token } => write!(f, "invalid turn pattern {}", token),
+        }
+    }
+}
+
+impl std::error::Error for RouteParseError {}
+
+// Something Route::parse_with_diagnostics had to skip over or fill in. The position is a byte offset
+// into the input, and nothing is found when the input ended first.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ParseDiagnostic {
+    pub position:usize,
+    pub expected:&'static str,
+    pub found:Option<String>
+}
+
+impl std::fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.found {
+            Some(found) => write!(f, "expected {} at position {}, found '{}'", self.expected, self.position, found),
+            None => write!(f, "expected {} at position {}, found end of input", self.expected, self.position),
+        }
+    }
+}
+
+// A structural problem found by Network::validate.
+#[derive(PartialEq, Debug, Clone)]
+pub enum NetworkWarning {
+    // A link whose origin or destination names a junction that doesn't exist.
+    DanglingLinkEndpoint { link:u16, junction:u32 },
+    // A junction exit onto a link that doesn't exist.
+    DanglingExit { junction:u32, link:u16 },
+    // A tile with no segments, or one belonging to a link that doesn't exist.
+    OrphanTile { tile:u16 },
+    // A link with no tiles.
+    EmptyLink { link:u16 },
+    // A junction that can't be reached from the first junction.
+    UnreachableJunction { junction:u32 },
+    // A table that Network::from couldn't read and left out.
+    UnreadableTable { table:&'static str, reason:String }
+}
+
+impl std::fmt::Display for NetworkWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkWarning::DanglingLinkEndpoint { link, junction } => write!(f, "link {} refers to missing junction {}", link, junction),
+            NetworkWarning::DanglingExit { junction, link } => write!(f, "junction {} has an exit onto missing link {}", junction, link),
+            NetworkWarning::OrphanTile { tile } => write!(f, "tile {} has no segments or no link", tile),
+            NetworkWarning::EmptyLink { link } => write!(f, "link {} has no tiles", link),
+            NetworkWarning::UnreachableJunction { junction } => write!(f, "junction {} is unreachable", junction),
+            NetworkWarning::UnreadableTable { table, reason } => write!(f, "table {} was left out: {}", table, reason),
+        }
+    }
+}
+
+// Controls how imported data is joined up into a network.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct NetworkImportOptions {
+    // How close, in coordinate units on each axis, a link's end must be to a junction's point to join them.
+    pub snap_distance:f64,
+    // How many degrees an exit may be from a heading and still match it.
+    pub heading_tolerance:f64,
+    // Give each link an exit at whichever of its junctions lacks one, for data that only lists one direction.
+    pub reciprocal_exits:bool
+}
+
+impl Default for NetworkImportOptions {
+    fn default() -> NetworkImportOptions {
+        NetworkImportOptions { snap_distance:1e-6, heading_tolerance:1e-6, reciprocal_exits:false }
+    }
+}
+
+// The names a database uses for the tables and columns the gateways read, for schemas that differ
+// from the usual one. Anything not mapped keeps its usual name.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SchemaMapping {
+    tables:BTreeMap<String, String>,
+    // Keyed by the usual table and column names.
+    columns:BTreeMap<(String, String), String>,
+    // SQL conditions limiting which rows of a table are read.
+    filters:BTreeMap<String, String>
+}
+
+static DEFAULT_SCHEMA:SchemaMapping = SchemaMapping::new();
+
+impl SchemaMapping {
+    pub const fn new() -> SchemaMapping {
+        SchemaMapping { tables:BTreeMap::new(), columns:BTreeMap::new(), filters:BTreeMap::new() }
+    }
+
+    pub fn map_table(&mut self, table:&str, name:&str) {
+        self.tables.insert(table.to_string(), name.to_string());
+    }
+
+    pub fn map_column(&mut self, table:&str, column:&str, name:&str) {
+        self.columns.insert((table.to_string(), column.to_string()), name.to_string());
+    }
+
+    fn filter(&mut self, table:&str, condition:String) {
+        self.filters.insert(table.to_string(), condition);
+    }
+
+    pub fn table<'b>(&'b self, table:&'b str) -> &'b str {
+        self.tables.get(table).map(|name| name.as_str()).unwrap_or(table)
+    }
+
+    // Selects every column of the table, with the mapped columns also under their usual names. The
+    // aliases come first so they are found ahead of any unrelated column of the same name.
+    fn select(&self, table:&str) -> String {
+        let quote = |name:&str| format!("\"{}\"", name.replace('"', "\"\""));
+        let mut columns:Vec<String> = self.columns.iter()
+            .filter(|((mapped, _), _)| mapped == table)
+            .map(|((_, column), name)| format!("{} AS {}", quote(name), quote(column)))
+            .collect();
+        columns.push("*".to_string());
+        let select = format!("SELECT {} FROM {}", columns.join(", "), quote(self.table(table)));
+        match self.filters.get(table) {
+            Some(condition) => format!("{} WHERE {}", select, condition),
+            None => select
+        }
+    }
+}
+
+// Why a GeoJSON document couldn't be turned into a Network.
+#[derive(PartialEq, Debug)]
+pub enum ImportError {
+    #[cfg(feature = "geojson")]
+    BadJson { reason:String },
+    #[cfg(feature = "geojson")]
+    NotFeatureCollection,
+    #[cfg(feature = "geojson")]
+    BadFeature { index:usize, reason:String },
+    // A database row that couldn't be read.
+    BadRow { table:&'static str, reason:String }
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "geojson")]
+            ImportError::BadJson { reason } => write!(f, "invalid JSON: {}", reason),
+            #[cfg(feature = "geojson")]
+            ImportError::NotFeatureCollection => write!(f, "document is not a FeatureCollection"),
+            #[cfg(feature = "geojson")]
+            ImportError::BadFeature { index, reason } => write!(f, "feature {}: {}", index, reason),
+            ImportError::BadRow { table, reason } => write!(f, "bad row in {}: {}", table, reason),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+// A table that doesn't exist loads as an empty one. Any other failure, a bad row or a database that
+// can't be read, is kept.
+fn table_rows<T>(rows:Result<Vec<T>, Error>) -> Result<Vec<T>, Error> {
+    match rows {
+        Err(Error::SqliteFailure(_, Some(message))) if message.starts_with("no such table") => Ok(Vec::new()),
+        rows => rows
+    }
+}
+
+fn read_table<T>(table:&'static str, rows:Result<Vec<T>, Error>) -> Result<Vec<T>, ImportError> {
+    table_rows(rows).map_err(|e| ImportError::BadRow { table, reason:e.to_string() })
+}
+
+// Like read_table, but a table that can't be read is left out with a warning.
+fn read_table_leniently<T>(table:&'static str, rows:Result<Vec<T>, Error>, warnings:&mut Vec<NetworkWarning>) -> Vec<T> {
+    table_rows(rows).unwrap_or_else(|e| {
+        warnings.push(NetworkWarning::UnreadableTable { table, reason:e.to_string() });
+        Vec::new()
+    })
+}
+
+impl Route {
+    pub fn empty() -> Route {
+        Route {
+            start:RouteStart::Link(0),
+            offset:0.0,
+            distance:0.0,
+            trav_dir:1,
+            lane:0,
+            patterns:vec![]
+        }
+    }
+
+    // Lossy version of try_parse: anything malformed falls back to its default or is dropped.
+    pub fn parse(input:&str) -> Route {
+        Route::parse_tokens(input, false, &mut Vec::new()).unwrap_or_else(|_| Route::empty())
+    }
+
+    pub fn try_parse(input:&str) -> Result<Route, RouteParseError> {
+        Route::parse_tokens(input, true, &mut Vec::new())
+    }
+
+    // Parses as parse does, noting each field that was malformed and so fell back or was dropped, and
+    // the first one missing if the input stops before the travel direction.
+    pub fn parse_with_diagnostics(input:&str) -> (Route, Vec<ParseDiagnostic>) {
+        let mut diagnostics = Vec::new();
+        let route = Route::parse_tokens(input, false, &mut diagnostics).unwrap_or_else(|_| Route::empty());
+        (route, diagnostics)
+    }
+
+    // Fields missing from the end of the input keep their defaults; with strict set, a field that
+    // is present but malformed is an error. Tokens are separated by any run of whitespace, tabs and
+    // line breaks included, so a line read from a file parses as it is.
+    fn parse_tokens(input:&str, strict:bool, diagnostics:&mut Vec<ParseDiagnostic>) -> Result<Route, RouteParseError> {
+        // Tokens are slices of the input, so where they start in it can be found from their addresses.
+        let diagnostic = |expected:&'static str, token:Option<&str>| ParseDiagnostic {
+            position:token.map_or(input.len(), |token| token.as_ptr() as usize - input.as_ptr() as usize),
+            expected,
+            found:token.map(String::from)
+        };
+        let mut retval = Route::empty();
+        let trimmed = input.trim_start();
+        let mut tokens:Vec<&str> = vec![];
+        if let Some(quoted) = trimmed.strip_prefix('"') {
+            // A place name may contain spaces, so it is quoted and stands in for the start link token.
+            let (name, rest) = match quoted.split_once('"') {
+                Some(split) => split,
+                None if strict => return Err(RouteParseError::UnterminatedPlace),
+                None => {
+                    diagnostics.push(diagnostic("closing quote", None));
+                    (quoted, "")
+                }
+            };
+            retval.start = RouteStart::Place(String::from(name));
+            tokens.push(name);
+            tokens.extend(rest.split_whitespace());
+        }
+        else {
+            tokens.extend(trimmed.split_whitespace());
+            match tokens.first().map(|token| (*token, token.parse::<u16>())) {
+                Some((_, Ok(start_link))) => retval.start = RouteStart::Link(start_link),
+                _ if strict => return Err(RouteParseError::MissingStartLink),
+                first => diagnostics.push(diagnostic("start link", first.map(|(token, _)| token)))
             }
-            RouteParsing::ParsingTurnPattern => {
-                let turn = input[start..=end].trim_start().parse::<TurningPattern>();
-                if let Ok(turn) = turn {
-                    retval.patterns.push(turn);
+        }
+        let parse_float = |field:&'static str, token:Option<&&str>, diagnostics:&mut Vec<ParseDiagnostic>| -> Result<Option<f64>, RouteParseError> {
+            match token.map(|value| (value, value.parse::<f64>())) {
+                Some((_, Ok(value))) => Ok(Some(value)),
+                Some((value, Err(_))) if strict => Err(RouteParseError::BadFloat { field, value:value.to_string() }),
+                Some((value, _)) => {
+                    diagnostics.push(diagnostic(field, Some(value)));
+                    Ok(Some(0.0))
                 }
+                None => Ok(None)
             }
-            _ => {
+        };
+        if let Some(offset) = parse_float("offset", tokens.get(1), diagnostics)? {
+            retval.offset = offset;
+        }
+        if let Some(distance) = parse_float("distance", tokens.get(2), diagnostics)? {
+            retval.distance = distance;
+        }
+        if let Some(value) = tokens.get(3) {
+            match value.parse::<i32>() {
+                Ok(trav_dir) => retval.trav_dir = trav_dir,
+                Err(_) if strict => return Err(RouteParseError::BadTravDir { value:value.to_string() }),
+                Err(_) => {
+                    diagnostics.push(diagnostic("travel direction", Some(value)));
+                    retval.trav_dir = 0
+                }
+            }
+        }
+        else if let Some(field) = ["offset", "distance", "travel direction"].get(tokens.len().wrapping_sub(1)) {
+            diagnostics.push(diagnostic(field, None));
+        }
+        let mut pattern_tokens:Vec<&&str> = tokens.iter().skip(4).collect();
+        if pattern_tokens.len() % 2 == 1 && let Some(Ok(lane)) = pattern_tokens.last().map(|token| token.parse::<i16>()) {
+            retval.lane = lane;
+            pattern_tokens.pop();
+        }
+        for chunk in pattern_tokens.chunks(2) {
+            let token = chunk.iter().map(|part| **part).collect::<Vec<_>>().join(" ");
+            match token.parse::<TurningPattern>() {
+                Ok(pattern) => retval.patterns.push(pattern),
+                Err(_) if strict => return Err(RouteParseError::BadPattern { token }),
+                Err(_) => diagnostics.push(ParseDiagnostic { found:Some(token), ..diagnostic("turn pattern", Some(chunk[0])) })
+            }
+        }
+        Ok(retval)
+    }
+
+    // The position the route starts from, or None if its link or place doesn't exist.
+    pub fn resolve_start(&self, network:&Network) -> Option<LogicalCoord> {
+        network.route_start(self)
+    }
 
+    // Render evaluated steps back into a route string that Route::parse accepts,
+    // joining consecutive identical turns into a single counted pattern.
+    pub fn describe(&self, network:&Network, steps:&[(u32, usize)]) -> String {
+        let mut turns:Vec<(TurnDirection, u32)> = Vec::new();
+        for (_, dir) in network.turn_instructions(self, steps) {
+            match turns.last_mut() {
+                Some((last, count)) if *last == dir => *count += 1,
+                _ => turns.push((dir, 1))
             }
         }
-        retval
+        let mut description = format!("{} {} {} {}", self.start, self.offset, self.distance, self.trav_dir);
+        for (dir, count) in turns {
+            description.push_str(&format!(" {}", TurningPattern { turn:Turn::Relative(dir), count:TurnMultiplicity::Count(count) }));
+        }
+        if self.lane != 0 {
+            description.push_str(&format!(" {}", self.lane));
+        }
+        description
     }
 }
+
+// Everything Network::run_route found out about a route string. Errors explain why the steps
+// stop short of a pattern's turns, or why they are empty when the route couldn't be parsed or started.
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteReport {
+    pub steps:Vec<(u32, usize)>,
+    pub distance:f64,
+    // The turn made at each junction reached.
+    pub instructions:Vec<(u32, TurnDirection)>,
+    pub errors:Vec<String>
+}
+
 #[derive(Copy, Clone)]
 #[derive(Eq, Hash, PartialEq)]
 pub struct Hop {
     junction: u32,
     dest_junc:u32,
-    // destination: LogicalAddress,
-    // next_hop: LogicalAddress,
+    // The link arriving at dest_junc.
+    destination: LogicalAddress,
+    // The link to take out of junction.
+    next_hop: LogicalAddress,
     exit: u32
 }
 
 pub struct Routing {
     hops: HashSet<Hop>,
+    // The same hops keyed by (junction, dest_junc), keeping the first inserted for each pair.
+    by_destination: HashMap<(u32, u32), Hop>
 }
 
 impl Hop {
-    pub fn from(junction:u32, dest_junc:u32, exit:u32) -> Hop {
+    pub fn from(junction:u32, dest_junc:u32, destination:LogicalAddress, next_hop:LogicalAddress, exit:u32) -> Hop {
         Hop {
             junction,
             dest_junc,
+            destination,
+            next_hop,
             exit
         }
     }
+
+    pub fn destination(&self) -> LogicalAddress {
+        self.destination
+    }
+
+    pub fn next_hop(&self) -> LogicalAddress {
+        self.next_hop
+    }
+
+    pub fn exit(&self) -> u32 {
+        self.exit
+    }
 }
 impl Routing {
     pub fn new() -> Routing {
         Routing {
             hops: HashSet::new(),
+            by_destination: HashMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, hop:Hop) {
+        self.hops.insert(hop);
+        self.by_destination.entry((hop.junction, hop.dest_junc)).or_insert(hop);
+    }
+
+    pub fn set_hops(&mut self, hops:HashSet<Hop>) {
+        self.clear();
+        for hop in hops {
+            self.insert(hop);
         }
     }
+
+    pub fn clear(&mut self) {
+        self.hops.clear();
+        self.by_destination.clear();
+    }
+
+    pub fn find(&self, junction:u32, dest_junc:u32) -> Option<Hop> {
+        self.by_destination.get(&(junction, dest_junc)).copied()
+    }
 }
 
 #[derive(Clone)]
@@ -871,7 +1685,11 @@ impl SpanningNode {
         }
     }
 
+    // The empty tree, which has no junction, counts as zero nodes.
     pub fn num_nodes(&self) -> usize {
+        if self.value.strong_count() == 0 && self.children.is_empty() {
+            return 0;
+        }
         let retval:usize = 0;
         self.num_nodes_helper(retval)
     }
@@ -884,6 +1702,31 @@ impl SpanningNode {
         retval
     }
 
+    // The number of ancestors, so the root has depth 0.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut parent = self.parent.upgrade();
+        while let Some(node) = parent {
+            depth += 1;
+            parent = node.borrow().parent.upgrade();
+        }
+        depth
+    }
+
+    // The junction ids from the root down to node, inclusive.
+    pub fn path_to_root(node:&Rc<RefCell<SpanningNode>>) -> Vec<u32> {
+        let mut path = vec![];
+        let mut current = Some(node.clone());
+        while let Some(node) = current {
+            if let Some(junc) = node.borrow().value.upgrade() {
+                path.push(junc.borrow().id);
+            }
+            current = node.borrow().parent.upgrade();
+        }
+        path.reverse();
+        path
+    }
+
     pub fn depth_first_traversal<NodeFunc>(node:Rc<RefCell<SpanningNode>>, node_func:&NodeFunc) -> ()
     where NodeFunc : Fn(Rc<RefCell<SpanningNode>>)
     {
@@ -892,855 +1735,4232 @@ impl SpanningNode {
             Self::depth_first_traversal(child.clone(), node_func);
         }
     }
+
+    // Visits every node at one depth before any node at the next.
+    pub fn breadth_first_traversal<NodeFunc>(root:Rc<RefCell<SpanningNode>>, node_func:&NodeFunc)
+    where NodeFunc : Fn(Rc<RefCell<SpanningNode>>)
+    {
+        let mut queue = VecDeque::new();
+        queue.push_back(root);
+        while let Some(node) = queue.pop_front() {
+            node_func(node.clone());
+            for child in &node.borrow().children {
+                queue.push_back(child.clone());
+            }
+        }
+    }
 }
 
-pub struct Network {
-    links : Vec<Box<Link>>,
-    junctions : Vec<Rc<RefCell<Junction>>>,
-    tiles: Vec<Box<Tile>>,
-    segments: Vec<Box<Segment>>,
-    // One for each Junction
-    routing: RefCell<Routing>,
-    spanning_tree: Rc<RefCell<SpanningNode>>
+// An entry in the open set of a best-first search, ordered so that BinaryHeap pops the lowest estimate.
+#[derive(PartialEq)]
+struct SearchEntry {
+    estimate: f64,
+    junc: u32
 }
 
-impl<'a> Network {
-    pub fn new(links:Vec<Box<Link>>, junctions:Vec<Rc<RefCell<Junction>>>) -> Network {
-        Network {
-            links,
-            junctions,
-            tiles: Vec::new(),
-            segments: Vec::new(),
-            routing:RefCell::new(Routing::new()),
-            spanning_tree: Rc::new(RefCell::new(SpanningNode::empty()))
-        }
+impl Eq for SearchEntry {}
+
+impl Ord for SearchEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
     }
+}
 
-    pub fn from(connection:&Connection) -> Network {
-        let link_gw:LinkGateway = LinkGateway::new(connection);
-        let junc_gw:JunctionGateway = JunctionGateway::new(connection);
-        let tile_gw: TileGateway = TileGateway::new(connection);
-        let seg_gw : SegmentGateway = SegmentGateway::new(connection);
-        let mut network = Network::empty();
-        network.set_links(link_gw.find_all().unwrap_or(Vec::new()));
-        network.set_junctions(junc_gw.find_all().unwrap_or(Vec::new()));
-        network.set_junction_connections(&mut junc_gw.find_connections().unwrap_or(Vec::<(u32,u16,u32)>::new()));
-        network.set_tiles(tile_gw.find_all().unwrap_or(Vec::new()));
-        network.set_segments(seg_gw.find_all().unwrap_or(Vec::new()));
-        network.build_spanning_tree();
-        network.build_routes();
-        network
+impl PartialOrd for SearchEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    pub fn first_segment_for_link(&self, link:&Link) -> Option<&Segment> {
-        for tile in &self.tiles {
-            if tile.link == link.id {
-                for segment in &self.segments {
-                    if segment.tile == tile.id {
-                        return Some(segment);
-                    }
+// An exit copied out of a junction for RoutingSnapshot.
+struct SnapshotExit {
+    // The junction at the other end of the exit's link.
+    next:u32,
+    link:u16,
+    heading:u32,
+    cost:f64
+}
+
+// Plain ids and link lengths copied out of a Network, so routing can be searched without touching
+// the Rc<RefCell<..>> junctions, and from other threads.
+struct RoutingSnapshot {
+    // Each junction's id and the exits that lead somewhere.
+    sources:Vec<(u32, Vec<SnapshotExit>)>,
+    // Neighbouring junction, joining link and its length, as Network::neighbors gives them.
+    neighbors:HashMap<u32, Vec<(u32, u16, f64)>>
+}
+
+impl RoutingSnapshot {
+    fn all_pairs_hops(&self) -> HashSet<Hop> {
+        self.sources.iter().flat_map(|(source, exits)| self.hops_from(*source, exits)).collect()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn all_pairs_hops_parallel(&self) -> HashSet<Hop> {
+        use rayon::prelude::*;
+        self.sources.par_iter().flat_map_iter(|(source, exits)| self.hops_from(*source, exits)).collect()
+    }
+
+    fn hops_from(&self, source:u32, exits:&[SnapshotExit]) -> Vec<Hop> {
+        // Cost, position in exits of the first exit, and arriving link for each junction reached so far.
+        let mut best: HashMap<u32, (f64, usize, u16)> = HashMap::new();
+        let mut closed: HashSet<u32> = HashSet::new();
+        let mut open = BinaryHeap::new();
+        for (position, exit) in exits.iter().enumerate() {
+            if exit.next != source && best.get(&exit.next).is_none_or(|known| exit.cost < known.0) {
+                best.insert(exit.next, (exit.cost, position, exit.link));
+                open.push(SearchEntry { estimate: exit.cost, junc: exit.next });
+            }
+        }
+        while let Some(SearchEntry { junc, .. }) = open.pop() {
+            if !closed.insert(junc) {
+                continue;
+            }
+            let (cost, first_exit, _) = best[&junc];
+            for &(next, link_id, length) in self.neighbors.get(&junc).into_iter().flatten() {
+                let next_cost = cost + length;
+                if next != source && best.get(&next).is_none_or(|known| next_cost < known.0) {
+                    best.insert(next, (next_cost, first_exit, link_id));
+                    open.push(SearchEntry { estimate: next_cost, junc: next });
                 }
             }
         }
-        return None;
+        best.into_iter().map(|(dest_junc, (_, first_exit, arriving_link))| {
+            let exit = &exits[first_exit];
+            Hop::from(source, dest_junc, LogicalAddress::for_link(arriving_link), LogicalAddress::for_link(exit.link), exit.heading)
+        }).collect()
     }
+}
 
-    pub fn last_segment_for_link(&self, link:&Link) -> Option<&Segment> {
-        let mut retval:Option<&Segment> = None;
-        for tile in &self.tiles {
-            if tile.link == link.id {
-                for segment in &self.segments {
-                    if segment.tile == tile.id {
-                        retval = Some(segment);
-                    }
-                }
-            }
+// What route evaluation needs from a network, so that RouteSteps can walk a Network or a SyncNetwork.
+trait RouteTopology {
+    fn link(&self, id:u16) -> Option<&Link>;
+    fn length(&self, link:&Link) -> f64;
+    // The headings of the link's first and last segments.
+    fn end_headings(&self, link:&Link) -> (Option<f64>, Option<f64>);
+    fn place(&self, name:&str) -> Option<&Place>;
+    fn with_junction<R>(&self, id:u32, f:impl FnOnce(&Junction) -> R) -> Option<R>;
+
+    // The junction at the far end of a link and the heading on which it is entered.
+    fn approach(&self, link:&Link, trav_dir:i32) -> (Option<u32>, f64) {
+        let (first, last) = self.end_headings(link);
+        if trav_dir == -1 {
+            (link.origin, first.map(|heading| Heading::new(heading).reciprocal().degrees()).unwrap_or(0.0))
+        }
+        else {
+            (link.destination, last.unwrap_or(0.0))
         }
-        retval
     }
 
-    pub fn find_exit_by_heading(&self, to: &Junction, exit_heading: u32) -> usize {
-        let mut exit_index = 0;
-        for _ in 0..self.links.len() {
-            let exit = &to.links[exit_index];
-            if exit.borrow().exit == exit_heading {
-                return exit_index;
-            }
-            exit_index = (exit_index+1) % self.links.len();
+    // The link taken out of a junction and the direction it is travelled in, or None if that would
+    // go the wrong way along a one-way link.
+    fn depart(&self, junc:u32, link_id:u16, trav_dir:i32) -> Option<(&Link, i32)> {
+        let link = self.link(link_id)?;
+        let trav_dir = match link.other_end(junc) {
+            Some(end) if link.destination == Some(end) => 1,
+            Some(_) => -1,
+            None => trav_dir
+        };
+        if link.one_way && trav_dir == -1 {
+            return None;
         }
+        Some((link, trav_dir))
+    }
 
-        return exit_index;
+    // None if the place's link doesn't exist or the place lies beyond its end.
+    fn place_coord(&self, place:&Place) -> Option<LogicalCoord> {
+        let link = self.link(place.link())?;
+        if place.distance() < 0.0 || place.distance() > self.length(link) {
+            return None;
+        }
+        Some(LogicalCoord::new(LogicalAddress::for_link(link.id), place.offset(), place.distance(), place.loft()))
     }
 
-    pub fn find_exit(&self, from:&Junction, to:&Junction) -> usize {
-        // let from = from.upgrade().unwrap().clone().borrow();
-        // let to = to.upgrade().unwrap().clone().borrow();
-        for i in 0..from.links.len() {
-            let exit = from.links[i].borrow();
-            let link = self.get_link(exit.link_id);
-            if let Some(origin) = link.origin {
-                if let Some(dest) = link.destination {
-                    if self.get_junc(origin).borrow().id == from.id && self.get_junc(dest).borrow().id == to.id {
-                        return i;
-                    }
-                    if self.get_junc(origin).borrow().id == to.id && self.get_junc(dest).borrow().id == from.id {
-                        return i;
-                    }
-                }
+    fn route_start(&self, route:&Route) -> Option<LogicalCoord> {
+        match &route.start {
+            RouteStart::Link(link) => {
+                self.link(*link)?;
+                Some(LogicalCoord::new(LogicalAddress::for_link(*link), route.offset, route.distance, 0.0))
+            }
+            RouteStart::Place(name) => {
+                let place = self.place_coord(self.place(name)?)?;
+                Some(LogicalCoord::new(place.addr, place.offset + route.offset, place.distance + route.distance, place.loft))
             }
         }
-        return usize::max_value();
     }
+}
 
-    fn dummy(&self, junc:&Junction, link:&Link, exit:u32, dest_junc:u32) -> () {
-        println!("{} {} {} {}", junc.id, link.id, exit, dest_junc);
+// The traversal state of a route part way through evaluation.
+struct RouteSteps<'a, N:RouteTopology = Network> {
+    network:&'a N,
+    route:&'a Route,
+    link:Option<&'a Link>,
+    trav_dir:i32,
+    pattern:usize,
+    turn_num:u32,
+    // Distance covered since the current pattern started, beginning with the rest of the start link.
+    travelled:f64,
+    // Distance to the next junction and to the last one a step was taken at.
+    reached:f64,
+    total:f64,
+    // Junction and exit pairs taken by the current Always pattern, which would repeat forever once one recurs.
+    visited:HashSet<(u32, usize)>,
+    // The exit index the last step entered its junction by.
+    entry:usize,
+    steps:usize,
+    max_steps:usize,
+    // Why each pattern given up before making all its turns was given up.
+    shortfalls:Vec<String>
+}
+
+impl<'a, N:RouteTopology> RouteSteps<'a, N> {
+    fn new(network:&'a N, route:&'a Route) -> RouteSteps<'a, N> {
+        let start = network.route_start(route);
+        let link = start.as_ref().and_then(|start| network.link(start.addr.id.link));
+        let travelled = match (link, start) {
+            (Some(link), Some(start)) if route.trav_dir != -1 => network.length(link) - start.distance,
+            (Some(_), Some(start)) => start.distance,
+            _ => 0.0
+        };
+        RouteSteps {
+            network,
+            route,
+            link,
+            trav_dir:route.trav_dir,
+            pattern:0,
+            turn_num:0,
+            travelled,
+            reached:travelled,
+            total:0.0,
+            visited:HashSet::new(),
+            entry:usize::MAX,
+            steps:0,
+            max_steps:usize::MAX,
+            shortfalls:Vec::new()
+        }
     }
 
-    pub fn evaluate_route(&self, route:&Route) -> Vec<(u32, usize)> {
-        let mut v = Vec::new();
-        let mut pos = LogicalCoord::empty();
-        pos.offset = route.offset;
-        pos.distance = route.distance;
-        let mut link = self.get_link(route.start_link);
-        let mut trav_dir = route.trav_dir;
-        for i in 0..route.patterns.len() {
-            let mut num_turns:u32 = u32::MAX;
-            match route.patterns[i].count {
+    fn next_pattern(&mut self) {
+        self.pattern += 1;
+        self.turn_num = 0;
+        self.visited.clear();
+    }
+
+    // Moves on from a pattern that can go no further. Always patterns have no number of turns to
+    // fall short of, so only the others are recorded.
+    fn give_up(&mut self, num_turns:Option<u32>, reason:&str) {
+        if let Some(num_turns) = num_turns {
+            let pattern = &self.route.patterns[self.pattern];
+            self.shortfalls.push(format!("{} stopped after {} of {} turns: {}", pattern, self.turn_num, num_turns, reason));
+        }
+        self.next_pattern();
+    }
+}
+
+impl<'a, N:RouteTopology> Iterator for RouteSteps<'a, N> {
+    type Item = (u32, usize, i16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let link = self.link?;
+        if self.steps >= self.max_steps {
+            return None;
+        }
+        while self.pattern < self.route.patterns.len() {
+            let pattern = &self.route.patterns[self.pattern];
+            // No number of turns means Always.
+            let mut num_turns:Option<u32> = None;
+            let mut min_distance = 0.0;
+            match pattern.count {
                 TurnMultiplicity::Count(count) => {
-                    num_turns = count;
+                    num_turns = Some(count);
                 }
-                _ => {
-                    // Do nothing yet.
+                TurnMultiplicity::Distance(distance) => {
+                    // Carry straight on until the distance is covered, then turn once.
+                    num_turns = Some(1);
+                    min_distance = distance;
                 }
-
+                TurnMultiplicity::Always => {}
             }
-            let mut turn_num = 0;
-            loop {
-                let mut junc = link.destination;
-                let mut incoming_heading = 0.0;
-                if trav_dir == -1 {
-                    if let Some(segment) = self.first_segment_for_link(link) {
-                        incoming_heading = find_reciprocal_heading(segment.h);
-                    }
-                    junc = link.origin;
-                }
-                else {
-                    if let Some(segment) = self.last_segment_for_link(link) {
-                        incoming_heading = segment.h;
-                    }
-                }
-                if let Some(upcoming_junc) = junc {
-                    let upcoming_junc = self.get_junc(upcoming_junc);
-                    let entry = upcoming_junc.borrow().find_entry(incoming_heading);
-                    let mut exit_index = usize::MAX;
-                    match &route.patterns[i].turn {
-                        Turn::Relative(dir) => {
-                            exit_index = upcoming_junc.borrow().find_exit_from_turn_direction(entry, *dir);
-                        }
-                        Turn::Compass(dir) => {
-                            exit_index = upcoming_junc.borrow().find_exit_from_compass(*dir);
-                        }
-                        Turn::Exit(relative_exit) => {
-                            exit_index = upcoming_junc.borrow().find_relative_exit(entry, *relative_exit as usize)
-                        }
-                        Turn::Heading(heading) => {
-                            exit_index = upcoming_junc.borrow().find_exit_from_heading(*heading as f64)
-                        }
-                    }
-                    if exit_index != usize::MAX {
-                        v.push((upcoming_junc.borrow().id, exit_index));
-                        let exit = upcoming_junc.borrow().links[exit_index].clone();
-                        link = self.get_link(exit.borrow().link_id);
-                        if let Some(origin) = link.origin {
-                            if origin == upcoming_junc.borrow().id {
-                                trav_dir = 1;
-                            }
-                        }
-                        if let Some(destination) = link.destination {
-                            if destination == upcoming_junc.borrow().id {
-                                trav_dir = -1;
-                            }
-                        }
-                    }
-                    else {
-                        break;
-                    }
-                    turn_num += 1;
-                    if turn_num == num_turns {
-                        break;
-                    }
+            // Count:0 asks for no turns at all, so the pattern is skipped without stepping.
+            if num_turns == Some(0) {
+                self.next_pattern();
+                continue;
+            }
+            let (junc, incoming_heading) = self.network.approach(link, self.trav_dir);
+            let turning = self.travelled >= min_distance;
+            let turn = if turning { &pattern.turn } else { &Turn::Relative(TurnDirection::Straight) };
+            let choice = junc.and_then(|junc| self.network.with_junction(junc, |upcoming_junc| {
+                let entry = upcoming_junc.find_entry(incoming_heading);
+                let exit_index = match turn {
+                    Turn::Relative(dir) => upcoming_junc.find_exit_from_turn_direction(entry, *dir),
+                    Turn::Compass(dir) => upcoming_junc.find_exit_from_compass(*dir),
+                    Turn::Exit(relative_exit) => upcoming_junc.find_relative_exit(entry, *relative_exit as i32),
+                    Turn::Heading(heading) => upcoming_junc.find_exit_from_heading(*heading)
+                };
+                (upcoming_junc.id, entry, exit_index, upcoming_junc.links.get(exit_index).map(|exit| exit.link_id))
+            }));
+            let Some((junc_id, entry, exit_index, exit_link)) = choice else {
+                self.give_up(num_turns, "no junction ahead");
+                continue;
+            };
+            let Some(exit_link) = exit_link else {
+                self.give_up(num_turns, &format!("no exit that way at junction {}", junc_id));
+                continue;
+            };
+            // Banned turns already chosen by compass, heading or exit number end the pattern.
+            if self.network.with_junction(junc_id, |junc| junc.is_turn_restricted(entry, exit_index)).unwrap_or(false) {
+                self.give_up(num_turns, &format!("turn onto link {} is restricted at junction {}", exit_link, junc_id));
+                continue;
+            }
+            let Some((next_link, trav_dir)) = self.network.depart(junc_id, exit_link, self.trav_dir) else {
+                self.give_up(num_turns, &format!("link {} can't be taken from junction {}", exit_link, junc_id));
+                continue;
+            };
+            if num_turns.is_none() && !self.visited.insert((junc_id, exit_index)) {
+                self.next_pattern();
+                continue;
+            }
+            self.steps += 1;
+            self.entry = entry;
+            self.total = self.reached;
+            self.link = Some(next_link);
+            self.trav_dir = trav_dir;
+            self.travelled += self.network.length(next_link);
+            self.reached += self.network.length(next_link);
+            if turning {
+                self.turn_num += 1;
+            }
+            if num_turns == Some(self.turn_num) {
+                self.travelled = self.network.length(next_link);
+                self.next_pattern();
+            }
+            return Some((junc_id, exit_index, self.route.lane));
+        }
+        None
+    }
+}
+
+// A uniform grid over the segments of a network, so nearest-segment queries only look at the
+// segments in cells around the point. Each segment is filed under every cell its bounds touch.
+pub struct SpatialIndex {
+    cell_size:f64,
+    // Indices into the segments the index was built from.
+    cells:HashMap<(i64, i64), Vec<usize>>,
+    // The lowest and highest cells holding any segment.
+    min:(i64, i64),
+    max:(i64, i64)
+}
+
+impl SpatialIndex {
+    pub fn new(segments:&[Box<Segment>], cell_size:f64) -> SpatialIndex {
+        let mut index = SpatialIndex { cell_size, cells:HashMap::new(), min:(i64::MAX, i64::MAX), max:(i64::MIN, i64::MIN) };
+        for (position, segment) in segments.iter().enumerate() {
+            let (low, high) = Self::bounds(segment);
+            let (low, high) = (index.cell_of(low.0, low.1), index.cell_of(high.0, high.1));
+            for cx in low.0..=high.0 {
+                for cy in low.1..=high.1 {
+                    index.cells.entry((cx, cy)).or_default().push(position);
                 }
             }
+            index.min = (index.min.0.min(low.0), index.min.1.min(low.1));
+            index.max = (index.max.0.max(high.0), index.max.1.max(high.1));
         }
-        v
+        index
     }
 
-    fn build_routes(&mut self) {
-        // for junc in &self.junctions {
-        //     junc.build_routes(self, &mut self.routing.borrow_mut());
-        // }
-        // let print_step = |junc:Rc<RefCell<Junction>>, link:&Link, exit:u32, dest_junc:u32, path:&Vec<(u32,u32)>| {
-        //     // self.routing.borrow_mut().hops.insert(Hop::from(junc.id,
-        //     //                                                 LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
-        //     //                                                 LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
-        //     //                                                 exit
-        //     // )
-        //     // );
-        //     // For each outgoing link reachable directly from dest_junc, add a route from origin to origin via link
-        //     //let dest_junc = self.get_junc(dest_junc);
-        //     // for outgoing_exit in &dest_junc.outgoing {
-        //     //     let outgoing_link = self.get_link(outgoing_exit.link_id);
-        //     //     self.routing.borrow_mut().hops.insert(Hop::from(junc.id,
-        //     //     LogicalAddress::new(Identifier::new(outgoing_link.id, 0, 0, 0), Mask::new(true, false, false, false)),
-        //     //     LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
-        //     //     exit
-        //     //     ));
-        //     //     println!("Add route: {} {} {} {}", junc.id, outgoing_exit.link_id, link.id, exit);
-        //     // }
-        //     if let Some(last_junc) = path.last() {
-        //         let last_junc = self.get_junc(last_junc.0);
-        //
-        //         if last_junc.borrow().links.is_empty() {
-        //
-        //             // Iterate over path, adding routes
-        //             for i in 0..path.len() {
-        //                 println!("path: junc {} exit {}", path[i].0, path[i].1);
-        //                 let src_junc = self.get_junc(path[i].0);
-        //                 for j in i+1..path.len() {
-        //                     let dest_junc = self.get_junc(path[j].0);
-        //                     if path[i].0 != path[j].0 && path[i].1 != 270 {
-        //                         //println!("origin_junc: {} dest_junc: {} exit {}", src_junc.id, dest_junc.id, path[i].1);
-        //
-        //                         println!("Add route from {} to {} via {} exit {}", src_junc.borrow().id, dest_junc.borrow().id, path[i].0, path[i].1);
-        //                         self.routing.borrow_mut().hops.insert(Hop::from(src_junc.borrow().id, dest_junc.borrow().id, path[i].1));
-        //                     }
-        //                 }
-        //             }
-        //         }
-        //     }
-        // };
-        // self.depth_first_traversal(&print_step, |junc:Rc<RefCell<Junction>>| println!("{}", junc.borrow().id));
-        let build = |node:Rc<RefCell<SpanningNode>>| {
-            if node.borrow().children.is_empty() {
-                let mut root:Weak<RefCell<SpanningNode>> = Rc::downgrade(&node);
-                let mut path:Vec<Rc<RefCell<SpanningNode>>> = vec![];
-                while let Some(parent) = root.upgrade() {
-                    root = parent.borrow().parent.clone();
-                    path.push(parent);
-                }
-                path.reverse();
-                for i in 0..path.len() {
-                    let src_junc = &path[i].borrow().value.upgrade().clone().unwrap().borrow().clone();
-                    println!("path: junc {}", src_junc.id);
-                    if i+1<path.len() {
-                        let next_hop = &path[i + 1].borrow().value.upgrade().clone().unwrap().borrow().clone();
-                        let exit_index = self.find_exit(src_junc, next_hop);
-                        if exit_index != usize::max_value() {
-                            let exit = src_junc.links[exit_index].clone();
-                            self.routing.borrow_mut().hops.insert(Hop::from(src_junc.id, next_hop.id, exit.borrow().exit));
-                            for j in i + 2..path.len() {
-                                let dest_junc = &path[j].borrow().value.upgrade().unwrap().borrow().clone();
-                                if src_junc.id != dest_junc.id && exit.borrow().exit != 270 {
-                                    //println!("origin_junc: {} dest_junc: {} exit {}", src_junc.id, dest_junc.id, path[i].1);
-
-                                    println!("Add route from {} to {} via {} exit {}", src_junc.id, dest_junc.id, src_junc.id, exit.borrow().exit);
-                                    self.routing.borrow_mut().hops.insert(Hop::from(src_junc.id, dest_junc.id, exit.borrow().exit));
-                                }
-                            }
-                        } else {
-                            println!("Warning team:No exit from {} to {}", src_junc.id, next_hop.id);
-                        }
-                    }
-                }
+    // Straights lie between their ends; anything else stays within its length of its start.
+    fn bounds(segment:&Segment) -> ((f64, f64), (f64, f64)) {
+        match segment.segment_type {
+            SegmentType::Straight => {
+                let end = Network::segment_end(segment);
+                ((segment.x.min(end.x), segment.y.min(end.y)), (segment.x.max(end.x), segment.y.max(end.y)))
             }
-        };
-        SpanningNode::depth_first_traversal(self.spanning_tree.clone(),&build);
+            _ => ((segment.x - segment.length(), segment.y - segment.length()), (segment.x + segment.length(), segment.y + segment.length()))
+        }
     }
 
-    fn build_spanning_tree(&mut self) -> () {
-        let parent_stack:RefCell<Vec<Rc<RefCell<SpanningNode>>>> = RefCell::from(Vec::new());
-        parent_stack.borrow_mut().push(Rc::from(RefCell::from(SpanningNode::new(Weak::new(), Rc::downgrade(&(self.junctions[0].clone()))))));
-        let build = |junc:Rc<RefCell<Junction>>| {//, link:&Link, exit:u32, dest_junc:u32, path:&Vec<(u32,u32)>| {
-            let mut parent_stack = parent_stack.borrow_mut();
-            if let Some(top) = parent_stack.deref().last() {
-                let child = Rc::from(RefCell::new(SpanningNode::new(Rc::downgrade(&top.clone()), Rc::downgrade(&junc.clone()))));
-                top.borrow_mut().children.push(child.clone());
-                parent_stack.push(child.clone());
+    fn overlaps(segment:&Segment, min:&InertialCoord, max:&InertialCoord) -> bool {
+        let (low, high) = Self::bounds(segment);
+        low.0 <= max.x && high.0 >= min.x && low.1 <= max.y && high.1 >= min.y
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    pub fn cell_of(&self, x:f64, y:f64) -> (i64, i64) {
+        ((x / self.cell_size).floor() as i64, (y / self.cell_size).floor() as i64)
+    }
+
+    // The segments filed in cells exactly ring cells away from the point's cell, in index order.
+    pub fn segments_in_ring(&self, point:&InertialCoord, ring:i64) -> Vec<usize> {
+        let (px, py) = self.cell_of(point.x, point.y);
+        let mut found = HashSet::new();
+        for cx in px - ring..=px + ring {
+            for cy in py - ring..=py + ring {
+                if (cx - px).abs() != ring && (cy - py).abs() != ring {
+                    continue;
+                }
+                if let Some(positions) = self.cells.get(&(cx, cy)) {
+                    found.extend(positions);
+                }
             }
-        };
-        if let Some(root) = parent_stack.borrow_mut().last() {
-            self.spanning_tree = root.clone();
         }
-        let empty = |junc:Rc<RefCell<Junction>>, link:&Link, exit:u32, origin:u32, path:&Vec<(u32,u32)>| {
-        };
-        self.depth_first_traversal(&empty, &build);
+        let mut found:Vec<usize> = found.into_iter().collect();
+        found.sort();
+        found
     }
 
-    fn depth_first_traversal_helper<LinkFunc, JuncFunc>(& self, junc:Rc<RefCell<Junction>>, visited:&mut HashSet<u32>, path: &mut Vec<(u32,u32)>, link_func:&LinkFunc, junc_func:&JuncFunc) -> ()
-    where LinkFunc : Fn(Rc<RefCell<Junction>>, &Link, u32, u32, &Vec<(u32,u32)>),
-        JuncFunc: Fn(Rc<RefCell<Junction>>)
-    {
-        if !visited.contains(&junc.borrow().id) {
-            visited.insert(junc.borrow().id);
-            for exit in &junc.borrow().links {
-                let link = self.get_link(exit.borrow().link_id);
-                let dest_junc = link.destination;
-                if let Some(origin) = link.origin && dest_junc.is_some() {
-                    path.push((dest_junc.unwrap(),exit.borrow().exit));
-                    let destination = self.get_junc(dest_junc.unwrap());
-                    let origin = self.get_junc(origin);
-                    if !visited.contains(&destination.borrow().id) {
-                        junc_func(destination.clone());
-                        link_func(destination.clone(), link, exit.borrow().exit, origin.borrow().id, path);
-                        self.depth_first_traversal_helper(destination, visited, path, link_func, junc_func);
-                    }
+    // The segments filed in any cell the box touches, in index order.
+    pub fn segments_in_box(&self, min:&InertialCoord, max:&InertialCoord) -> Vec<usize> {
+        let (low, high) = (self.cell_of(min.x, min.y), self.cell_of(max.x, max.y));
+        let mut found = HashSet::new();
+        // Only visit cells that can hold segments, however big the box is.
+        for cx in low.0.max(self.min.0)..=high.0.min(self.max.0) {
+            for cy in low.1.max(self.min.1)..=high.1.min(self.max.1) {
+                if let Some(positions) = self.cells.get(&(cx, cy)) {
+                    found.extend(positions);
                 }
             }
-
-            path.pop();
         }
+        let mut found:Vec<usize> = found.into_iter().collect();
+        found.sort();
+        found
     }
 
-    pub fn depth_first_traversal<LinkFunc, JuncFunc>(&self, link_func:&LinkFunc, junc_func:JuncFunc) -> ()
-    where LinkFunc: Fn(Rc<RefCell<Junction>>, &Link, u32, u32, &Vec<(u32,u32)>),
-        JuncFunc: Fn(Rc<RefCell<Junction>>)
-    {
-        let mut visited: HashSet<u32> = HashSet::new();
-        let mut path:Vec<(u32,u32)> = Vec::new();
-        if !self.junctions.is_empty() {
-            let junc = self.get_junc(1);
-            self.depth_first_traversal_helper(junc, &mut visited, &mut path, link_func, &junc_func);
+    // Grows rings of cells out from the point until nothing further out could be closer than the best found.
+    fn nearest(&self, segments:&[Box<Segment>], point:&InertialCoord) -> Option<(usize, f64)> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let (px, py) = self.cell_of(point.x, point.y);
+        let reach = [px - self.min.0, self.max.0 - px, py - self.min.1, self.max.1 - py].into_iter().max().unwrap_or(0).max(0);
+        let mut nearest:Option<(usize, f64)> = None;
+        for ring in 0..=reach {
+            for position in self.segments_in_ring(point, ring) {
+                let (_, gap) = Network::nearest_on_segment(&segments[position], point);
+                if nearest.is_none_or(|(best_position, best)| gap < best || (gap == best && position < best_position)) {
+                    nearest = Some((position, gap));
+                }
+            }
+            // Cells beyond this ring are at least ring cells away from the point.
+            if nearest.is_some_and(|(_, best)| best < ring as f64 * self.cell_size) {
+                break;
+            }
         }
+        nearest
     }
+}
 
-    pub fn empty() -> Network {
+// A place a trace point might have been, on one of the segments near it.
+struct MatchCandidate {
+    segment:usize,
+    link:u16,
+    link_offset:f64,
+    tile_offset:f64,
+    // How far the point is from the segment.
+    gap:f64,
+    // Whether the link is being travelled from its origin to its destination.
+    forwards:bool
+}
+
+pub struct Network {
+    links : Vec<Box<Link>>,
+    junctions : Vec<Rc<RefCell<Junction>>>,
+    tiles: Vec<Box<Tile>>,
+    segments: Vec<Box<Segment>>,
+    // One for each Junction
+    routing: RefCell<Routing>,
+    spanning_tree: Rc<RefCell<SpanningNode>>,
+    places: Vec<Place>,
+    options: NetworkImportOptions,
+    // Indices into segments for each link, in tile then segment order; rebuilt when tiles or segments are set.
+    link_segments: HashMap<u16, Vec<usize>>,
+    // Dropped whenever the segments change.
+    spatial_index: Option<SpatialIndex>,
+    // Tables left out of a lenient load.
+    load_warnings: Vec<NetworkWarning>
+}
+
+impl<'a> Network {
+    pub fn new(links:Vec<Box<Link>>, junctions:Vec<Rc<RefCell<Junction>>>) -> Network {
         Network {
-            links:Vec::new(),
-            junctions:Vec::new(),
+            links,
+            junctions,
             tiles: Vec::new(),
-            segments:Vec::new(),
+            segments: Vec::new(),
             routing:RefCell::new(Routing::new()),
-            spanning_tree:Rc::new(RefCell::from(SpanningNode::empty()))
+            spanning_tree: Rc::new(RefCell::new(SpanningNode::empty())),
+            places: Vec::new(),
+            options: NetworkImportOptions::default(),
+            link_segments: HashMap::new(),
+            spatial_index: None,
+            load_warnings: Vec::new()
         }
     }
 
-    pub fn route(&self, junc_id: u32, src_junc:u32, dest_junc:u32, to_dest:bool) -> Option<Hop> {
-        let src_junc = self.get_junc(src_junc);
-        // let origin = src_link.origin;
-        // let dest = src_link.destination;
+    pub fn from(connection:&Connection) -> Network {
+        Network::from_with_options(connection, NetworkImportOptions::default())
+    }
 
-        for hop in &self.routing.borrow().hops {
-            let junc = self.get_junc(hop.junction);
-            let dest = self.get_junc(hop.dest_junc);
-            if  junc.borrow().id == junc_id && dest.borrow().id == dest_junc && to_dest {
-                return Some(*hop);
-            }
-            if junc.borrow().id == junc_id && dest.borrow().id == src_junc.borrow().id && !to_dest {
-                return Some(*hop);
-            }
-        }
-        None
+    pub fn from_with_options(connection:&Connection, options:NetworkImportOptions) -> Network {
+        Network::from_with_schema_and_options(connection, &DEFAULT_SCHEMA, options)
     }
 
-    pub fn get_link(&self, id:u16) -> &Link {
-        &self.links[(id-1) as usize]
+    pub fn from_with_schema(connection:&Connection, schema:&SchemaMapping) -> Network {
+        Network::from_with_schema_and_options(connection, schema, NetworkImportOptions::default())
     }
 
-    pub fn get_link_mut(&mut self, id:u16) -> &mut Link {
-        &mut self.links[(id-1) as usize]
+    pub fn from_with_schema_and_options(connection:&Connection, schema:&SchemaMapping, options:NetworkImportOptions) -> Network {
+        let link_gw:LinkGateway = LinkGateway::with_schema(connection, schema);
+        let junc_gw:JunctionGateway = JunctionGateway::with_schema(connection, schema);
+        let tile_gw: TileGateway = TileGateway::with_schema(connection, schema);
+        let seg_gw : SegmentGateway = SegmentGateway::with_schema(connection, schema);
+        let place_gw : PlaceGateway = PlaceGateway::with_schema(connection, schema);
+        let mut warnings = Vec::new();
+        let mut network = Network::empty();
+        network.set_import_options(options);
+        network.set_links(read_table_leniently("links", link_gw.find_all(), &mut warnings));
+        network.set_junctions(read_table_leniently("junctions", junc_gw.find_all(), &mut warnings));
+        network.set_junction_connections(&mut read_table_leniently("junctions_links", junc_gw.find_connections(), &mut warnings));
+        if options.reciprocal_exits {
+            network.add_reciprocal_exits();
+        }
+        network.set_tiles(read_table_leniently("tiles", tile_gw.find_all(), &mut warnings));
+        network.set_segments(read_table_leniently("segments", seg_gw.find_all(), &mut warnings));
+        network.set_places(read_table_leniently("places", place_gw.find_all(), &mut warnings));
+        network.set_turn_restrictions(&read_table_leniently("turn_restrictions", TurnRestrictionGateway::with_schema(connection, schema).find_all(), &mut warnings));
+        network.load_warnings = warnings;
+        network.build_spanning_tree();
+        network.build_all_pairs_routing();
+        network
     }
 
-    pub fn add_link(&mut self, link:Box<Link>) {
-        self.links.push(link);
+    // Only the links with a segment starting or ending inside the box, whole, with the junctions at
+    // their ends and the exits, places and turn restrictions that stay among them. The box is tested
+    // in SQL, so everything else is left in the database rather than being loaded and thrown away.
+    // Segment ends are taken straight along the heading for the stored length, as segment_end takes
+    // them, by a function registered on the connection. A table that can't be read is an error.
+    pub fn from_region(connection:&Connection, min:InertialCoord, max:InertialCoord) -> Result<Network, ImportError> {
+        Network::from_region_with_schema(connection, &DEFAULT_SCHEMA, min, max)
     }
 
-    pub fn set_links(&mut self, links:Vec<Box<Link>>) {
-        self.links = links;
+    pub fn from_region_with_schema(connection:&Connection, schema:&SchemaMapping, min:InertialCoord, max:InertialCoord) -> Result<Network, ImportError> {
+        let segments = schema.select("segments");
+        let columns:Vec<String> = match connection.prepare(&segments) {
+            Ok(statement) => statement.column_names().into_iter().map(String::from).collect(),
+            // Without a segments table nothing is inside.
+            Err(e) => return read_table::<()>("segments", Err(e)).map(|_| Network::empty())
+        };
+        // Older databases have no length column.
+        let length = if columns.iter().any(|column| column == "length") { "length" } else { "0" };
+        let deterministic = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+        for (name, axis) in [("lrn_segment_end_x", 0), ("lrn_segment_end_y", 1)] {
+            // NULL in, NULL out, as with SQL's own functions.
+            connection.create_scalar_function(name, 3, deterministic, move |ctx| {
+                let args = (ctx.get::<Option<f64>>(0)?, ctx.get::<Option<f64>>(1)?, ctx.get::<Option<f64>>(2)?);
+                let (Some(start), Some(heading), Some(length)) = args else {
+                    return Ok(None);
+                };
+                let (dx, dy) = heading_to_direction(heading);
+                let along = if axis == 0 { dx } else { dy };
+                Ok(Some(start + along * length))
+            }).map_err(|e| ImportError::BadRow { table:"segments", reason:e.to_string() })?;
+        }
+        // Infinite corners would be written as inf, which isn't SQL.
+        let number = |value:f64| format!("{:?}", value.clamp(f64::MIN, f64::MAX));
+        let inside = |x:&str, y:&str| format!("({} BETWEEN {} AND {} AND {} BETWEEN {} AND {})", x, number(min.x), number(max.x), y, number(min.y), number(max.y));
+        let tiles_inside = format!("SELECT tile_id FROM ({segments}) WHERE {} OR {}",
+            inside("x", "y"), inside(&format!("lrn_segment_end_x(x, h, {length})"), &format!("lrn_segment_end_y(y, h, {length})")));
+        let tiles = schema.select("tiles");
+        let links = format!("SELECT link_id FROM ({tiles}) WHERE id IN ({tiles_inside})");
+        let link_table = schema.select("links");
+        let junctions = format!("SELECT origin FROM ({link_table}) WHERE id IN ({links}) UNION SELECT destination FROM ({link_table}) WHERE id IN ({links})");
+        let mut schema = schema.clone();
+        schema.filter("links", format!("id IN ({links})"));
+        schema.filter("junctions", format!("id IN ({junctions})"));
+        schema.filter("junctions_links", format!("link_id IN ({links}) AND junc_id IN ({junctions})"));
+        schema.filter("tiles", format!("link_id IN ({links})"));
+        schema.filter("segments", format!("tile_id IN (SELECT id FROM ({tiles}) WHERE link_id IN ({links}))"));
+        schema.filter("places", format!("link_id IN ({links})"));
+        schema.filter("turn_restrictions", format!("entry_link IN ({links}) AND exit_link IN ({links})"));
+        Network::try_from_with_schema_and_options(connection, &schema, NetworkImportOptions::default())
     }
 
-    pub fn set_junctions(&mut self, junctions:Vec<Rc<RefCell<Junction>>>) {
-        self.junctions = junctions;
+    pub fn try_from(connection:&Connection) -> Result<Network, ImportError> {
+        Network::try_from_with_options(connection, NetworkImportOptions::default())
     }
 
-    pub fn set_tiles(&mut self, tiles:Vec<Box<Tile>>) {
-        self.tiles = tiles;
+    // Like from_with_options, but a table that can't be read is an error rather than being left out
+    // with a warning. Missing tables still load as empty ones.
+    pub fn try_from_with_options(connection:&Connection, options:NetworkImportOptions) -> Result<Network, ImportError> {
+        Network::try_from_with_schema_and_options(connection, &DEFAULT_SCHEMA, options)
     }
-    pub fn set_junction_connections(&mut self, connections: &mut Vec<(u32, u16, u32)>) {
-        for connection in connections {
-        self.get_junc_mut(connection.0).borrow_mut().add_link(connection.1, connection.2);
+
+    pub fn try_from_with_schema_and_options(connection:&Connection, schema:&SchemaMapping, options:NetworkImportOptions) -> Result<Network, ImportError> {
+        let junc_gw:JunctionGateway = JunctionGateway::with_schema(connection, schema);
+        let mut network = Network::empty();
+        network.set_import_options(options);
+        network.set_links(read_table("links", LinkGateway::with_schema(connection, schema).find_all())?);
+        network.set_junctions(read_table("junctions", junc_gw.find_all())?);
+        network.set_junction_connections(&mut read_table("junctions_links", junc_gw.find_connections())?);
+        if options.reciprocal_exits {
+            network.add_reciprocal_exits();
         }
+        network.set_tiles(read_table("tiles", TileGateway::with_schema(connection, schema).find_all())?);
+        network.set_segments(read_table("segments", SegmentGateway::with_schema(connection, schema).find_all())?);
+        network.set_places(read_table("places", PlaceGateway::with_schema(connection, schema).find_all())?);
+        network.set_turn_restrictions(&read_table("turn_restrictions", TurnRestrictionGateway::with_schema(connection, schema).find_all())?);
+        network.build_spanning_tree();
+        network.build_all_pairs_routing();
+        Ok(network)
     }
 
-    pub fn set_segments(&mut self , segments:Vec<Box<Segment>>) {
-        self.segments = segments;
+    #[cfg(feature = "geojson")]
+    pub fn from_geojson(json:&str) -> Result<Network, ImportError> {
+        Network::from_geojson_with_options(json, NetworkImportOptions::default())
     }
 
-    pub fn num_links(&self) -> usize {
-        self.links.len()
+    // Links and junctions are numbered from 1 in the order their LineString and Point features appear.
+    // A link starts or ends at the first junction whose point is within the snap tolerance of its end.
+    #[cfg(feature = "geojson")]
+    pub fn from_geojson_with_options(json:&str, options:NetworkImportOptions) -> Result<Network, ImportError> {
+        let document:serde_json::Value = serde_json::from_str(json).map_err(|e| ImportError::BadJson { reason:e.to_string() })?;
+        if document["type"] != "FeatureCollection" {
+            return Err(ImportError::NotFeatureCollection);
+        }
+        let features = document["features"].as_array().ok_or(ImportError::NotFeatureCollection)?;
+        // Each with the index of its feature.
+        let mut lines:Vec<(usize, Vec<InertialCoord>)> = Vec::new();
+        let mut points:Vec<Option<InertialCoord>> = Vec::new();
+        for (index, feature) in features.iter().enumerate() {
+            let bad = |reason:&str| ImportError::BadFeature { index, reason:reason.to_string() };
+            let geometry = &feature["geometry"];
+            if geometry.is_null() {
+                // Only junctions can be unpositioned.
+                if feature["properties"]["kind"] == "junction" {
+                    points.push(None);
+                    continue;
+                }
+                return Err(bad("missing geometry"));
+            }
+            let coords = &geometry["coordinates"];
+            match geometry["type"].as_str() {
+                Some("LineString") => {
+                    let coords = coords.as_array().ok_or_else(|| bad("LineString has no coordinates"))?;
+                    let line = coords.iter().map(Self::geojson_position).collect::<Option<Vec<InertialCoord>>>()
+                        .ok_or_else(|| bad("invalid position"))?;
+                    if line.len() < 2 {
+                        return Err(bad("LineString needs at least two positions"));
+                    }
+                    lines.push((index, line));
+                }
+                Some("Point") => points.push(Some(Self::geojson_position(coords).ok_or_else(|| bad("invalid position"))?)),
+                _ => return Err(bad("unsupported geometry type"))
+            }
+        }
+        let find_junction = |coord:&InertialCoord| {
+            points.iter().position(|point| point.as_ref().is_some_and(|point| {
+                let tolerance = options.snap_distance;
+                (point.x - coord.x).abs() <= tolerance && (point.y - coord.y).abs() <= tolerance && (point.z - coord.z).abs() <= tolerance
+            })).map(|index| index as u32 + 1)
+        };
+        let junctions:Vec<Rc<RefCell<Junction>>> = (1..=points.len() as u32).map(|id| Rc::new(RefCell::new(Junction::new(id)))).collect();
+        let mut links = Vec::new();
+        let mut tiles = Vec::new();
+        let mut segments = Vec::new();
+        let mut connections = Vec::new();
+        for (position, (index, line)) in lines.iter().enumerate() {
+            let too_many = |what:&str| ImportError::BadFeature { index:*index, reason:format!("too many {}", what) };
+            let id = u16::try_from(position + 1).map_err(|_| too_many("links"))?;
+            let mut link = Link::new(id);
+            link.tiles.push(id);
+            tiles.push(Box::new(Tile::from_query(id, id)));
+            for pair in line.windows(2) {
+                let (dx, dy) = (pair[1].x - pair[0].x, pair[1].y - pair[0].y);
+                segments.push(Box::new(Segment {
+                    id:u16::try_from(segments.len() + 1).map_err(|_| too_many("segments"))?,
+                    tile:id,
+                    x:pair[0].x,
+                    y:pair[0].y,
+                    z:pair[0].z,
+                    h:Heading::new((-dx).atan2(dy).to_degrees()).degrees(),
+                    length:dx.hypot(dy),
+                    ..Segment::new()
+                }));
+            }
+            let first = &segments[segments.len() + 1 - line.len()];
+            let last = &segments[segments.len() - 1];
+            // Exits are the heading leaving the junction, so a link arrives on the reciprocal of its last heading.
+            let exit = |heading:Heading| (heading.degrees().round() as u32) % 360;
+            link.origin = find_junction(&line[0]);
+            if let Some(origin) = link.origin {
+                connections.push((origin, id, exit(Heading::new(first.h)), 0));
+            }
+            link.destination = find_junction(&line[line.len() - 1]);
+            if let Some(destination) = link.destination {
+                connections.push((destination, id, exit(Heading::new(last.h).reciprocal()), 0));
+            }
+            links.push(Box::new(link));
+        }
+        // Junction exits are kept in heading order, as find_connections loads them.
+        connections.sort_by_key(|connection| (connection.0, connection.2));
+        let mut network = Network::new(links, junctions);
+        network.set_import_options(options);
+        network.set_junction_connections(&mut connections);
+        network.set_tiles(tiles);
+        network.set_segments(segments);
+        network.build_spanning_tree();
+        network.build_all_pairs_routing();
+        Ok(network)
     }
 
-    pub fn num_junctions(&self) -> usize {
-        self.junctions.len()
+    #[cfg(feature = "geojson")]
+    fn geojson_position(position:&serde_json::Value) -> Option<InertialCoord> {
+        let position = position.as_array()?;
+        if position.len() < 2 {
+            return None;
+        }
+        let z = match position.get(2) {
+            Some(z) => z.as_f64()?,
+            None => 0.0
+        };
+        Some(InertialCoord::new(position[0].as_f64()?, position[1].as_f64()?, z))
     }
 
-    pub fn get_junc_mut(&mut self, id:u32) -> Rc<RefCell<Junction>> {
-        self.junctions[(id - 1) as usize].clone()
+    // Like from, but junctions come back from a single join with their exits attached, rather than being
+    // wired up afterwards one connection at a time.
+    pub fn from_optimized(connection:&Connection) -> Network {
+        let mut warnings = Vec::new();
+        let mut network = Network::empty();
+        network.set_links(read_table_leniently("links", LinkGateway::new(connection).find_all(), &mut warnings));
+        network.set_junctions(read_table_leniently("junctions", JunctionGateway::new(connection).find_all_with_connections(), &mut warnings));
+        network.set_tiles(read_table_leniently("tiles", TileGateway::new(connection).find_all(), &mut warnings));
+        network.set_segments(read_table_leniently("segments", SegmentGateway::new(connection).find_all(), &mut warnings));
+        network.set_places(read_table_leniently("places", PlaceGateway::new(connection).find_all(), &mut warnings));
+        network.set_turn_restrictions(&read_table_leniently("turn_restrictions", TurnRestrictionGateway::new(connection).find_all(), &mut warnings));
+        network.load_warnings = warnings;
+        network.build_spanning_tree();
+        network.build_all_pairs_routing();
+        network
     }
 
-    pub fn get_junc(&self, id:u32) -> Rc<RefCell<Junction>> {
-        self.junctions[(id-1) as usize].clone()
+    pub fn first_segment_for_link(&self, link:&Link) -> Option<&Segment> {
+        let index = *self.link_segments.get(&link.id)?.first()?;
+        Some(&self.segments[index])
     }
 
-    pub fn get_junc_if_exists(&self, id: Option<u32>) -> Option<Rc<RefCell<Junction>>> {
-        if let Some(valid_id) = id {
-            Some(self.get_junc(valid_id))
-        }
-        else {
-            None
+    pub fn last_segment_for_link(&self, link:&Link) -> Option<&Segment> {
+        let index = *self.link_segments.get(&link.id)?.last()?;
+        Some(&self.segments[index])
+    }
+
+    // The segments on all of the link's tiles, in tile then segment order.
+    fn segments_for_link(&self, link:&Link) -> Vec<&Segment> {
+        match self.link_segments.get(&link.id) {
+            Some(indices) => indices.iter().map(|index| self.segments[*index].deref()).collect(),
+            None => Vec::new()
         }
     }
-    pub fn get_junc_if_exists_mut(&mut self, id: Option<u32>) -> Option<Rc<RefCell<Junction>>> {
-        if let Some(valid_id) = id {
-            Some(self.get_junc_mut(valid_id))
+
+    // Tiles go in seq order along their link and segments in seq order along their tile, whatever
+    // order the rows were loaded in, so the first and last segments are at the link's ends.
+    fn index_segments(&mut self) {
+        self.link_segments.clear();
+        let mut tile_segments:HashMap<u16, Vec<usize>> = HashMap::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            tile_segments.entry(segment.tile).or_default().push(index);
         }
-        else {
-            None
+        for indices in tile_segments.values_mut() {
+            indices.sort_by_key(|index| (self.segments[*index].seq, self.segments[*index].id));
+        }
+        let mut tiles:Vec<&Tile> = self.tiles.iter().map(|tile| tile.deref()).collect();
+        tiles.sort_by_key(|tile| (tile.seq, tile.id));
+        for tile in tiles {
+            if let Some(indices) = tile_segments.get(&tile.id) {
+                self.link_segments.entry(tile.link).or_default().extend(indices);
+            }
         }
     }
 
-    pub fn num_tiles(&self) -> usize {
-        self.tiles.len()
+    fn segment_end(segment:&Segment) -> InertialCoord {
+        let (dx, dy) = heading_to_direction(segment.h);
+        InertialCoord::new(segment.x + dx * segment.length(), segment.y + dy * segment.length(), segment.z)
     }
 
-    pub fn num_segments(&self) -> usize {
-        self.segments.len()
+    // The segment's geometry as a Curve measured from its start. Segments don't record which way an
+    // arc bends, so arcs are taken to turn left.
+    fn segment_curve(segment:&Segment) -> Curve {
+        let start = InertialCoord::new(segment.x, segment.y, segment.z);
+        match segment.segment_type {
+            SegmentType::Arc { radius } if radius > 0.0 => {
+                // A negative sweep turns right, about a centre on the right.
+                let sign = segment.sweep.map_or(1.0, f64::signum);
+                let (dx, dy) = heading_to_direction(segment.h);
+                let center = InertialCoord::new(segment.x - sign * dy * radius, segment.y + sign * dx * radius, segment.z);
+                let start_angle = (segment.y - center.y).atan2(segment.x - center.x).to_degrees();
+                Curve::arc(center, radius, start_angle, sign * (segment.length() / radius).to_degrees())
+            }
+            _ => Curve::straight(start, Self::segment_end(segment))
+        }
     }
-}
 
-pub struct NetworkBuilder {
-    links:Vec<Box<Link>>,
-    junctions:Vec<Rc<RefCell<Junction>>>,
-    next_junc:u32,
-    next_link:u16
-}
+    // The segments a logical address covers, in order along the link: the whole link, or one tile
+    // or one segment of it when the mask selects them.
+    fn segments_for_address(&self, addr:&LogicalAddress) -> Vec<&Segment> {
+        let Some(link) = self.get_link_checked(addr.id.link) else {
+            return Vec::new();
+        };
+        self.segments_for_link(link).into_iter()
+            .filter(|segment| !addr.mask.tile || segment.tile == addr.id.tile)
+            .filter(|segment| !addr.mask.segment || segment.id == addr.id.segment)
+            .collect()
+    }
 
-impl<'a> NetworkBuilder {
-    pub fn new() -> NetworkBuilder {
-        NetworkBuilder {
-            links:Vec::new(),
-            junctions:Vec::new(),
-            next_junc:0,
-            next_link:0
+    // The distance is measured along whatever the address selects, and None if it falls outside it.
+    pub fn logical_to_inertial(&self, coord:&LogicalCoord) -> Option<InertialCoord> {
+        if coord.distance < 0.0 {
+            return None;
+        }
+        let mut start = 0.0;
+        for segment in self.segments_for_address(&coord.addr) {
+            if coord.distance <= start + segment.length() {
+                let local = LogicalCoord::new(coord.addr, coord.offset, coord.distance - start, coord.loft);
+                let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+                Self::segment_curve(segment).logical_to_inertial(&local, &mut inertial);
+                return Some(inertial);
+            }
+            start += segment.length();
         }
+        None
     }
 
-    pub fn create_link(&mut self) {
-        self.links.push(Box::new(Link::new(self.next_link)));
-        self.next_link+=1;
-        if let Some(j) = self.junctions.last_mut() {
-            j.borrow_mut().links.push(Rc::new(RefCell::new(Exit{link_id:self.links.last().unwrap().id,exit:90})));
+    // The heading of travel at the coordinate, found the same way as logical_to_inertial finds its position.
+    pub fn heading_at_logical(&self, coord:&LogicalCoord) -> Option<f64> {
+        if coord.distance < 0.0 {
+            return None;
         }
+        let mut start = 0.0;
+        for segment in self.segments_for_address(&coord.addr) {
+            if coord.distance <= start + segment.length() {
+                return Some(segment.heading_at(coord.distance - start));
+            }
+            start += segment.length();
+        }
+        None
     }
 
-    pub fn add_junction(&mut self) {
-        self.junctions.push(Rc::new(RefCell::from(Junction::new(self.next_junc))));
-        self.next_junc += 1;
+    // The position on the segment nearest the point, measured from the segment's start, and how far
+    // the point is from it.
+    fn nearest_on_segment(segment:&Segment, point:&InertialCoord) -> (LogicalCoord, f64) {
+        let curve = Self::segment_curve(segment);
+        let mut logical = LogicalCoord::empty();
+        curve.inertial_to_logical(point, &mut logical);
+        if logical.distance < 0.0 || logical.distance > segment.length() {
+            logical.distance = logical.distance.clamp(0.0, segment.length());
+            let mut on_curve = InertialCoord::new(0.0, 0.0, 0.0);
+            let centre_line = LogicalCoord::new(logical.addr, 0.0, logical.distance, 0.0);
+            curve.logical_to_inertial(&centre_line, &mut on_curve);
+            let gap = (point.x - on_curve.x).hypot(point.y - on_curve.y);
+            return (logical, gap);
+        }
+        let gap = logical.offset.abs();
+        (logical, gap)
     }
 
-    pub fn add_straight(&mut self, _:InertialCoord, _:f64) {
+    // The tile of the segment closest to the point and the distance to it, using the spatial index
+    // if one has been built and looking at every segment otherwise. Ties go to the earlier segment.
+    pub fn nearest_segment(&self, point:&InertialCoord) -> Option<(u16, f64)> {
+        let nearest = match &self.spatial_index {
+            Some(index) => index.nearest(&self.segments, point),
+            None => self.nearest_segment_scan(point)
+        };
+        nearest.map(|(position, gap)| (self.segments[position].tile, gap))
+    }
 
+    fn nearest_segment_scan(&self, point:&InertialCoord) -> Option<(usize, f64)> {
+        let mut nearest:Option<(usize, f64)> = None;
+        for (position, segment) in self.segments.iter().enumerate() {
+            let (_, gap) = Self::nearest_on_segment(segment, point);
+            if nearest.is_none_or(|(_, best)| gap < best) {
+                nearest = Some((position, gap));
+            }
+        }
+        nearest
     }
 
-    pub fn build(self) -> Box<Network> {
-        Box::new(Network::new(self.links, self.junctions))
+    pub fn build_spatial_index(&mut self) {
+        self.spatial_index = Some(SpatialIndex::new(&self.segments, self.default_cell_size()));
     }
-}
 
-struct LinkGateway<'a> {
-    connection: &'a Connection,
+    // Cells are the mean segment length across, which keeps a few segments in each.
+    fn default_cell_size(&self) -> f64 {
+        let total:f64 = self.segments.iter().map(|segment| segment.length()).sum();
+        (total / self.segments.len().max(1) as f64).max(1.0)
+    }
 
-}
+    pub fn spatial_index(&self) -> Option<&SpatialIndex> {
+        self.spatial_index.as_ref()
+    }
 
-impl<'a> LinkGateway<'a> {
-    pub fn new(connection: &'a Connection) ->  LinkGateway<'a> {
-        LinkGateway {
-            connection
-        }
+    // The segments whose bounds overlap the box, in load order. Arcs are taken to reach their length
+    // from their start in every direction, so a few near the edges may not actually enter the box.
+    pub fn segments_in_bbox(&self, min:InertialCoord, max:InertialCoord) -> Vec<&Segment> {
+        let positions = match &self.spatial_index {
+            Some(index) => index.segments_in_box(&min, &max),
+            None => (0..self.segments.len()).collect()
+        };
+        positions.into_iter()
+            .map(|position| self.segments[position].deref())
+            .filter(|segment| SpatialIndex::overlaps(segment, &min, &max))
+            .collect()
     }
 
-    pub fn find_all(&self) -> Result<Vec<Box<Link>>, Error> {
-        let statement = self.connection.prepare("SELECT * FROM links;");
-        if let  Err(e) = statement {
-            return Err(e);
+    // Junctions whose position lies in the box, in id order. Junctions with no position are left out.
+    pub fn junctions_in_bbox(&self, min:InertialCoord, max:InertialCoord) -> Vec<Rc<RefCell<Junction>>> {
+        self.junctions()
+            .filter(|junc| {
+                let id = junc.borrow().id;
+                self.junction_position(id)
+                    .is_some_and(|position| position.x >= min.x && position.x <= max.x && position.y >= min.y && position.y <= max.y)
+            })
+            .collect()
+    }
+
+    // The most likely place on the network for each point of a trace, as a tile and the distance
+    // along it. Candidates are the segments in the cells around each point; the chosen sequence
+    // minimises the distance of each point from its candidate plus, between successive points, the
+    // difference between the distance travelled along the network and the straight-line distance.
+    // Moves are only allowed along a link without turning round, or from the end of a link onto
+    // another at the junction they share. One-way links are only travelled forwards. If no
+    // candidate can be reached from the previous point the sequence starts afresh.
+    pub fn map_match(&self, points:&[InertialCoord]) -> Vec<(u16, f64)> {
+        let built;
+        let index = match &self.spatial_index {
+            Some(index) => index,
+            None => {
+                built = SpatialIndex::new(&self.segments, self.default_cell_size());
+                &built
+            }
+        };
+        // Where each segment starts along its link and along its tile.
+        let mut starts:HashMap<usize, (u16, f64, f64)> = HashMap::new();
+        let mut link_lengths:HashMap<u16, f64> = HashMap::new();
+        for (link, positions) in &self.link_segments {
+            let mut start = 0.0;
+            let mut tile_starts:HashMap<u16, f64> = HashMap::new();
+            for position in positions {
+                let segment = &self.segments[*position];
+                let tile_start = *tile_starts.entry(segment.tile).or_insert(start);
+                starts.insert(*position, (*link, start, start - tile_start));
+                start += segment.length();
+            }
+            link_lengths.insert(*link, start);
         }
-        let mut statement = statement.unwrap();
-        let link_iter = statement.query_map([], |row| {
-            Ok(Link::from_query(row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap()))
-        });
-        let mut links = Vec::new();
-        for link in link_iter.unwrap() {
-            links.push(Box::new(link.unwrap()));
+        let mut candidates:Vec<Vec<MatchCandidate>> = Vec::new();
+        for point in points {
+            let mut positions = index.segments_in_ring(point, 0);
+            positions.extend(index.segments_in_ring(point, 1));
+            positions.extend(index.nearest(&self.segments, point).map(|(position, _)| position));
+            positions.sort();
+            positions.dedup();
+            let mut options = Vec::new();
+            for position in positions {
+                let Some((link, link_start, tile_start)) = starts.get(&position) else {
+                    continue;
+                };
+                let (logical, gap) = Self::nearest_on_segment(&self.segments[position], point);
+                let two_way = self.get_link_checked(*link).is_some_and(|link| !link.is_one_way());
+                for forwards in [true, false] {
+                    if forwards || two_way {
+                        options.push(MatchCandidate { segment:position, link:*link, link_offset:link_start + logical.distance, tile_offset:tile_start + logical.distance, gap, forwards });
+                    }
+                }
+            }
+            if options.is_empty() {
+                return Vec::new();
+            }
+            candidates.push(options);
+        }
+        let transition = |from:&MatchCandidate, to:&MatchCandidate, straight:f64| -> f64 {
+            // Turning round part way along a link isn't allowed.
+            let along = if from.link == to.link && from.forwards == to.forwards {
+                (to.link_offset - from.link_offset).abs()
+            } else if from.link == to.link {
+                return f64::INFINITY;
+            } else {
+                let (Some(from_link), Some(to_link)) = (self.get_link_checked(from.link), self.get_link_checked(to.link)) else {
+                    return f64::INFINITY;
+                };
+                let (leaving, left_by) = match from.forwards {
+                    true => (link_lengths[&from.link] - from.link_offset, from_link.destination),
+                    false => (from.link_offset, from_link.origin)
+                };
+                let (entering, entered_by) = match to.forwards {
+                    true => (to.link_offset, to_link.origin),
+                    false => (link_lengths[&to.link] - to.link_offset, to_link.destination)
+                };
+                if left_by.is_none() || left_by != entered_by {
+                    return f64::INFINITY;
+                }
+                leaving + entering
+            };
+            (along - straight).abs()
+        };
+        let mut costs:Vec<f64> = match candidates.first() {
+            Some(options) => options.iter().map(|option| option.gap).collect(),
+            None => return Vec::new()
+        };
+        let mut back:Vec<Vec<usize>> = vec![Vec::new()];
+        for step in 1..candidates.len() {
+            let straight = (points[step].x - points[step - 1].x).hypot(points[step].y - points[step - 1].y);
+            let mut next_costs = Vec::new();
+            let mut next_back = Vec::new();
+            for option in &candidates[step] {
+                let (previous, cost) = candidates[step - 1].iter().enumerate()
+                    .map(|(previous, from)| (previous, costs[previous] + transition(from, option, straight)))
+                    .fold((0, f64::INFINITY), |best, next| if next.1 < best.1 { next } else { best });
+                next_costs.push(option.gap + cost);
+                next_back.push(previous);
+            }
+            if next_costs.iter().all(|cost| cost.is_infinite()) {
+                let (previous, cost) = costs.iter().enumerate()
+                    .fold((0, f64::INFINITY), |best, (previous, cost)| if *cost < best.1 { (previous, *cost) } else { best });
+                next_costs = candidates[step].iter().map(|option| option.gap + cost).collect();
+                next_back = vec![previous; candidates[step].len()];
+            }
+            costs = next_costs;
+            back.push(next_back);
+        }
+        let mut chosen = costs.iter().enumerate()
+            .fold((0, f64::INFINITY), |best, (option, cost)| if *cost < best.1 { (option, *cost) } else { best }).0;
+        let mut matched = vec![(0, 0.0); candidates.len()];
+        for step in (0..candidates.len()).rev() {
+            let candidate = &candidates[step][chosen];
+            matched[step] = (self.segments[candidate.segment].tile, candidate.tile_offset);
+            if step > 0 {
+                chosen = back[step][chosen];
+            }
         }
-        Ok(links)
+        matched
     }
-}
-
-struct JunctionGateway<'a> {
-    connection: & 'a Connection,
-}
 
-impl<'a> JunctionGateway<'a> {
-    pub fn new(connection: &'a Connection) -> JunctionGateway<'a> {
-        JunctionGateway {
-            connection
+    // The link-relative position of the nearest point on any segment, with the address of the whole link.
+    pub fn inertial_to_logical(&self, point:&InertialCoord) -> Option<LogicalCoord> {
+        let mut nearest:Option<(LogicalCoord, f64)> = None;
+        for link in &self.links {
+            let mut start = 0.0;
+            for segment in self.segments_for_link(link) {
+                let (mut logical, gap) = Self::nearest_on_segment(segment, point);
+                if nearest.as_ref().is_none_or(|(_, best)| gap < *best) {
+                    logical.addr = LogicalAddress::for_link(link.id);
+                    logical.distance += start;
+                    nearest = Some((logical, gap));
+                }
+                start += segment.length();
+            }
         }
+        nearest.map(|(logical, _)| logical)
     }
-    pub fn find_all(&self) -> Result<Vec<Rc<RefCell<Junction>>>, Error> {
-        let mut statement = self.connection.prepare("SELECT * FROM junctions;");
-        if let  Err(e) = statement {
-            return Err(e);
+
+    // Segments have no lanes, so the lane field of the address is ignored.
+    pub fn find_segments_by_address(&self, addr:&LogicalAddress) -> Vec<&Segment> {
+        let mask = Mask { lane:false, ..addr.mask };
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            if let Some(tile) = self.tiles.iter().find(|tile| tile.id == segment.tile) {
+                let id = Identifier::new(tile.link, tile.id, segment.id, 0);
+                if id.matches(&addr.id, &mask) {
+                    segments.push(segment.deref());
+                }
+            }
         }
-        let mut statement = statement.unwrap();
-        let junc_iter = statement.query_map([], |row| {
-            Ok(Junction::from_query(row.get(0).unwrap()))
-        });
-        let mut juncs:Vec<Rc<RefCell<Junction>>> = Vec::new();
-        for junc in junc_iter.unwrap() {
-            juncs.push(Rc::new(RefCell::from(junc.unwrap())));
+        segments
+    }
+
+    pub fn link_length(&self, link:&Link) -> f64 {
+        match self.link_segments.get(&link.id) {
+            Some(indices) => indices.iter().map(|index| self.segments[*index].length()).sum(),
+            None => 0.0
         }
-        Ok(juncs)
     }
 
-    pub fn find_connections(&self) -> Result<Vec<(u32,u16,u32)>, Error> {
-        let mut statement = self.connection.prepare("SELECT * FROM junctions_links ORDER BY junc_id, exit;");
-        if let  Err(e) = statement {
-            return Err(e);
+    // Exits within the heading tolerance of the heading match it. usize::MAX if none does.
+    pub fn find_exit_by_heading(&self, to: &Junction, exit_heading: u32) -> usize {
+        let heading = Heading::new(exit_heading as f64);
+        for exit_index in 0..to.links.len() {
+            let exit = &to.links[exit_index];
+            if Heading::new(exit.exit as f64).circular_delta(heading) <= self.options.heading_tolerance {
+                return exit_index;
+            }
         }
-        let mut statement = statement.unwrap();
-        let connection_iter = statement.query_map([], |row| {
-            Ok((row.get::<usize, u32>(0).unwrap() as u32, row.get::<usize,u16>(1).unwrap(), row.get::<usize,u32>(2).unwrap()))
-        });
-        let mut connections = Vec::new();
-        for connection in connection_iter.unwrap() {
-            let connection = connection.unwrap();
-            connections.push(connection);
+        usize::MAX
+    }
+
+    pub fn find_exit(&self, from:&Junction, to:&Junction) -> usize {
+        // let from = from.upgrade().unwrap().clone().borrow();
+        // let to = to.upgrade().unwrap().clone().borrow();
+        for i in 0..from.links.len() {
+            let exit = from.links[i];
+            let link = self.get_link(exit.link_id);
+            if link.other_end(from.id) == Some(to.id) {
+                return i;
+            }
         }
-        Ok(connections)
+        return usize::max_value();
     }
-}
 
-struct TileGateway<'a> {
-    connection: &'a Connection,
-}
+    fn dummy(&self, junc:&Junction, link:&Link, exit:u32, dest_junc:u32) -> () {
+        println!("{} {} {} {}", junc.id, link.id, exit, dest_junc);
+    }
 
-impl<'a> TileGateway<'a> {
-    pub fn new(connection: &'a Connection) -> TileGateway<'a> {
-        TileGateway {
-            connection
+    // Like evaluate_route_simple, with the index of the exit each junction was entered by:
+    // (junction, entry index, exit index).
+    pub fn evaluate_route_detailed(&self, route:&Route) -> Vec<(u32, usize, usize)> {
+        let mut steps = RouteSteps::new(self, route);
+        let mut detailed = Vec::new();
+        while let Some((junc, exit, _)) = steps.next() {
+            detailed.push((junc, steps.entry, exit));
         }
+        detailed
     }
-    pub fn find_all(&self) -> Result<Vec<Box<Tile>>, Error> {
-        let statement = self.connection.prepare("SELECT * FROM tiles;");
-        if let  Err(e) = statement {
-            return Err(e);
-        }
-        let mut statement = statement.unwrap();
-        let tile_iter = statement.query_map([], |row| {
-            Ok(Tile::from_query(row.get(0).unwrap(), row.get(1).unwrap()))
-        });
-        let mut tiles = Vec::new();
-        for tile in tile_iter.unwrap() {
-            tiles.push(Box::new(tile.unwrap()));
-        }
-        Ok(tiles)
+
+    pub fn evaluate_route_simple(&self, route:&Route) -> Vec<(u32, usize)> {
+        self.route_steps(route).collect()
     }
-}
 
-struct SegmentGateway<'a> {
-    connection: &'a Connection
-}
+    // Each step carries the lane it is taken in, which stays the start lane until turns can change lanes.
+    pub fn evaluate_route(&self, route:&Route) -> Vec<(u32, usize, i16)> {
+        RouteSteps::new(self, route).collect()
+    }
 
-impl<'a> SegmentGateway<'a> {
-    pub fn new(connection: &Connection) -> SegmentGateway<'_> {
-        SegmentGateway {
-            connection
-        }
+    // The distance runs from the start position to the last junction the route reaches.
+    pub fn evaluate_route_with_distance(&self, route:&Route) -> (Vec<(u32, usize)>, f64) {
+        let mut steps = RouteSteps::new(self, route);
+        let v = steps.by_ref().map(|(junc, exit, _)| (junc, exit)).collect();
+        (v, steps.total)
     }
 
-    pub fn find_all(&self) -> Result<Vec<Box<Segment>>, Error> {
-        let mut statement = self.connection.prepare("SELECT * FROM segments;");
-        if let  Err(e) = statement {
-            return Err(e);
-        }
-        let mut statement = statement.unwrap();
-        let seg_iter = statement.query_map([], |row| {
-            Ok(Segment::from_query(row))
-        });
-        let mut segments = Vec::new();
-        for segment in seg_iter.unwrap() {
-            segments.push(Box::new(segment.unwrap()));
+    // The turn the route makes at each of the evaluated steps, stopping at the first step that
+    // doesn't follow on from the one before.
+    pub fn turn_instructions(&self, route:&Route, steps:&[(u32, usize)]) -> Vec<(u32, TurnDirection)> {
+        let mut instructions = Vec::new();
+        let mut link = route.resolve_start(self).and_then(|start| self.get_link_checked(start.addr.id.link));
+        let mut trav_dir = route.trav_dir;
+        for (junc_id, exit_index) in steps {
+            let (Some(current), Some(junc)) = (link, self.get_junc_checked(*junc_id)) else {
+                break;
+            };
+            let (_, incoming_heading) = self.approach(current, trav_dir);
+            let junc = junc.borrow();
+            if *exit_index >= junc.num_links() {
+                break;
+            }
+            let entry = junc.find_entry(incoming_heading);
+            instructions.push((junc.id, junc.turn_direction_to_exit(entry, *exit_index)));
+            let departure = self.depart(junc.id, junc.links[*exit_index].link_id, trav_dir);
+            link = departure.map(|(next, _)| next);
+            trav_dir = departure.map(|(_, dir)| dir).unwrap_or(trav_dir);
         }
-        Ok(segments)
+        instructions
     }
-}
 
-pub fn find_reciprocal_heading(heading:f64) -> f64 {
-    let mut reciprocal_heading:f64 = heading + 180.0;
-    while reciprocal_heading >= 360.0 {
-        reciprocal_heading -= 360.0;
+    // Parses and evaluates the route in one go, for callers that only have the route string.
+    pub fn run_route(&self, input:&str) -> RouteReport {
+        let mut report = RouteReport { steps:Vec::new(), distance:0.0, instructions:Vec::new(), errors:Vec::new() };
+        let route = match Route::try_parse(input) {
+            Ok(route) => route,
+            Err(e) => {
+                report.errors.push(e.to_string());
+                return report;
+            }
+        };
+        if route.resolve_start(self).is_none() {
+            report.errors.push(format!("route start {} not found", route.start));
+            return report;
+        }
+        let mut steps = RouteSteps::new(self, &route);
+        report.steps = steps.by_ref().map(|(junc, exit, _)| (junc, exit)).collect();
+        report.distance = steps.total;
+        report.errors = steps.shortfalls;
+        report.instructions = self.turn_instructions(&route, &report.steps);
+        report
     }
-    reciprocal_heading
-}
 
-pub fn hemisphere(input:u32) -> u32 {
-    let mut angle = input;
-    while angle >= 360 {
-        angle -= 360;
+    // Like evaluate_route, but giving up after max_steps junctions.
+    pub fn evaluate_route_limited(&self, route:&Route, max_steps:usize) -> Vec<(u32, usize, i16)> {
+        let mut steps = RouteSteps::new(self, route);
+        steps.max_steps = max_steps;
+        steps.collect()
     }
-    if angle < 90 || (angle >= 270 && angle < 360) {
-        return 0;
+
+    // Evaluates each route as evaluate_route_simple would, in the same order, sharing one visited set
+    // between them so it is only grown once.
+    pub fn evaluate_routes(&self, routes:&[Route]) -> Vec<Vec<(u32, usize)>> {
+        let mut results = Vec::with_capacity(routes.len());
+        let mut visited = HashSet::new();
+        for route in routes {
+            visited.clear();
+            let mut steps = RouteSteps::new(self, route);
+            steps.visited = visited;
+            results.push(steps.by_ref().map(|(junc, exit, _)| (junc, exit)).collect());
+            visited = steps.visited;
+        }
+        results
     }
-    1
-}
 
-#[cfg(test)]
-mod tests {
-    use std::ops::Deref;
-    use rstest::rstest;
-    use rusqlite::Connection;
-    use super::*;
-    use crate::math::{Curve, Identifier, InertialCoord, LogicalAddress, LogicalCoord, Mask, Network, NetworkBuilder};
+    // Lazily evaluates the route one junction at a time.
+    pub fn route_steps<'r>(&'r self, route:&'r Route) -> impl Iterator<Item = (u32, usize)> + 'r {
+        RouteSteps::new(self, route).map(|(junc, exit, _)| (junc, exit))
+    }
 
-    #[test]
-    fn test_inertial_coords() {
-        let sut = InertialCoord::new(1.0, 2.0, 3.0);
-        assert_eq!(sut.x, 1.0);
-        assert_eq!(sut.y, 2.0);
-        assert_eq!(sut.z, 3.0);
+    fn build_routes(&mut self) {
+        if self.spanning_tree.borrow().num_nodes() == 0 {
+            return;
+        }
+        // for junc in &self.junctions {
+        //     junc.build_routes(self, &mut self.routing.borrow_mut());
+        // }
+        // let print_step = |junc:Rc<RefCell<Junction>>, link:&Link, exit:u32, dest_junc:u32, path:&Vec<(u32,u32)>| {
+        //     // self.routing.borrow_mut().hops.insert(Hop::from(junc.id,
+        //     //                                                 LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
+        //     //                                                 LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
+        //     //                                                 exit
+        //     // )
+        //     // );
+        //     // For each outgoing link reachable directly from dest_junc, add a route from origin to origin via link
+        //     //let dest_junc = self.get_junc(dest_junc);
+        //     // for outgoing_exit in &dest_junc.outgoing {
+        //     //     let outgoing_link = self.get_link(outgoing_exit.link_id);
+        //     //     self.routing.borrow_mut().hops.insert(Hop::from(junc.id,
+        //     //     LogicalAddress::new(Identifier::new(outgoing_link.id, 0, 0, 0), Mask::new(true, false, false, false)),
+        //     //     LogicalAddress::new(Identifier::new(link.id, 0, 0, 0), Mask::new(true, false, false, false)),
+        //     //     exit
+        //     //     ));
+        //     //     println!("Add route: {} {} {} {}", junc.id, outgoing_exit.link_id, link.id, exit);
+        //     // }
+        //     if let Some(last_junc) = path.last() {
+        //         let last_junc = self.get_junc(last_junc.0);
+        //
+        //         if last_junc.borrow().links.is_empty() {
+        //
+        //             // Iterate over path, adding routes
+        //             for i in 0..path.len() {
+        //                 println!("path: junc {} exit {}", path[i].0, path[i].1);
+        //                 let src_junc = self.get_junc(path[i].0);
+        //                 for j in i+1..path.len() {
+        //                     let dest_junc = self.get_junc(path[j].0);
+        //                     if path[i].0 != path[j].0 && path[i].1 != 270 {
+        //                         //println!("origin_junc: {} dest_junc: {} exit {}", src_junc.id, dest_junc.id, path[i].1);
+        //
+        //                         println!("Add route from {} to {} via {} exit {}", src_junc.borrow().id, dest_junc.borrow().id, path[i].0, path[i].1);
+        //                         self.routing.borrow_mut().hops.insert(Hop::from(src_junc.borrow().id, dest_junc.borrow().id, path[i].1));
+        //                     }
+        //                 }
+        //             }
+        //         }
+        //     }
+        // };
+        // self.depth_first_traversal(&print_step, |junc:Rc<RefCell<Junction>>| println!("{}", junc.borrow().id));
+        let build = |node:Rc<RefCell<SpanningNode>>| {
+            if node.borrow().children.is_empty() {
+                let path:Vec<Junction> = SpanningNode::path_to_root(&node).iter().map(|id| self.get_junc(*id).borrow().clone()).collect();
+                for i in 0..path.len() {
+                    let src_junc = &path[i];
+                    println!("path: junc {}", src_junc.id);
+                    if i+1<path.len() {
+                        let next_hop = &path[i + 1];
+                        let exit_index = self.find_exit(src_junc, next_hop);
+                        if exit_index != usize::max_value() {
+                            let exit = src_junc.links[exit_index];
+                            let next_link = LogicalAddress::for_link(exit.link_id);
+                            self.routing.borrow_mut().insert(Hop::from(src_junc.id, next_hop.id, next_link, next_link, exit.exit));
+                            for j in i + 2..path.len() {
+                                let dest_junc = &path[j];
+                                if src_junc.id != dest_junc.id && exit.exit != 270 {
+                                    //println!("origin_junc: {} dest_junc: {} exit {}", src_junc.id, dest_junc.id, path[i].1);
+                                    let prev_junc = &path[j - 1];
+                                    let arrival_index = self.find_exit(prev_junc, dest_junc);
+                                    if arrival_index == usize::MAX {
+                                        continue;
+                                    }
+                                    let destination = LogicalAddress::for_link(prev_junc.links[arrival_index].link_id);
+                                    println!("Add route from {} to {} via {} exit {}", src_junc.id, dest_junc.id, src_junc.id, exit.exit);
+                                    self.routing.borrow_mut().insert(Hop::from(src_junc.id, dest_junc.id, destination, next_link, exit.exit));
+                                }
+                            }
+                        } else {
+                            println!("Warning team:No exit from {} to {}", src_junc.id, next_hop.id);
+                        }
+                    }
+                }
+            }
+        };
+        SpanningNode::depth_first_traversal(self.spanning_tree.clone(),&build);
     }
 
-    #[test]
-    fn test_logical_coords() {
-        let sut = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), 1.0, 2.0, 3.0);
-        assert_eq!(sut.offset, 1.0);
-        assert_eq!(sut.distance, 2.0);
-        assert_eq!(sut.loft, 3.0);
+    // Runs Dijkstra from every junction, recording for each junction reached the exit to take
+    // first and the link that arrives at it. Unlike build_routes this covers every connected pair.
+    // With the parallel feature the sources are searched on the rayon thread pool.
+    pub fn build_all_pairs_routing(&mut self) {
+        let snapshot = self.routing_snapshot();
+        #[cfg(feature = "parallel")]
+        let hops = snapshot.all_pairs_hops_parallel();
+        #[cfg(not(feature = "parallel"))]
+        let hops = snapshot.all_pairs_hops();
+        self.routing.borrow_mut().set_hops(hops);
     }
 
-    #[rstest]
-    #[case(-1.825, 50.0, 0.0)]
-    fn test_logical_to_inertial_coords(#[case] _offset: f64, #[case] _distance: f64, #[case] _loft: f64) {
-        let sut = Curve::new();
-        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), -1.825, 50.0, 0.0);
-        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
-        sut.logical_to_inertial(&logical, &mut inertial);
-        assert_eq!(inertial.x, -1.825);
-        assert_eq!(inertial.y, 50.0);
-        assert_eq!(inertial.z, 0.0);
+    fn routing_snapshot(&self) -> RoutingSnapshot {
+        let mut sources = Vec::new();
+        let mut neighbors = HashMap::new();
+        for junc in self.junctions() {
+            let junc = junc.borrow();
+            let mut exits = Vec::new();
+            for exit in &junc.links {
+                let Some(link) = self.get_link_checked(exit.link_id) else {
+                    continue;
+                };
+                if !link.can_leave(junc.id) {
+                    continue;
+                }
+                if let Some(next) = link.other_end(junc.id) {
+                    exits.push(SnapshotExit { next, link:exit.link_id, heading:exit.exit, cost:self.link_length(link) });
+                }
+            }
+            sources.push((junc.id, exits));
+            let adjacent:Vec<(u32, u16, f64)> = self.neighbors(junc.id).into_iter()
+                .filter(|(_, link_id)| self.get_link(*link_id).can_leave(junc.id))
+                .map(|(next, link_id)| (next, link_id, self.link_length(self.get_link(link_id))))
+                .collect();
+            neighbors.insert(junc.id, adjacent);
+        }
+        RoutingSnapshot { sources, neighbors }
     }
 
-    #[rstest]
-    #[case(-1.825, 50.0, 0.0)]
-    fn test_inertial_to_logical(#[case] x: f64, #[case] y: f64, #[case] z: f64) {
-        let sut = Curve::new();
-        let mut logical = LogicalCoord::empty();
-        let inertial = InertialCoord::new(x, y, z);
-        sut.inertial_to_logical(&inertial, &mut logical);
-        assert_eq!(logical.offset, -1.825);
-        assert_eq!(logical.distance, 50.0);
-        assert_eq!(logical.loft, 0.0);
+    fn build_spanning_tree(&mut self) -> () {
+        if self.junctions.is_empty() {
+            self.spanning_tree = Rc::new(RefCell::new(SpanningNode::empty()));
+            return;
+        }
+        let parent_stack:RefCell<Vec<Rc<RefCell<SpanningNode>>>> = RefCell::from(Vec::new());
+        parent_stack.borrow_mut().push(Rc::from(RefCell::from(SpanningNode::new(Weak::new(), Rc::downgrade(&(self.junctions[0].clone()))))));
+        let build = |junc:Rc<RefCell<Junction>>| {//, link:&Link, exit:u32, dest_junc:u32, path:&Vec<(u32,u32)>| {
+            let mut parent_stack = parent_stack.borrow_mut();
+            if let Some(top) = parent_stack.deref().last() {
+                let child = Rc::from(RefCell::new(SpanningNode::new(Rc::downgrade(&top.clone()), Rc::downgrade(&junc.clone()))));
+                top.borrow_mut().children.push(child.clone());
+                parent_stack.push(child.clone());
+            }
+        };
+        if let Some(root) = parent_stack.borrow_mut().last() {
+            self.spanning_tree = root.clone();
+        }
+        let empty = |junc:Rc<RefCell<Junction>>, link:&Link, exit:u32, origin:u32, path:&Vec<(u32,u32)>| {
+        };
+        self.depth_first_traversal(&empty, &build);
     }
 
-    #[rstest]
-    #[case("1.1.1.0/1.1.1.0", Ok(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false))))]
-    #[case("2.10.2.1/1.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,1),Mask::new(true,true,true,true))))]
-    #[case("2.10.2.-1/1.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
-    #[case("-2.10.2.-1/1.1.1.1", Err("Expected whole number, got minus sign"))]
-    #[case("2.10.2.-1/2.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
-    #[case("2.10.2.-1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
-    #[case("", Err("Expected some content before the '/'"))]
-    #[case("/", Err("Expected some content before the '/'"))]
-    #[case("/1.1.1.1", Err("Expected some content before the '/'"))]
-    fn test_parse_logical_address(#[case] str: &str, #[case] addr: Result<LogicalAddress, &str>) {
-        assert_eq!(LogicalAddress::parse(str),addr);
+    fn depth_first_traversal_helper<LinkFunc, JuncFunc>(& self, junc:Rc<RefCell<Junction>>, visited:&mut HashSet<u32>, path: &mut Vec<(u32,u32)>, link_func:&LinkFunc, junc_func:&JuncFunc) -> ()
+    where LinkFunc : Fn(Rc<RefCell<Junction>>, &Link, u32, u32, &Vec<(u32,u32)>),
+        JuncFunc: Fn(Rc<RefCell<Junction>>)
+    {
+        if !visited.contains(&junc.borrow().id) {
+            visited.insert(junc.borrow().id);
+            for exit in &junc.borrow().links {
+                // Skip exits whose link or far junction is missing rather than panicking on bad data.
+                let Some(link) = self.get_link_checked(exit.link_id) else {
+                    continue;
+                };
+                let origin = link.origin.and_then(|id| self.get_junc_checked(id));
+                let destination = link.destination.and_then(|id| self.get_junc_checked(id));
+                if let (Some(origin), Some(destination)) = (origin, destination) {
+                    path.push((destination.borrow().id,exit.exit));
+                    if !visited.contains(&destination.borrow().id) {
+                        junc_func(destination.clone());
+                        link_func(destination.clone(), link, exit.exit, origin.borrow().id, path);
+                        self.depth_first_traversal_helper(destination, visited, path, link_func, junc_func);
+                    }
+                }
+            }
+
+            path.pop();
+        }
     }
 
-    #[test]
-    fn test_network_builder_add() {
-        let mut sut = NetworkBuilder::new();
-        sut.add_junction();
-        assert_eq!(sut.junctions.len(), 1);
-        sut.create_link();
-        sut.add_straight(InertialCoord::new(0.0, 0.0, 0.0), 252.0);
+    pub fn depth_first_traversal<LinkFunc, JuncFunc>(&self, link_func:&LinkFunc, junc_func:JuncFunc) -> ()
+    where LinkFunc: Fn(Rc<RefCell<Junction>>, &Link, u32, u32, &Vec<(u32,u32)>),
+        JuncFunc: Fn(Rc<RefCell<Junction>>)
+    {
+        let mut visited: HashSet<u32> = HashSet::new();
+        let mut path:Vec<(u32,u32)> = Vec::new();
+        if !self.junctions.is_empty() {
+            let junc = self.get_junc(1);
+            self.depth_first_traversal_helper(junc, &mut visited, &mut path, link_func, &junc_func);
+        }
+    }
+
+    pub fn empty() -> Network {
+        Network {
+            links:Vec::new(),
+            junctions:Vec::new(),
+            tiles: Vec::new(),
+            segments:Vec::new(),
+            routing:RefCell::new(Routing::new()),
+            spanning_tree:Rc::new(RefCell::from(SpanningNode::empty())),
+            places:Vec::new(),
+            options:NetworkImportOptions::default(),
+            link_segments:HashMap::new(),
+            spatial_index:None,
+            load_warnings:Vec::new()
+        }
+    }
+
+    // Junctions have no stored position, so use the start of a link leaving the junction,
+    // or failing that the end of a link arriving at it.
+    pub fn junction_position(&self, junc:u32) -> Option<InertialCoord> {
+        for link in &self.links {
+            if link.origin == Some(junc) {
+                if let Some(segment) = self.first_segment_for_link(link) {
+                    return Some(InertialCoord::new(segment.x, segment.y, segment.z));
+                }
+            }
+        }
+        for link in &self.links {
+            if link.destination == Some(junc) {
+                if let Some(segment) = self.last_segment_for_link(link) {
+                    return Some(Self::segment_end(segment));
+                }
+            }
+        }
+        None
+    }
+
+    // Junctions joined to junc by a link in either direction, with the joining link.
+    pub fn neighbors(&self, junc:u32) -> Vec<(u32, u16)> {
+        self.links.iter()
+            .filter_map(|link| link.other_end(junc).map(|other| (other, link.id)))
+            .collect()
+    }
+
+    // Partitions the junctions into groups joined by links in either direction, each listed in id order.
+    pub fn connected_components(&self) -> Vec<Vec<u32>> {
+        let mut components = Vec::new();
+        let mut visited: HashSet<u32> = HashSet::new();
+        for junc in self.junctions() {
+            let start = junc.borrow().id;
+            if !visited.insert(start) {
+                continue;
+            }
+            let mut component = vec![];
+            let mut open = VecDeque::from([start]);
+            while let Some(junc) = open.pop_front() {
+                component.push(junc);
+                for (next, _) in self.neighbors(junc) {
+                    if visited.insert(next) {
+                        open.push_back(next);
+                    }
+                }
+            }
+            component.sort();
+            components.push(component);
+        }
+        components
+    }
+
+    // Junctions with a single exit, in id order.
+    pub fn dead_ends(&self) -> Vec<u32> {
+        self.junctions()
+            .filter(|junc| junc.borrow().degree() == 1)
+            .map(|junc| junc.borrow().id)
+            .collect()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected_components().len() <= 1
+    }
+
+    // A GraphViz digraph with a node per junction and an edge per link from origin to destination,
+    // labelled with the link id and the heading of the exit it leaves by.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph network {\n");
+        for junc in self.junctions() {
+            let id = junc.borrow().id;
+            dot.push_str(&format!("    {} [label=\"{}\"];\n", id, id));
+        }
+        for link in &self.links {
+            let (Some(origin), Some(destination)) = (link.origin, link.destination) else {
+                continue;
+            };
+            let exit = self.get_junc_checked(origin).and_then(|junc| {
+                junc.borrow().links.iter().find(|exit| exit.link_id == link.id).map(|exit| exit.exit)
+            });
+            let label = match exit {
+                Some(exit) => format!("{} ({})", link.id, exit),
+                None => format!("{}", link.id)
+            };
+            dot.push_str(&format!("    {} -> {} [label=\"{}\"];\n", origin, destination, label));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // A GeoJSON FeatureCollection with a LineString through the segments of each link followed by a Point
+    // for each junction. Junctions without a positioned link have a null geometry, as do links and
+    // junctions with a coordinate that isn't finite, which JSON has no way to write.
+    pub fn to_geojson(&self) -> String {
+        let point = |coord:&InertialCoord| {
+            let finite = coord.x.is_finite() && coord.y.is_finite() && coord.z.is_finite();
+            finite.then(|| format!("[{},{},{}]", coord.x, coord.y, coord.z))
+        };
+        let mut features = Vec::new();
+        for link in &self.links {
+            let segments = self.segments_for_link(link);
+            let mut coords:Vec<Option<String>> = segments.iter()
+                .map(|segment| point(&InertialCoord::new(segment.x, segment.y, segment.z)))
+                .collect();
+            if let Some(last) = segments.last() {
+                coords.push(point(&Self::segment_end(last)));
+            }
+            let geometry = match coords.into_iter().collect::<Option<Vec<String>>>() {
+                Some(coords) => format!("{{\"type\":\"LineString\",\"coordinates\":[{}]}}", coords.join(",")),
+                None => String::from("null")
+            };
+            let endpoint = |junc:Option<u32>| junc.map_or(String::from("null"), |junc| junc.to_string());
+            features.push(format!(
+                "{{\"type\":\"Feature\",\"properties\":{{\"kind\":\"link\",\"id\":{},\"origin\":{},\"destination\":{}}},\"geometry\":{}}}",
+                link.id, endpoint(link.origin), endpoint(link.destination), geometry));
+        }
+        for junc in self.junctions() {
+            let id = junc.borrow().id;
+            let geometry = match self.junction_position(id).and_then(|coord| point(&coord)) {
+                Some(coord) => format!("{{\"type\":\"Point\",\"coordinates\":{}}}", coord),
+                None => String::from("null")
+            };
+            features.push(format!(
+                "{{\"type\":\"Feature\",\"properties\":{{\"kind\":\"junction\",\"id\":{}}},\"geometry\":{}}}",
+                id, geometry));
+        }
+        format!("{{\"type\":\"FeatureCollection\",\"features\":[{}]}}", features.join(","))
+    }
+
+    // The tables Network::from couldn't read and left out.
+    pub fn load_warnings(&self) -> &[NetworkWarning] {
+        &self.load_warnings
+    }
+
+    // Problems in the loaded data that Network::from tolerates, grouped by kind, after any tables it
+    // left out.
+    pub fn validate(&self) -> Vec<NetworkWarning> {
+        let mut warnings = self.load_warnings.clone();
+        for link in &self.links {
+            for junction in [link.origin, link.destination].into_iter().flatten() {
+                if self.get_junc_checked(junction).is_none() {
+                    warnings.push(NetworkWarning::DanglingLinkEndpoint { link:link.id, junction });
+                }
+            }
+        }
+        for link in &self.links {
+            if !self.tiles.iter().any(|tile| tile.link == link.id) {
+                warnings.push(NetworkWarning::EmptyLink { link:link.id });
+            }
+        }
+        for junc in self.junctions() {
+            let junc = junc.borrow();
+            for exit in &junc.links {
+                let link = exit.link_id;
+                if self.get_link_checked(link).is_none() {
+                    warnings.push(NetworkWarning::DanglingExit { junction:junc.id, link });
+                }
+            }
+        }
+        for tile in &self.tiles {
+            let has_segments = self.segments.iter().any(|segment| segment.tile == tile.id);
+            if !has_segments || self.get_link_checked(tile.link).is_none() {
+                warnings.push(NetworkWarning::OrphanTile { tile:tile.id });
+            }
+        }
+        for component in self.connected_components().iter().skip(1) {
+            for junction in component {
+                warnings.push(NetworkWarning::UnreachableJunction { junction:*junction });
+            }
+        }
+        warnings
+    }
+
+    // Dijkstra over link lengths scaled by their cost multipliers, returning the junctions visited
+    // from from to to inclusive.
+    pub fn shortest_path(&self, from:u32, to:u32) -> Option<Vec<u32>> {
+        self.best_first_search(from, to, |_| 0.0)
+    }
+
+    // A* over link lengths using the straight-line distance between junction positions, which
+    // never exceeds the length of the links joining them. The distance is scaled by the smallest
+    // cost multiplier below one so it never exceeds the cost of the links either.
+    pub fn a_star(&self, from:u32, to:u32) -> Option<Vec<u32>> {
+        let goal = self.junction_position(to);
+        let scale = self.links.iter().map(|link| link.cost_multiplier).fold(1.0, f64::min);
+        self.best_first_search(from, to, |junc| {
+            match (&goal, self.junction_position(junc)) {
+                (Some(goal), Some(position)) => scale * f64::sqrt((goal.x - position.x).powi(2) + (goal.y - position.y).powi(2) + (goal.z - position.z).powi(2)),
+                _ => 0.0
+            }
+        })
+    }
+
+    // Every path from from to to using at most max_hops links and visiting no junction twice, found
+    // depth first in exit order. One-way links are only followed forwards.
+    pub fn all_paths(&self, from:u32, to:u32, max_hops:usize) -> Vec<Vec<u32>> {
+        let mut paths = Vec::new();
+        if self.get_junc_checked(from).is_none() || self.get_junc_checked(to).is_none() {
+            return paths;
+        }
+        let mut path = vec![from];
+        self.all_paths_helper(to, max_hops, &mut path, &mut paths);
+        paths
+    }
+
+    fn all_paths_helper(&self, to:u32, max_hops:usize, path:&mut Vec<u32>, paths:&mut Vec<Vec<u32>>) {
+        let junc = *path.last().unwrap();
+        if junc == to {
+            paths.push(path.clone());
+            return;
+        }
+        if path.len() > max_hops {
+            return;
+        }
+        for (next, link_id) in self.neighbors(junc) {
+            if path.contains(&next) || !self.get_link(link_id).can_leave(junc) {
+                continue;
+            }
+            path.push(next);
+            self.all_paths_helper(to, max_hops, path, paths);
+            path.pop();
+        }
+    }
+
+    // Breadth-first search over (junction, entry) states where carrying straight on is free and any
+    // other turn costs one, returning the junctions visited from from to to inclusive.
+    pub fn fewest_turns_path(&self, from:u32, to:u32) -> Option<Vec<u32>> {
+        self.get_junc_checked(from)?;
+        self.get_junc_checked(to)?;
+        let start = (from, None);
+        let mut turns: HashMap<(u32, Option<usize>), u32> = HashMap::new();
+        let mut came_from: HashMap<(u32, Option<usize>), (u32, Option<usize>)> = HashMap::new();
+        let mut open = VecDeque::new();
+        turns.insert(start, 0);
+        open.push_back((start, 0));
+        while let Some((state, cost)) = open.pop_front() {
+            if cost > turns[&state] {
+                continue;
+            }
+            if state.0 == to {
+                let mut path = vec![to];
+                let mut current = state;
+                while let Some(previous) = came_from.get(&current) {
+                    path.push(previous.0);
+                    current = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let junc = self.get_junc(state.0);
+            let junc = junc.borrow();
+            for exit_index in 0..junc.num_links() {
+                let link_id = junc.links[exit_index].link_id;
+                let Some(next) = self.get_link_checked(link_id).filter(|link| link.can_leave(junc.id)).and_then(|link| link.other_end(junc.id)) else {
+                    continue;
+                };
+                let Some(next_junc) = self.get_junc_checked(next) else {
+                    continue;
+                };
+                let entry = next_junc.borrow().links.iter().position(|exit| exit.link_id == link_id);
+                let turn_cost = match state.1 {
+                    Some(entry_index) if junc.turn_direction_to_exit(entry_index, exit_index) != TurnDirection::Straight => 1,
+                    _ => 0
+                };
+                let next_state = (next, entry);
+                let next_cost = cost + turn_cost;
+                if turns.get(&next_state).is_none_or(|known| next_cost < *known) {
+                    turns.insert(next_state, next_cost);
+                    came_from.insert(next_state, state);
+                    if turn_cost == 0 {
+                        open.push_front((next_state, next_cost));
+                    }
+                    else {
+                        open.push_back((next_state, next_cost));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn best_first_search<Heuristic>(&self, from:u32, to:u32, heuristic:Heuristic) -> Option<Vec<u32>>
+    where Heuristic: Fn(u32) -> f64
+    {
+        let num_junctions = self.junctions.len() as u32;
+        if from == 0 || to == 0 || from > num_junctions || to > num_junctions {
+            return None;
+        }
+        let mut cost_so_far: HashMap<u32, f64> = HashMap::new();
+        let mut came_from: HashMap<u32, u32> = HashMap::new();
+        let mut closed: HashSet<u32> = HashSet::new();
+        let mut open = BinaryHeap::new();
+        cost_so_far.insert(from, 0.0);
+        open.push(SearchEntry { estimate: heuristic(from), junc: from });
+        while let Some(SearchEntry { junc, .. }) = open.pop() {
+            if junc == to {
+                let mut path = vec![to];
+                let mut current = to;
+                while let Some(previous) = came_from.get(&current) {
+                    path.push(*previous);
+                    current = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            if !closed.insert(junc) {
+                continue;
+            }
+            let cost = cost_so_far[&junc];
+            for (next, link_id) in self.neighbors(junc) {
+                let link = self.get_link(link_id);
+                if !link.can_leave(junc) {
+                    continue;
+                }
+                let next_cost = cost + self.link_length(link) * link.cost_multiplier;
+                if cost_so_far.get(&next).is_none_or(|known| next_cost < *known) {
+                    cost_so_far.insert(next, next_cost);
+                    came_from.insert(next, junc);
+                    open.push(SearchEntry { estimate: next_cost + heuristic(next), junc: next });
+                }
+            }
+        }
+        None
+    }
+
+    pub fn route(&self, junc_id: u32, src_junc:u32, dest_junc:u32, to_dest:bool) -> Option<Hop> {
+        let src_junc = self.get_junc_checked(src_junc)?.borrow().id;
+        let target = if to_dest { dest_junc } else { src_junc };
+        // Hops naming junctions that don't exist are never returned.
+        self.get_junc_checked(junc_id)?;
+        self.get_junc_checked(target)?;
+        self.routing.borrow().find(junc_id, target)
+    }
+
+    pub fn get_link(&self, id:u16) -> &Link {
+        &self.links[(id-1) as usize]
+    }
+
+    // Like get_link, but None for 0 or an id past the end instead of panicking.
+    pub fn get_link_checked(&self, id:u16) -> Option<&Link> {
+        self.links.get(id.checked_sub(1)? as usize).map(|link| link.deref())
+    }
+
+    pub fn get_link_mut(&mut self, id:u16) -> &mut Link {
+        &mut self.links[(id-1) as usize]
+    }
+
+    pub fn add_link(&mut self, link:Box<Link>) {
+        self.links.push(link);
+    }
+
+    // Ids are positional, so a gap in them, as a partial load leaves, is filled with a link that
+    // goes nowhere.
+    pub fn set_links(&mut self, links:Vec<Box<Link>>) {
+        self.links = Vec::with_capacity(links.len());
+        for link in links {
+            while (self.links.len() + 1) < link.id as usize {
+                self.links.push(Box::new(Link::new(self.links.len() as u16 + 1)));
+            }
+            self.links.push(link);
+        }
+    }
+
+    // Gaps in the ids are filled with junctions that have no exits, as set_links does for links.
+    pub fn set_junctions(&mut self, junctions:Vec<Rc<RefCell<Junction>>>) {
+        self.junctions = Vec::with_capacity(junctions.len());
+        for junc in junctions {
+            let id = junc.borrow().id as usize;
+            while (self.junctions.len() + 1) < id {
+                self.junctions.push(Rc::new(RefCell::new(Junction::new(self.junctions.len() as u32 + 1))));
+            }
+            self.junctions.push(junc);
+        }
+    }
+
+    pub fn set_tiles(&mut self, tiles:Vec<Box<Tile>>) {
+        self.tiles = tiles;
+        self.index_segments();
+    }
+    pub fn set_junction_connections(&mut self, connections: &mut Vec<(u32, u16, u32, i16)>) {
+        for connection in connections {
+            if let Some(junc) = self.get_junc_checked(connection.0) {
+                junc.borrow_mut().add_link(connection.1, connection.2, connection.3);
+            }
+        }
+    }
+
+    pub fn set_segments(&mut self , segments:Vec<Box<Segment>>) {
+        self.segments = segments;
+        self.spatial_index = None;
+        self.index_segments();
+    }
+
+    // For each link with both ends, gives the end without an exit onto it one on the reciprocal of the
+    // other end's heading. Links with no exit at either end are left alone.
+    pub fn add_reciprocal_exits(&mut self) {
+        let exit_onto = |junc:&Rc<RefCell<Junction>>, link:u16| junc.borrow().links.iter().find(|exit| exit.link_id == link).map(|exit| exit.exit);
+        for link in &self.links {
+            let (Some(origin), Some(destination)) = (link.origin, link.destination) else {
+                continue;
+            };
+            let (Some(origin), Some(destination)) = (self.get_junc_checked(origin), self.get_junc_checked(destination)) else {
+                continue;
+            };
+            let missing = match (exit_onto(&origin, link.id), exit_onto(&destination, link.id)) {
+                (Some(exit), None) => Some((destination, exit)),
+                (None, Some(exit)) => Some((origin, exit)),
+                _ => None
+            };
+            if let Some((junc, exit)) = missing {
+                let mut junc = junc.borrow_mut();
+                junc.add_link(link.id, Junction::reciprocal(exit), 0);
+                // Keep the exits in heading order, as find_connections loads them.
+                junc.links.sort_by_key(|exit| exit.exit);
+            }
+        }
+    }
+
+    // Sets each exit's heading from the geometry of its link: the heading of the first segment where
+    // the link leaves the junction, or the reciprocal of the last where it arrives. Exits onto links
+    // with no segments, or onto links that start and end at the same junction, are left alone.
+    pub fn recompute_exit_headings(&mut self) {
+        for junc in &self.junctions {
+            let mut junc = junc.borrow_mut();
+            let id = junc.id;
+            for exit in junc.links.iter_mut() {
+                let Some(link) = self.get_link_checked(exit.link_id) else {
+                    continue;
+                };
+                let heading = if link.origin == Some(id) && link.destination != Some(id) {
+                    self.first_segment_for_link(link).map(|segment| segment.h)
+                } else if link.destination == Some(id) && link.origin != Some(id) {
+                    self.last_segment_for_link(link).map(|segment| Heading::new(segment.h).reciprocal().degrees())
+                } else {
+                    None
+                };
+                if let Some(heading) = heading {
+                    exit.exit = heading.round() as u32 % 360;
+                }
+            }
+            // Keep the exits in heading order, as find_connections loads them.
+            junc.links.sort_by_key(|exit| exit.exit);
+        }
+        // Hops cache the exit heading they were built with.
+        self.rebuild_routing();
+    }
+
+    // Each restriction is (junction, entry link, exit link). Restrictions at junctions that don't exist are ignored.
+    pub fn set_turn_restrictions(&mut self, restrictions:&[(u32, u16, u16)]) {
+        for (junc, entry_link, exit_link) in restrictions {
+            if let Some(junc) = self.get_junc_checked(*junc) {
+                junc.borrow_mut().restrict_turn(*entry_link, *exit_link);
+            }
+        }
+    }
+
+    // Links and junctions are looked up by id, so removing one leaves its slot in place, detached
+    // from the rest of the network: a removed link has no ends, no exits lead onto it and its tiles
+    // and segments are gone, and a removed junction has no exits. Routing is rebuilt afterwards.
+    // Ids that don't exist are ignored.
+    pub fn remove_link(&mut self, id:u16) {
+        if self.detach_link(id) {
+            self.rebuild_routing();
+        }
+    }
+
+    // Removes the junction's links too.
+    pub fn remove_junction(&mut self, id:u32) {
+        let Some(junc) = self.get_junc_checked(id) else {
+            return;
+        };
+        let mut incident:Vec<u16> = junc.borrow().links.iter().map(|exit| exit.link_id).collect();
+        incident.extend(self.links.iter().filter(|link| link.origin == Some(id) || link.destination == Some(id)).map(|link| link.id));
+        for link in incident {
+            self.detach_link(link);
+        }
+        let mut junc = junc.borrow_mut();
+        junc.links.clear();
+        junc.restricted_turns.clear();
+        drop(junc);
+        self.rebuild_routing();
+    }
+
+    fn detach_link(&mut self, id:u16) -> bool {
+        let Some(link) = self.links.iter_mut().find(|link| link.id == id) else {
+            return false;
+        };
+        let ends = [link.origin.take(), link.destination.take()];
+        for junc in ends.into_iter().flatten().filter_map(|junc| self.get_junc_checked(junc)) {
+            let mut junc = junc.borrow_mut();
+            junc.links.retain(|exit| exit.link_id != id);
+            junc.restricted_turns.retain(|(entry, exit)| *entry != id && *exit != id);
+        }
+        // A closed road can't be measured or matched onto.
+        let tiles:HashSet<u16> = self.tiles.iter().filter(|tile| tile.link == id).map(|tile| tile.id).collect();
+        if !tiles.is_empty() {
+            self.tiles.retain(|tile| !tiles.contains(&tile.id));
+            self.segments.retain(|segment| !tiles.contains(&segment.tile));
+            self.spatial_index = None;
+            self.index_segments();
+        }
+        true
+    }
+
+    fn rebuild_routing(&mut self) {
+        self.build_spanning_tree();
+        self.build_all_pairs_routing();
+    }
+
+    // Links that don't exist are ignored.
+    pub fn set_link_cost(&mut self, link:u16, multiplier:f64) {
+        if let Some(link) = self.links.iter_mut().find(|candidate| candidate.id == link) {
+            link.cost_multiplier = multiplier;
+        }
+    }
+
+    pub fn set_exit_ordering(&mut self, ordering:ExitOrdering) {
+        for junc in &self.junctions {
+            junc.borrow_mut().set_exit_ordering(ordering);
+        }
+    }
+
+    pub fn set_import_options(&mut self, options:NetworkImportOptions) {
+        self.options = options;
+    }
+
+    pub fn set_places(&mut self, places:Vec<Place>) {
+        self.places = places;
+    }
+
+    pub fn find_place(&self, name:&str) -> Option<&Place> {
+        self.places.iter().find(|place| place.name() == name)
+    }
+
+    // None if the place's link doesn't exist or the place lies beyond its end.
+    pub fn resolve_place(&self, place:&Place) -> Option<LogicalCoord> {
+        self.place_coord(place)
+    }
+
+    pub fn num_links(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn links_for_road(&self, road:RoadID) -> Vec<&Link> {
+        self.links().filter(|link| link.road() == Some(road)).collect()
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &Link> {
+        self.links.iter().map(|link| link.deref())
+    }
+
+    pub fn junctions(&self) -> impl Iterator<Item = Rc<RefCell<Junction>>> {
+        self.junctions.iter().cloned()
+    }
+
+    pub fn num_junctions(&self) -> usize {
+        self.junctions.len()
+    }
+
+    pub fn get_junc_mut(&mut self, id:u32) -> Rc<RefCell<Junction>> {
+        self.junctions[(id - 1) as usize].clone()
+    }
+
+    pub fn get_junc(&self, id:u32) -> Rc<RefCell<Junction>> {
+        self.junctions[(id-1) as usize].clone()
+    }
+
+    pub fn get_junc_checked(&self, id:u32) -> Option<Rc<RefCell<Junction>>> {
+        self.junctions.get(id.checked_sub(1)? as usize).cloned()
+    }
+
+    pub fn get_junc_if_exists(&self, id: Option<u32>) -> Option<Rc<RefCell<Junction>>> {
+        if let Some(valid_id) = id {
+            Some(self.get_junc(valid_id))
+        }
+        else {
+            None
+        }
+    }
+    pub fn get_junc_if_exists_mut(&mut self, id: Option<u32>) -> Option<Rc<RefCell<Junction>>> {
+        if let Some(valid_id) = id {
+            Some(self.get_junc_mut(valid_id))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+impl RouteTopology for Network {
+    fn link(&self, id:u16) -> Option<&Link> {
+        self.get_link_checked(id)
+    }
+
+    fn length(&self, link:&Link) -> f64 {
+        self.link_length(link)
+    }
+
+    fn end_headings(&self, link:&Link) -> (Option<f64>, Option<f64>) {
+        (self.first_segment_for_link(link).map(|segment| segment.h), self.last_segment_for_link(link).map(|segment| segment.h))
+    }
+
+    fn place(&self, name:&str) -> Option<&Place> {
+        self.find_place(name)
+    }
+
+    fn with_junction<R>(&self, id:u32, f:impl FnOnce(&Junction) -> R) -> Option<R> {
+        self.get_junc_checked(id).map(|junc| f(&junc.borrow()))
+    }
+}
+
+// A read-only copy of a loaded Network that can be shared between threads, for example behind the
+// handlers of a service. Junctions sit behind Arc<RwLock<..>> instead of Rc<RefCell<..>>, so every
+// lookup pays for a lock; query a Network instead when everything happens on one thread.
+pub struct SyncNetwork {
+    links:Vec<Link>,
+    junctions:Vec<Arc<RwLock<Junction>>>,
+    // Length and first and last segment headings for each link.
+    geometry:HashMap<u16, (f64, Option<f64>, Option<f64>)>,
+    places:Vec<Place>,
+    hops:HashMap<(u32, u32), Hop>
+}
+
+impl SyncNetwork {
+    pub fn from(connection:&Connection) -> SyncNetwork {
+        SyncNetwork::from_network(&Network::from(connection))
+    }
+
+    pub fn from_network(network:&Network) -> SyncNetwork {
+        let junctions = network.junctions().map(|junc| Arc::new(RwLock::new(junc.borrow().clone()))).collect();
+        let geometry = network.links().map(|link| {
+            let (first, last) = network.end_headings(link);
+            (link.id, (network.link_length(link), first, last))
+        }).collect();
+        SyncNetwork {
+            links:network.links().cloned().collect(),
+            junctions,
+            geometry,
+            places:network.places.clone(),
+            hops:network.routing.borrow().by_destination.clone()
+        }
+    }
+
+    pub fn num_links(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn num_junctions(&self) -> usize {
+        self.junctions.len()
+    }
+
+    // As Network::route.
+    pub fn route(&self, junc_id: u32, src_junc:u32, dest_junc:u32, to_dest:bool) -> Option<Hop> {
+        let src_junc = self.junction(src_junc)?.read().ok()?.id;
+        let target = if to_dest { dest_junc } else { src_junc };
+        self.junction(junc_id)?;
+        self.junction(target)?;
+        self.hops.get(&(junc_id, target)).copied()
+    }
+
+    pub fn evaluate_route(&self, route:&Route) -> Vec<(u32, usize, i16)> {
+        RouteSteps::new(self, route).collect()
+    }
+
+    pub fn neighbors(&self, junc:u32) -> Vec<(u32, u16)> {
+        self.links.iter()
+            .filter_map(|link| link.other_end(junc).map(|other| (other, link.id)))
+            .collect()
+    }
+
+    fn junction(&self, id:u32) -> Option<&Arc<RwLock<Junction>>> {
+        self.junctions.get(id.checked_sub(1)? as usize)
+    }
+}
+
+impl RouteTopology for SyncNetwork {
+    fn link(&self, id:u16) -> Option<&Link> {
+        self.links.get(id.checked_sub(1)? as usize)
+    }
+
+    fn length(&self, link:&Link) -> f64 {
+        self.geometry.get(&link.id).map_or(0.0, |geometry| geometry.0)
+    }
+
+    fn end_headings(&self, link:&Link) -> (Option<f64>, Option<f64>) {
+        self.geometry.get(&link.id).map_or((None, None), |geometry| (geometry.1, geometry.2))
+    }
+
+    fn place(&self, name:&str) -> Option<&Place> {
+        self.places.iter().find(|place| place.name == name)
+    }
+
+    fn with_junction<R>(&self, id:u32, f:impl FnOnce(&Junction) -> R) -> Option<R> {
+        let junc = self.junction(id)?.read().ok()?;
+        Some(f(&junc))
+    }
+}
+
+pub struct NetworkBuilder {
+    links:Vec<Box<Link>>,
+    junctions:Vec<Rc<RefCell<Junction>>>,
+    tiles:Vec<Box<Tile>>,
+    segments:Vec<Box<Segment>>,
+    next_junc:u32,
+    next_link:u16,
+    next_tile:u16,
+    next_segment:u16,
+    // The heading the next straight is laid on, left where the last arc finished.
+    heading:f64
+}
+
+impl<'a> NetworkBuilder {
+    pub fn new() -> NetworkBuilder {
+        NetworkBuilder {
+            links:Vec::new(),
+            junctions:Vec::new(),
+            tiles:Vec::new(),
+            segments:Vec::new(),
+            // Ids start at 1, as they do in the database.
+            next_junc:1,
+            next_link:1,
+            next_tile:1,
+            next_segment:1,
+            heading:0.0
+        }
+    }
+
+    pub fn create_link(&mut self) {
+        self.links.push(Box::new(Link::new(self.next_link)));
+        self.next_link+=1;
+        if let Some(j) = self.junctions.last_mut() {
+            j.borrow_mut().links.push(Exit{link_id:self.links.last().unwrap().id,exit:90,lane:0});
+        }
+    }
+
+    pub fn add_junction(&mut self) {
+        self.junctions.push(Rc::new(RefCell::from(Junction::new(self.next_junc))));
+        self.next_junc += 1;
+    }
+
+    // Links from to to, leaving from on exit_heading, and returns the new link's id. Like create_link,
+    // segments added next go on the new link.
+    //
+    // # Panics
+    //
+    // If either junction hasn't been added yet.
+    pub fn connect(&mut self, from:u32, to:u32, exit_heading:u32) -> u16 {
+        let exists = |junc:u32| (1..=self.junctions.len() as u32).contains(&junc);
+        assert!(exists(from) && exists(to), "can't connect junction {} to {}: only {} junctions", from, to, self.junctions.len());
+        let mut link = Link::new(self.next_link);
+        link.origin = Some(from);
+        link.destination = Some(to);
+        self.links.push(Box::new(link));
+        self.next_link += 1;
+        self.junctions[(from - 1) as usize].borrow_mut().add_link(self.next_link - 1, exit_heading, 0);
+        self.next_link - 1
+    }
+
+    pub fn set_heading(&mut self, heading:f64) {
+        self.heading = Heading::new(heading).degrees();
+    }
+
+    pub fn add_straight(&mut self, start:InertialCoord, length:f64) {
+        self.add_segment(start, self.heading, length, SegmentType::Straight);
+    }
+
+    // The sweep is in degrees, positive turning left, and the heading is taken at the start of the arc.
+    pub fn add_arc(&mut self, start:InertialCoord, heading:f64, radius:f64, sweep:f64) {
+        let length = radius * sweep.abs().to_radians();
+        self.add_segment(start, heading, length, SegmentType::Arc { radius });
+        if let Some(segment) = self.segments.last_mut() {
+            segment.sweep = Some(sweep);
+        }
+        self.set_heading(heading + sweep);
+    }
+
+    // Segments go on the current link's tile, creating one if the link doesn't have one yet.
+    fn add_segment(&mut self, start:InertialCoord, heading:f64, length:f64, segment_type:SegmentType) {
+        let link_id = match self.links.last() {
+            Some(link) => link.id,
+            None => return
+        };
+        let tile_id = match self.tiles.last() {
+            Some(tile) if tile.link == link_id => tile.id,
+            _ => {
+                self.tiles.push(Box::new(Tile::from_query(self.next_tile, link_id)));
+                self.next_tile += 1;
+                self.next_tile - 1
+            }
+        };
+        self.next_segment += 1;
+        self.segments.push(Box::new(Segment {
+            id:self.next_segment - 1,
+            tile:tile_id,
+            x:start.x,
+            y:start.y,
+            z:start.z,
+            h:heading,
+            length,
+            segment_type,
+            ..Segment::new()
+        }));
+    }
+
+    pub fn build(self) -> Box<Network> {
+        let mut network = Network::new(self.links, self.junctions);
+        network.set_tiles(self.tiles);
+        network.set_segments(self.segments);
+        Box::new(network)
+    }
+}
+
+struct LinkGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> LinkGateway<'a> {
+    pub fn new(connection: &'a Connection) -> LinkGateway<'a> {
+        LinkGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> LinkGateway<'a> {
+        LinkGateway {
+            connection,
+            schema
+        }
+    }
+
+    pub fn find_all(&self) -> Result<Vec<Box<Link>>, Error> {
+        let mut statement = self.connection.prepare(&self.schema.select("links"))?;
+        let link_iter = statement.query_map([], |row| {
+            // The road, lane and one-way columns are optional, so older databases without them still load.
+            let major:Option<i16> = row.get("road_major").unwrap_or(None);
+            let minor:Option<i16> = row.get("road_minor").unwrap_or(None);
+            let road = major.zip(minor).map(|(major, minor)| RoadID::new(major, minor));
+            let lane_count:i16 = row.get("lane_count").unwrap_or(None).unwrap_or(1);
+            let one_way:bool = row.get("one_way").unwrap_or(None).unwrap_or(false);
+            Ok(Link::from_query(row.get("id")?, row.get("origin")?, row.get("destination")?, road, lane_count, one_way))
+        })?;
+        link_iter.map(|link| link.map(Box::new)).collect()
+    }
+}
+
+struct JunctionGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> JunctionGateway<'a> {
+    pub fn new(connection: &'a Connection) -> JunctionGateway<'a> {
+        JunctionGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> JunctionGateway<'a> {
+        JunctionGateway {
+            connection,
+            schema
+        }
+    }
+    pub fn find_all(&self) -> Result<Vec<Rc<RefCell<Junction>>>, Error> {
+        let mut statement = self.connection.prepare(&self.schema.select("junctions"))?;
+        let junc_iter = statement.query_map([], |row| {
+            Ok(Junction::from_query(row.get("id")?))
+        })?;
+        junc_iter.map(|junc| junc.map(|junc| Rc::new(RefCell::from(junc)))).collect()
+    }
+
+    // Junctions with their exits already attached, from one join ordered the same way as find_connections.
+    // Connections to junctions that don't exist are dropped, as set_junction_connections does.
+    pub fn find_all_with_connections(&self) -> Result<Vec<Rc<RefCell<Junction>>>, Error> {
+        let query = format!("SELECT junctions.id AS junc, junctions_links.* FROM ({}) AS junctions LEFT JOIN ({}) AS junctions_links ON junctions_links.junc_id = junctions.id ORDER BY junctions.id, junctions_links.exit;",
+            self.schema.select("junctions"), self.schema.select("junctions_links"));
+        let mut statement = self.connection.prepare(&query)?;
+        let mut rows = statement.query([])?;
+        let mut juncs:Vec<Junction> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id:u32 = row.get("junc")?;
+            if juncs.last().is_none_or(|junc| junc.id != id) {
+                juncs.push(Junction::from_query(id));
+            }
+            let link:Option<u16> = row.get("link_id")?;
+            let exit:Option<u32> = row.get("exit")?;
+            if let (Some(link), Some(exit), Some(junc)) = (link, exit, juncs.last_mut()) {
+                let lane:i16 = row.get("lane").unwrap_or(None).unwrap_or(0);
+                junc.add_link(link, exit, lane);
+            }
+        }
+        Ok(juncs.into_iter().map(|junc| Rc::new(RefCell::new(junc))).collect())
+    }
+
+    pub fn find_connections(&self) -> Result<Vec<(u32,u16,u32,i16)>, Error> {
+        let mut statement = self.connection.prepare(&format!("{} ORDER BY junc_id, exit;", self.schema.select("junctions_links")))?;
+        let connection_iter = statement.query_map([], |row| {
+            let lane:i16 = row.get("lane").unwrap_or(None).unwrap_or(0);
+            Ok((row.get::<&str, u32>("junc_id")?, row.get::<&str, u16>("link_id")?, row.get::<&str, u32>("exit")?, lane))
+        })?;
+        connection_iter.collect()
+    }
+}
+
+struct TileGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> TileGateway<'a> {
+    pub fn new(connection: &'a Connection) -> TileGateway<'a> {
+        TileGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> TileGateway<'a> {
+        TileGateway {
+            connection,
+            schema
+        }
+    }
+    pub fn find_all(&self) -> Result<Vec<Box<Tile>>, Error> {
+        let mut statement = self.connection.prepare(&self.schema.select("tiles"))?;
+        let tile_iter = statement.query_map([], |row| {
+            let id = row.get("id")?;
+            // Databases without a seq column are in id order.
+            Ok(Tile { seq:row.get("seq").unwrap_or(id), ..Tile::from_query(id, row.get("link_id")?) })
+        })?;
+        tile_iter.map(|tile| tile.map(Box::new)).collect()
+    }
+}
+
+struct PlaceGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> PlaceGateway<'a> {
+    pub fn new(connection: &'a Connection) -> PlaceGateway<'a> {
+        PlaceGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> PlaceGateway<'a> {
+        PlaceGateway {
+            connection,
+            schema
+        }
+    }
+
+    pub fn find_all(&self) -> Result<Vec<Place>, Error> {
+        let mut statement = self.connection.prepare(&self.schema.select("places"))?;
+        let place_iter = statement.query_map([], |row| Ok(Place::from_query(row)))?;
+        place_iter.collect()
+    }
+}
+
+struct TurnRestrictionGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> TurnRestrictionGateway<'a> {
+    pub fn new(connection: &'a Connection) -> TurnRestrictionGateway<'a> {
+        TurnRestrictionGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> TurnRestrictionGateway<'a> {
+        TurnRestrictionGateway {
+            connection,
+            schema
+        }
+    }
+
+    pub fn find_all(&self) -> Result<Vec<(u32, u16, u16)>, Error> {
+        let mut statement = self.connection.prepare(&format!("SELECT junc_id, entry_link, exit_link FROM ({});", self.schema.select("turn_restrictions")))?;
+        let restriction_iter = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        restriction_iter.collect()
+    }
+}
+
+struct SegmentGateway<'a> {
+    connection: &'a Connection,
+    schema: &'a SchemaMapping
+}
+
+impl<'a> SegmentGateway<'a> {
+    pub fn new(connection: &'a Connection) -> SegmentGateway<'a> {
+        SegmentGateway::with_schema(connection, &DEFAULT_SCHEMA)
+    }
+
+    pub fn with_schema(connection: &'a Connection, schema: &'a SchemaMapping) -> SegmentGateway<'a> {
+        SegmentGateway {
+            connection,
+            schema
+        }
+    }
+
+    // With the wkt feature, a table with a geometry column is read by find_all_wkt.
+    pub fn find_all(&self) -> Result<Vec<Box<Segment>>, Error> {
+        let mut statement = self.connection.prepare(&self.schema.select("segments"))?;
+        #[cfg(feature = "wkt")]
+        if statement.column_names().contains(&"geometry") {
+            return self.find_all_wkt().map(|segments| segments.into_iter().map(Box::new).collect());
+        }
+        let seg_iter = statement.query_map([], Segment::from_query)?;
+        seg_iter.map(|segment| segment.map(Box::new)).collect()
+    }
+
+    // Segments whose position comes from a WKT geometry column instead of x, y and z. A POINT is the
+    // segment's start, with the rest read as find_all reads it. A LINESTRING becomes a straight for
+    // each pair of positions on the row's tile. Segments are renumbered from 1 in row order.
+    #[cfg(feature = "wkt")]
+    pub fn find_all_wkt(&self) -> Result<Vec<Segment>, Error> {
+        let mut statement = self.connection.prepare(&format!("{} ORDER BY id;", self.schema.select("segments")))?;
+        let mut rows = statement.query([])?;
+        let mut segments:Vec<Segment> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let geometry:String = row.get("geometry")?;
+            let bad = |reason:&str| Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, format!("{}: {}", reason, geometry).into());
+            let tile = row.get("tile_id")?;
+            let next_id = |segments:&[Segment]| u16::try_from(segments.len() + 1).map_err(|_| bad("too many segments"));
+            match Self::parse_wkt(&geometry).ok_or_else(|| bad("invalid geometry"))? {
+                (false, positions) => {
+                    segments.push(Segment {
+                        id:next_id(&segments)?,
+                        tile,
+                        x:positions[0].x,
+                        y:positions[0].y,
+                        z:positions[0].z,
+                        h:row.get("h").unwrap_or(0.0),
+                        length:row.get("length").unwrap_or(0.0),
+                        segment_type:Segment::segment_type_from_field(row.get("type").unwrap_or(0), row.get("radius").unwrap_or(0.0)),
+                        ..Segment::new()
+                    });
+                }
+                (true, positions) => {
+                    for pair in positions.windows(2) {
+                        let (dx, dy) = (pair[1].x - pair[0].x, pair[1].y - pair[0].y);
+                        segments.push(Segment {
+                            id:next_id(&segments)?,
+                            tile,
+                            x:pair[0].x,
+                            y:pair[0].y,
+                            z:pair[0].z,
+                            h:Heading::new((-dx).atan2(dy).to_degrees()).degrees(),
+                            length:dx.hypot(dy),
+                            ..Segment::new()
+                        });
+                    }
+                }
+            }
+        }
+        Ok(segments)
+    }
+
+    // The positions of a POINT or LINESTRING, with or without a Z, and whether it was a LINESTRING.
+    #[cfg(feature = "wkt")]
+    fn parse_wkt(text:&str) -> Option<(bool, Vec<InertialCoord>)> {
+        let (tag, body) = text.trim().split_once('(')?;
+        let body = body.strip_suffix(')')?;
+        let mut words = tag.split_whitespace().map(|word| word.to_ascii_uppercase());
+        let is_line = match words.next()?.as_str() {
+            "POINT" => false,
+            "LINESTRING" => true,
+            _ => return None
+        };
+        if words.next().is_some_and(|dimension| dimension != "Z") {
+            return None;
+        }
+        let positions = body.split(',').map(|position| {
+            let values = position.split_whitespace().map(|value| value.parse::<f64>().ok()).collect::<Option<Vec<f64>>>()?;
+            match values.as_slice() {
+                [x, y] => Some(InertialCoord::new(*x, *y, 0.0)),
+                [x, y, z] => Some(InertialCoord::new(*x, *y, *z)),
+                _ => None
+            }
+        }).collect::<Option<Vec<InertialCoord>>>()?;
+        let valid = if is_line { positions.len() >= 2 } else { positions.len() == 1 };
+        valid.then_some((is_line, positions))
+    }
+}
+
+// A heading in degrees anticlockwise from north, normalised to [0, 360).
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Heading(f64);
+
+impl Heading {
+    pub fn new(degrees:f64) -> Heading {
+        let degrees = degrees.rem_euclid(360.0);
+        // rem_euclid can round a tiny negative input up to exactly 360.
+        Heading(if degrees >= 360.0 { 0.0 } else { degrees })
+    }
+
+    pub fn degrees(&self) -> f64 {
+        self.0
+    }
+
+    pub fn reciprocal(&self) -> Heading {
+        Heading::new(self.0 + 180.0)
+    }
+
+    // 0 for headings within a quarter turn of north, 1 for the southern half.
+    pub fn hemisphere(&self) -> u32 {
+        if self.0 < 90.0 || self.0 >= 270.0 { 0 } else { 1 }
+    }
+
+    // The smaller angle between two headings, in 0..=180.
+    pub fn circular_delta(&self, other:Heading) -> f64 {
+        let delta = Heading::new(self.0 - other.0).0;
+        f64::min(delta, 360.0 - delta)
+    }
+}
+
+#[deprecated(note = "use Heading::reciprocal")]
+pub fn find_reciprocal_heading(heading:f64) -> f64 {
+    Heading::new(heading).reciprocal().degrees()
+}
+
+#[deprecated(note = "use Heading::circular_delta")]
+pub fn heading_difference(a:f64, b:f64) -> f64 {
+    Heading::new(a).circular_delta(Heading::new(b))
+}
+
+// Unit vector for a heading in degrees anticlockwise from north (+y), so 90 points along -x.
+pub fn heading_to_direction(heading:f64) -> (f64, f64) {
+    let radians = heading.to_radians();
+    (-radians.sin(), radians.cos())
+}
+
+#[deprecated(note = "use Heading::hemisphere")]
+pub fn hemisphere(input:u32) -> u32 {
+    Heading::new(input as f64).hemisphere()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+    use rstest::rstest;
+    use rusqlite::Connection;
+    use super::*;
+    use crate::math::{Curve, Identifier, InertialCoord, LogicalAddress, LogicalCoord, Mask, Network, NetworkBuilder, Segment, SegmentType};
+
+    #[test]
+    fn test_inertial_coords() {
+        let sut = InertialCoord::new(1.0, 2.0, 3.0);
+        assert_eq!(sut.x, 1.0);
+        assert_eq!(sut.y, 2.0);
+        assert_eq!(sut.z, 3.0);
+    }
+
+    #[test]
+    fn test_logical_coords() {
+        let sut = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), 1.0, 2.0, 3.0);
+        assert_eq!(sut.offset, 1.0);
+        assert_eq!(sut.distance, 2.0);
+        assert_eq!(sut.loft, 3.0);
+    }
+
+    #[rstest]
+    #[case(-1.825, 50.0, 0.0)]
+    fn test_logical_to_inertial_coords(#[case] _offset: f64, #[case] _distance: f64, #[case] _loft: f64) {
+        let sut = Curve::new();
+        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), -1.825, 50.0, 0.0);
+        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+        sut.logical_to_inertial(&logical, &mut inertial);
+        assert_eq!(inertial.x, -1.825);
+        assert_eq!(inertial.y, 50.0);
+        assert_eq!(inertial.z, 0.0);
+    }
+
+    #[rstest]
+    #[case(-1.825, 50.0, 0.0)]
+    fn test_inertial_to_logical(#[case] x: f64, #[case] y: f64, #[case] z: f64) {
+        let sut = Curve::new();
+        let mut logical = LogicalCoord::empty();
+        let inertial = InertialCoord::new(x, y, z);
+        sut.inertial_to_logical(&inertial, &mut logical);
+        assert_eq!(logical.offset, -1.825);
+        assert_eq!(logical.distance, 50.0);
+        assert_eq!(logical.loft, 0.0);
+    }
+
+    #[rstest]
+    #[case(100.0, 0.0, 0.0, 10.0, 1.825, 10.0, -1.825)]
+    #[case(0.0, 100.0, 0.0, 10.0, 1.825, 1.825, 10.0)]
+    #[case(-100.0, 0.0, 0.0, 10.0, 1.825, -10.0, 1.825)]
+    #[case(0.0, 100.0, 2.0, 50.0, -1.825, -1.825, 50.0)]
+    fn test_straight_logical_to_inertial(#[case] end_x:f64, #[case] end_y:f64, #[case] loft:f64, #[case] distance:f64, #[case] offset:f64, #[case] expected_x:f64, #[case] expected_y:f64) {
+        let sut = Curve::straight(InertialCoord::new(0.0, 100.0, 0.0), InertialCoord::new(end_x, end_y + 100.0, 0.0));
+        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), offset, distance, loft);
+        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+        sut.logical_to_inertial(&logical, &mut inertial);
+        assert!((inertial.x - expected_x).abs() < 1e-9, "x {} != {}", inertial.x, expected_x);
+        assert!((inertial.y - 100.0 - expected_y).abs() < 1e-9, "y {} != {}", inertial.y - 100.0, expected_y);
+        assert_eq!(inertial.z, loft);
+    }
+
+    #[rstest]
+    #[case(-3.65)]
+    #[case(-1.825)]
+    #[case(0.0)]
+    #[case(1.825)]
+    #[case(3.65)]
+    fn test_straight_round_trip(#[case] offset:f64) {
+        let sut = Curve::straight(InertialCoord::new(5.0, -3.0, 1.0), InertialCoord::new(35.0, 37.0, 1.0));
+        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), offset, 42.0, 0.5);
+        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+        sut.logical_to_inertial(&logical, &mut inertial);
+        let mut actual = LogicalCoord::empty();
+        sut.inertial_to_logical(&inertial, &mut actual);
+        assert!((actual.offset - offset).abs() < 1e-9);
+        assert!((actual.distance - 42.0).abs() < 1e-9);
+        assert!((actual.loft - 0.5).abs() < 1e-9);
+    }
+
+    #[rstest]
+    #[case(90.0, 0.0, 0.0, 100.0, 0.0)]
+    #[case(90.0, 25.0 * std::f64::consts::PI / 2.0, 0.0, 100.0 * 22.5f64.to_radians().cos(), 100.0 * 22.5f64.to_radians().sin())]
+    #[case(90.0, 50.0 * std::f64::consts::PI, 0.0, 0.0, 100.0)]
+    #[case(90.0, 0.0, 1.825, 101.825, 0.0)]
+    #[case(-90.0, 0.0, 1.825, 98.175, 0.0)]
+    #[case(-90.0, 25.0 * std::f64::consts::PI / 2.0, 0.0, 100.0 * 22.5f64.to_radians().cos(), -100.0 * 22.5f64.to_radians().sin())]
+    fn test_arc_logical_to_inertial(#[case] sweep:f64, #[case] distance:f64, #[case] offset:f64, #[case] expected_x:f64, #[case] expected_y:f64) {
+        let sut = Curve::arc(InertialCoord::new(0.0, 0.0, 0.0), 100.0, 0.0, sweep);
+        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), offset, distance, 0.0);
+        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+        sut.logical_to_inertial(&logical, &mut inertial);
+        assert!((inertial.x - expected_x).abs() < 1e-9, "x {} != {}", inertial.x, expected_x);
+        assert!((inertial.y - expected_y).abs() < 1e-9, "y {} != {}", inertial.y, expected_y);
+    }
+
+    #[rstest]
+    #[case(90.0, -1.825, 0.0)]
+    #[case(90.0, 1.825, 40.0)]
+    #[case(-90.0, 1.825, 100.0)]
+    #[case(270.0, 0.0, 300.0)]
+    fn test_arc_round_trip(#[case] sweep:f64, #[case] offset:f64, #[case] distance:f64) {
+        let sut = Curve::arc(InertialCoord::new(10.0, -20.0, 0.0), 80.0, 45.0, sweep);
+        let logical = LogicalCoord::new(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false)), offset, distance, 0.5);
+        let mut inertial = InertialCoord::new(0.0, 0.0, 0.0);
+        sut.logical_to_inertial(&logical, &mut inertial);
+        let mut actual = LogicalCoord::empty();
+        sut.inertial_to_logical(&inertial, &mut actual);
+        assert!((actual.offset - offset).abs() < 1e-9);
+        assert!((actual.distance - distance).abs() < 1e-9);
+        assert!((actual.loft - 0.5).abs() < 1e-9);
+    }
+
+    #[rstest]
+    #[case("1.1.1.0/1.1.1.0", Ok(LogicalAddress::new(Identifier::new(1,1,1,0),Mask::new(true,true,true,false))))]
+    #[case("2.10.2.1/1.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,1),Mask::new(true,true,true,true))))]
+    #[case("2.10.2.-1/1.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
+    #[case("-2.10.2.-1/1.1.1.1", Err("Expected whole number, got minus sign"))]
+    #[case("2.10.2.-1/2.1.1.1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
+    #[case("2.10.2.-1", Ok(LogicalAddress::new(Identifier::new(2,10,2,-1),Mask::new(true,true,true,true))))]
+    #[case("", Err("Expected some content before the '/'"))]
+    #[case("/", Err("Expected some content before the '/'"))]
+    #[case("/1.1.1.1", Err("Expected some content before the '/'"))]
+    fn test_parse_logical_address(#[case] str: &str, #[case] addr: Result<LogicalAddress, &str>) {
+        assert_eq!(LogicalAddress::parse(str),addr);
+    }
+
+    #[rstest]
+    #[case("1.1.1.0/1.1.1.0", "1.1.1.0/1.1.1.0")]
+    #[case("2.10.2.1/1.1.1.1", "2.10.2.1/1.1.1.1")]
+    #[case("2.10.2.-1/1.1.1.1", "2.10.2.-1/1.1.1.1")]
+    #[case("2.10.2.-1/2.1.1.1", "2.10.2.-1/1.1.1.1")]
+    #[case("2.10.2.-1", "2.10.2.-1/1.1.1.1")]
+    fn test_logical_address_round_trip(#[case] str: &str, #[case] expected: &str) {
+        let addr = LogicalAddress::parse(str).unwrap();
+        let text = format!("{}", addr);
+        assert_eq!(expected, text);
+        assert_eq!(Ok(addr), LogicalAddress::parse(&text));
+    }
+
+    #[rstest]
+    #[case(Mask::new(true,true,true,true), "1.1.1.1")]
+    #[case(Mask::new(true,false,true,false), "1.0.1.0")]
+    #[case(Mask::new(false,false,false,false), "0.0.0.0")]
+    fn test_mask_display(#[case] mask: Mask, #[case] expected: &str) {
+        assert_eq!(expected, format!("{}", mask));
+        assert_eq!(mask, Mask::parse(expected));
+        assert_eq!(Ok(mask), expected.parse::<Mask>());
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("not a mask")]
+    #[case("1.1.1")]
+    #[case("1.1.1.1.1")]
+    #[case("1.2.1.1")]
+    #[case("1.1.1.1 ")]
+    fn test_mask_from_str_invalid(#[case] input: &str) {
+        assert!(input.parse::<Mask>().is_err());
+    }
+
+    #[rstest]
+    #[case("65535.65535.65535.32767", Ok(Identifier::new(65535,65535,65535,32767)))]
+    #[case("1.1.1.-32768", Ok(Identifier::new(1,1,1,-32768)))]
+    #[case("65536.1.1.0", Err("link out of range"))]
+    #[case("99999.1.1.0", Err("link out of range"))]
+    #[case("1.65536.1.0", Err("tile out of range"))]
+    #[case("1.1.65536.0", Err("segment out of range"))]
+    #[case("1.1.1.32768", Err("lane out of range"))]
+    #[case("1.1.1.-32769", Err("lane out of range"))]
+    #[case("1.1.1.40000.", Err("lane out of range"))]
+    fn test_parse_identifier(#[case] str:&str, #[case] expected:Result<Identifier, &str>) {
+        assert_eq!(expected, Identifier::parse(str));
+    }
+
+    #[rstest]
+    #[case(Identifier::new(1,1,1,0), "1.1.1.0")]
+    #[case(Identifier::new(2,10,2,1), "2.10.2.1")]
+    #[case(Identifier::new(2,10,2,-1), "2.10.2.-1")]
+    #[case(Identifier::new(65535,0,0,-32768), "65535.0.0.-32768")]
+    fn test_identifier_round_trip(#[case] id:Identifier, #[case] expected:&str) {
+        let text = format!("{}", id);
+        assert_eq!(expected, text);
+        assert_eq!(Ok(id), text.parse::<Identifier>());
+    }
+
+    #[test]
+    fn test_identifier_from_str_error() {
+        assert_eq!(Err("Expected whole number, got minus sign".to_string()), "-2.10.2.-1".parse::<Identifier>());
+    }
+
+    #[rstest]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(1,2,3,4), Mask::new(true,true,true,true), true)]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(1,2,3,-4), Mask::new(true,true,true,true), false)]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(1,2,3,-4), Mask::new(true,true,true,false), true)]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(1,9,9,9), Mask::new(true,false,false,false), true)]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(2,2,3,4), Mask::new(true,false,false,false), false)]
+    #[case(Identifier::new(1,2,3,4), Identifier::new(9,9,9,9), Mask::new(false,false,false,false), true)]
+    fn test_identifier_matches(#[case] id:Identifier, #[case] other:Identifier, #[case] mask:Mask, #[case] expected:bool) {
+        assert_eq!(expected, id.matches(&other, &mask));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4.0.0.0/1.0.0.0", vec![4, 5])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "5.5.0.0/1.1.0.0", vec![6, 7])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "5.4.0.0/1.1.0.0", vec![])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4.4.5.0/1.1.1.0", vec![5])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4.4.5.3", vec![5])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "0.0.0.0/0.0.0.0", vec![1, 2, 3, 4, 5, 6, 7])]
+    fn test_find_segments_by_address(#[case] dbfile:&str, #[case] addr:&str, #[case] expected:Vec<u16>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let addr = LogicalAddress::parse(addr).unwrap();
+        let actual:Vec<u16> = network.find_segments_by_address(&addr).iter().map(|segment| segment.id).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_network_builder_add() {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        assert_eq!(sut.junctions.len(), 1);
+        sut.create_link();
+        sut.add_straight(InertialCoord::new(0.0, 0.0, 0.0), 252.0);
         let network = sut.build();
         assert_eq!(1,network.num_links());
+        assert_eq!(1,network.num_segments());
+    }
+
+    #[test]
+    fn test_network_builder_add_arc() {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        sut.create_link();
+        sut.add_arc(InertialCoord::new(0.0, 0.0, 0.0), 0.0, 100.0, 90.0);
+        let network = sut.build();
+        assert_eq!(1, network.num_tiles());
+        assert_eq!(1, network.num_segments());
+        let segment = network.first_segment_for_link(network.get_link(1)).unwrap();
+        assert_eq!(SegmentType::Arc { radius: 100.0 }, segment.segment_type);
+        assert!((segment.length() - 50.0 * std::f64::consts::PI).abs() < 1e-9);
+    }
+
+    #[rstest]
+    #[case(10.0)]
+    #[case(100.0)]
+    #[case(252.0)]
+    fn test_arc_length(#[case] radius:f64) {
+        let sut = Segment { segment_type:SegmentType::Arc { radius }, sweep:Some(90.0), ..Segment::new() };
+        assert!((sut.length() - std::f64::consts::PI * radius / 2.0).abs() < 1e-9);
+        let straight = Segment { length:radius, ..Segment::new() };
+        assert_eq!(radius, straight.length());
+    }
+
+    #[test]
+    fn test_link_length_with_arc() {
+        let dbfile = "data/tests/LoadFromDB/arcs.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let link = network.get_link(1);
+        assert!((network.link_length(link) - (252.0 + 50.0 * std::f64::consts::PI)).abs() < 1e-9);
+        let end = network.logical_to_inertial(&LogicalCoord::new(LogicalAddress::for_link(1), 0.0, network.link_length(link), 0.0)).unwrap();
+        assert!((end.x + 100.0).abs() < 1e-9 && (end.y - 352.0).abs() < 1e-9, "ends at ({}, {})", end.x, end.y);
+    }
+
+    #[rstest]
+    #[case(None, None, 0.0)]
+    #[case(Some(90.0), None, 90.0)]
+    #[case(Some(-90.0), None, 270.0)]
+    #[case(None, Some((0.0, 90.0)), 90.0)]
+    #[case(Some(180.0), Some((270.0, -45.0)), 225.0)]
+    fn test_network_builder_straight_heading(#[case] heading:Option<f64>, #[case] arc:Option<(f64, f64)>, #[case] expected:f64) {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        if let Some(heading) = heading {
+            sut.set_heading(heading);
+        }
+        if let Some((arc_heading, sweep)) = arc {
+            sut.create_link();
+            sut.add_arc(InertialCoord::new(0.0, 0.0, 0.0), arc_heading, 100.0, sweep);
+        }
+        sut.create_link();
+        sut.add_straight(InertialCoord::new(0.0, 0.0, 0.0), 252.0);
+        let network = sut.build();
+        let link = network.get_link(network.num_links() as u16);
+        let segment = network.first_segment_for_link(link).expect("straight should have made a segment");
+        assert_eq!(SegmentType::Straight, segment.segment_type);
+        assert_eq!(expected, segment.h);
+        assert_eq!(252.0, network.link_length(link));
+    }
+
+    #[rstest]
+    #[case(90.0, -100.0, 100.0, 90.0)]
+    #[case(-90.0, 100.0, 100.0, 270.0)]
+    fn test_network_builder_arc_end(#[case] sweep:f64, #[case] expected_x:f64, #[case] expected_y:f64, #[case] expected_heading:f64) {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        sut.create_link();
+        sut.add_arc(InertialCoord::new(0.0, 0.0, 0.0), 0.0, 100.0, sweep);
+        sut.create_link();
+        sut.add_straight(InertialCoord::new(expected_x, expected_y, 0.0), 10.0);
+        let network = sut.build();
+        let arc = network.get_link(1);
+        let end = network.logical_to_inertial(&LogicalCoord::new(LogicalAddress::for_link(1), 0.0, network.link_length(arc), 0.0)).unwrap();
+        assert!((end.x - expected_x).abs() < 1e-9 && (end.y - expected_y).abs() < 1e-9, "ends at ({}, {})", end.x, end.y);
+        let straight = network.first_segment_for_link(network.get_link(2)).expect("straight should have made a segment");
+        assert_eq!(expected_heading, straight.h);
+    }
+
+    #[rstest]
+    #[case(LogicalAddress::for_link(1), 1.825, 300.0, 1.825, 300.0)]
+    #[case(LogicalAddress::for_link(1), -1.825, 126.0, -1.825, 126.0)]
+    #[case(LogicalAddress::for_link(1), 0.0, 504.0, 0.0, 504.0)]
+    #[case(LogicalAddress::new(Identifier::new(1, 2, 2, 0), Mask::new(true, true, true, false)), 1.825, 48.0, 1.825, 300.0)]
+    fn test_network_logical_to_inertial_round_trip(#[case] addr:LogicalAddress, #[case] offset:f64, #[case] distance:f64, #[case] expected_x:f64, #[case] expected_y:f64) {
+        let dbfile = "data/tests/LoadFromDB/onelink.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let coord = LogicalCoord::new(addr, offset, distance, 0.5);
+        let inertial = network.logical_to_inertial(&coord).expect("coordinate should be on the link");
+        assert!((inertial.x - expected_x).abs() < 1e-9, "x {} != {}", inertial.x, expected_x);
+        assert!((inertial.y - expected_y).abs() < 1e-9, "y {} != {}", inertial.y, expected_y);
+        assert!((inertial.z - 0.5).abs() < 1e-9);
+        let actual = network.inertial_to_logical(&inertial).expect("network has segments");
+        assert_eq!(LogicalAddress::for_link(1), actual.addr);
+        assert!((actual.offset - offset).abs() < 1e-9, "offset {} != {}", actual.offset, offset);
+        assert!((actual.distance - expected_y).abs() < 1e-9, "distance {} != {}", actual.distance, expected_y);
+        assert!((actual.loft - 0.5).abs() < 1e-9);
+    }
+
+    #[rstest]
+    #[case(1.0, 100.0, 1, 1.0)]
+    #[case(-2.0, 400.0, 2, 2.0)]
+    #[case(0.0, -30.0, 1, 30.0)]
+    #[case(-20.0, 300.0, 3, 6.0)]
+    fn test_nearest_segment(#[case] x:f64, #[case] y:f64, #[case] tile:u16, #[case] distance:f64) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let (actual_tile, actual_distance) = network.nearest_segment(&InertialCoord::new(x, y, 0.0)).expect("network has segments");
+        assert_eq!(tile, actual_tile);
+        assert!((actual_distance - distance).abs() < 1e-9, "distance {} != {}", actual_distance, distance);
+    }
+
+    #[test]
+    fn test_spatial_index_matches_scan() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        let points:Vec<InertialCoord> = (-4..12).flat_map(|i| (-4..12).map(move |j| InertialCoord::new(i as f64 * 37.5 - 3.0, j as f64 * 41.0 + 7.0, 0.0))).collect();
+        let scanned:Vec<Option<(u16, f64)>> = points.iter().map(|point| network.nearest_segment(point)).collect();
+        network.build_spatial_index();
+        assert!(network.spatial_index().is_some_and(|index| index.cell_size() > 0.0));
+        let indexed:Vec<Option<(u16, f64)>> = points.iter().map(|point| network.nearest_segment(point)).collect();
+        assert_eq!(scanned, indexed);
+        assert!(network.nearest_segment(&InertialCoord::new(10000.0, -5000.0, 0.0)).is_some());
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_bbox_queries(#[case] indexed:bool) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        if indexed {
+            network.build_spatial_index();
+        }
+        // The western half, stopping short of the north-south links along x = 0.
+        let west = || (InertialCoord::new(-300.0, -10.0, 0.0), InertialCoord::new(-1.0, 600.0, 0.0));
+        let (min, max) = west();
+        let segments:Vec<u16> = network.segments_in_bbox(min, max).iter().map(|segment| segment.id).collect();
+        assert_eq!(vec![3, 4, 5], segments);
+        let (min, max) = west();
+        let junctions:Vec<u32> = network.junctions_in_bbox(min, max).iter().map(|junc| junc.borrow().id).collect();
+        assert_eq!(vec![3, 4, 5], junctions);
+        // z plays no part.
+        let (min, max) = (InertialCoord::new(-300.0, -10.0, 100.0), InertialCoord::new(-1.0, 600.0, 200.0));
+        assert_eq!(3, network.segments_in_bbox(min, max).len());
+        assert!(network.segments_in_bbox(InertialCoord::new(1000.0, 1000.0, 0.0), InertialCoord::new(2000.0, 2000.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn test_map_match() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        network.build_spatial_index();
+        let trace = std::fs::read_to_string("data/tests/MapMatch/fivelinks_trace.csv").unwrap();
+        let points:Vec<InertialCoord> = trace.lines().skip(1).map(|line| {
+            let fields:Vec<f64> = line.split(',').map(|field| field.trim().parse().unwrap()).collect();
+            InertialCoord::new(fields[0], fields[1], 0.0)
+        }).collect();
+        // On its own the sixth point is nearer the parallel link 3, which can't be reached from link 2 without leaving it.
+        assert_eq!(Some(3), network.nearest_segment(&points[5]).map(|(tile, _)| tile));
+        let matched = network.map_match(&points);
+        let tiles:Vec<u16> = matched.iter().map(|(tile, _)| *tile).collect();
+        assert_eq!(vec![1, 1, 1, 1, 2, 2, 2], tiles);
+        let offsets:Vec<f64> = matched.iter().map(|(_, offset)| *offset).collect();
+        for (expected, actual) in [50.0, 150.0, 240.0, 252.0, 40.0, 120.0, 200.0].iter().zip(offsets) {
+            assert!((expected - actual).abs() < 1e-6, "expected {} got {}", expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_map_match_two_way() {
+        let mut sut = NetworkBuilder::new();
+        for _ in 0..4 {
+            sut.add_junction();
+        }
+        sut.connect(1, 2, 0);
+        sut.add_straight(InertialCoord::new(0.0, 0.0, 0.0), 100.0);
+        // Link 2 runs back towards junction 2, so the trace goes along it from its destination.
+        sut.connect(3, 2, 180);
+        sut.set_heading(180.0);
+        sut.add_straight(InertialCoord::new(0.0, 200.0, 0.0), 100.0);
+        // Link 3 leaves junction 2 forwards, alongside link 2 but further from the trace.
+        sut.connect(2, 4, 0);
+        sut.set_heading(0.0);
+        sut.add_straight(InertialCoord::new(5.0, 100.0, 0.0), 100.0);
+        let network = sut.build();
+        let points = [(0.0, 20.0), (0.0, 60.0), (0.0, 120.0), (0.0, 160.0)].map(|(x, y)| InertialCoord::new(x, y, 0.0));
+        let matched = network.map_match(&points);
+        let tiles:Vec<u16> = matched.iter().map(|(tile, _)| *tile).collect();
+        assert_eq!(vec![1, 1, 2, 2], tiles);
+        let offsets:Vec<f64> = matched.iter().map(|(_, offset)| *offset).collect();
+        for (expected, actual) in [20.0, 60.0, 80.0, 40.0].iter().zip(offsets) {
+            assert!((expected - actual).abs() < 1e-6, "expected {} got {}", expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_map_match_without_segments() {
+        let network = Network::empty();
+        assert!(network.map_match(&[InertialCoord::new(0.0, 0.0, 0.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_nearest_segment_without_segments() {
+        assert!(Network::empty().nearest_segment(&InertialCoord::new(0.0, 0.0, 0.0)).is_none());
+    }
+
+    #[rstest]
+    #[case(0.0, 0.0)]
+    #[case(126.0, 0.0)]
+    #[case(504.0, 0.0)]
+    fn test_heading_at_logical_straight(#[case] distance:f64, #[case] expected:f64) {
+        let dbfile = "data/tests/LoadFromDB/onelink.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let coord = LogicalCoord::new(LogicalAddress::for_link(1), 0.0, distance, 0.0);
+        assert_eq!(Some(expected), network.heading_at_logical(&coord));
+        assert_eq!(None, network.heading_at_logical(&LogicalCoord::new(LogicalAddress::for_link(1), 0.0, 504.5, 0.0)));
+    }
+
+    #[rstest]
+    #[case(300.0, 90.0, 0.0, 300.0)]
+    #[case(300.0, 90.0, 25.0, 315.0)]
+    #[case(300.0, 90.0, 50.0, 330.0)]
+    #[case(300.0, 90.0, 150.0, 30.0)]
+    #[case(0.0, 90.0, 100.0, 60.0)]
+    #[case(30.0, -90.0, 25.0, 15.0)]
+    #[case(30.0, -90.0, 150.0, 300.0)]
+    fn test_heading_at_logical_arc(#[case] heading:f64, #[case] sweep:f64, #[case] distance:f64, #[case] expected:f64) {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        sut.create_link();
+        // A radius of 300/PI turns the heading one degree every 5/3 metres.
+        sut.add_arc(InertialCoord::new(0.0, 0.0, 0.0), heading, 300.0 / std::f64::consts::PI, sweep);
+        let network = sut.build();
+        let segment = network.first_segment_for_link(network.get_link(1)).unwrap();
+        assert!((segment.heading_at(distance) - expected).abs() < 1e-9, "{} != {}", segment.heading_at(distance), expected);
+        let actual = network.heading_at_logical(&LogicalCoord::new(LogicalAddress::for_link(1), 0.0, distance, 0.0)).unwrap();
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[rstest]
+    #[case(LogicalAddress::for_link(1), -1.0)]
+    #[case(LogicalAddress::for_link(1), 504.5)]
+    #[case(LogicalAddress::for_link(2), 10.0)]
+    #[case(LogicalAddress::new(Identifier::new(1, 1, 1, 0), Mask::new(true, true, true, false)), 300.0)]
+    fn test_network_logical_to_inertial_off_network(#[case] addr:LogicalAddress, #[case] distance:f64) {
+        let dbfile = "data/tests/LoadFromDB/onelink.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert!(network.logical_to_inertial(&LogicalCoord::new(addr, 0.0, distance, 0.0)).is_none());
+    }
+
+    #[test]
+    fn test_network_builder_connect() {
+        let mut sut = NetworkBuilder::new();
+        for _ in 1..=5 {
+            sut.add_junction();
+        }
+        // A crossroads at junction 1 with an arm out to each of 2 to 5 and a link back from the north.
+        assert_eq!(1, sut.connect(1, 2, 0));
+        assert_eq!(2, sut.connect(1, 3, 90));
+        assert_eq!(3, sut.connect(1, 4, 180));
+        assert_eq!(4, sut.connect(1, 5, 270));
+        assert_eq!(5, sut.connect(2, 1, 180));
+        let network = sut.build();
+        assert_eq!(5, network.num_links());
+        assert_eq!(5, network.num_junctions());
+        assert_eq!(vec![(1, 0), (2, 90), (3, 180), (4, 270)], network.get_junc(1).borrow().exits().collect::<Vec<(u16, u32)>>());
+        let degrees:Vec<usize> = (1..=5).map(|junc| network.neighbors(junc).len()).collect();
+        assert_eq!(vec![5, 2, 1, 1, 1], degrees);
+        assert_eq!((Some(2), Some(1)), (network.get_link(5).origin, network.get_link(5).destination));
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(1, 0)]
+    #[case(1, 3)]
+    #[case(3, 1)]
+    #[should_panic(expected = "can't connect junction")]
+    fn test_network_builder_connect_missing_junction(#[case] from:u32, #[case] to:u32) {
+        let mut sut = NetworkBuilder::new();
+        sut.add_junction();
+        sut.add_junction();
+        sut.connect(from, to, 0);
+    }
+
+    #[rstest]
+    #[case(0, 0.0, SegmentType::Straight)]
+    #[case(1, 75.0, SegmentType::Arc { radius: 75.0 })]
+    #[case(7, 0.0, SegmentType::Unknown)]
+    fn test_segment_type_from_field(#[case] field:i32, #[case] radius:f64, #[case] expected:SegmentType) {
+        assert_eq!(expected, Segment::segment_type_from_field(field, radius));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 1)]
+    #[case("data/tests/LoadFromDB/onelink.db", 1)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 2)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 2)]
+    fn test_create_network_from_db_links(#[case] dbfile:&str, #[case] num_links:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(num_links, network.num_links());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 2, 1, 1)]
+    #[case("data/tests/LoadFromDB/onelink.db", 2, 2, 1)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 3, 2, 2)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 3, 3, 1)]
+    fn test_create_network_from_db_junctions(#[case]dbfile:&str, #[case] num_juncs:usize, #[case] junc_id:u32, #[case] num_links:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        assert_eq!(num_juncs, network.num_junctions());
+        assert_eq!(num_links, network.get_junc_mut(junc_id).borrow().num_links());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 2)]
+    fn test_create_network_from_db_tiles(#[case] dbfile:&str, #[case] num_tiles:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(num_tiles, network.num_tiles());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 2)]
+    fn test_create_network_from_db_segments(#[case] dbfile:&str, #[case] num_segments:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(num_segments, network.num_segments());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 1, 1, 2, true, Some((0, 1, 1)))]
+    #[case("data/tests/LoadFromDB/twolinks.db", 1, 1, 2, true, Some((0, 1, 1)))]
+    #[case("data/tests/LoadFromDB/twolinks.db", 1, 1, 3, true, Some((0, 1, 2)))]
+    #[case("data/tests/LoadFromDB/twolinks.db", 2, 1, 3, true, Some((0, 2, 2)))]
+    #[case("data/tests/LoadFromDB/twolinks.db", 3, 3, 1, true, Some((180, 2, 1)))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 2, 2, 1, true, Some((180, 1, 1)))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 4, 1, true, Some((180, 3, 1)))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 5, 5, 6, true, Some((270, 4, 5)))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 6, 6, 3, true, Some((90, 5, 2)))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, 3, 3, true, None)]
+    fn test_routing(#[case] dbfile:&str, #[case] junc_id:u32, #[case] source_junc:u32, #[case] dest_junc: u32, #[case] to_dest:bool, #[case] expected:Option<(u32, u16, u16)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+
+        let actual = network.route(junc_id, source_junc, dest_junc, to_dest);
+        assert_eq!(expected.is_some(), actual.is_some());
+        if let (Some(actual), Some((next_exit, next_link, dest_link))) = (actual, expected) {
+            assert_eq!(dest_junc, actual.dest_junc);
+            assert_eq!(next_exit, actual.exit());
+            assert_eq!(LogicalAddress::for_link(next_link), actual.next_hop());
+            assert_eq!(LogicalAddress::for_link(dest_link), actual.destination());
+        }
+
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_routing_matches_serial() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let snapshot = network.routing_snapshot();
+        let serial = snapshot.all_pairs_hops();
+        assert!(!serial.is_empty());
+        assert!(serial == snapshot.all_pairs_hops_parallel());
+        assert!(serial == network.routing.borrow().hops);
+    }
+
+    #[rstest]
+    #[case(ExitOrdering::Clockwise, 1, 1)]
+    #[case(ExitOrdering::CounterClockwise, 1, 3)]
+    #[case(ExitOrdering::Clockwise, -1, 3)]
+    #[case(ExitOrdering::CounterClockwise, -1, 1)]
+    #[case(ExitOrdering::Clockwise, 2, 0)]
+    #[case(ExitOrdering::CounterClockwise, 2, 0)]
+    fn test_find_relative_exit_ordering(#[case] ordering:ExitOrdering, #[case] relative_exit:i32, #[case] expected:usize) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let mut junc = network.get_junc(2).borrow().clone();
+        junc.set_exit_ordering(ordering);
+        assert_eq!(ordering, junc.exit_ordering());
+        // Entering from link 1 at the south exit.
+        let entry = junc.find_entry(0.0);
+        assert_eq!(2, entry);
+        assert_eq!(expected, junc.find_relative_exit(entry, relative_exit));
+    }
+
+    #[rstest]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 2, 0)])]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 2, 1)])]
+    #[case("3 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 1, 3)])]
+    #[case("9 -1.825 200.0 1 Relative:Straight Count:1", vec![])]
+    fn test_evaluate_route_detailed(#[case] input:&str, #[case] expected:Vec<(u32, usize, usize)>) {
+        let dbfile = "data/tests/LoadFromDB/crossroads.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        let actual = network.evaluate_route_detailed(&route);
+        assert_eq!(expected, actual);
+        let simple:Vec<(u32, usize)> = actual.iter().map(|(junc, _, exit)| (*junc, *exit)).collect();
+        assert_eq!(network.evaluate_route_simple(&route), simple);
+        if let Some((junc, entry, _)) = actual.first() {
+            let RouteStart::Link(start_link) = route.start else { panic!("expected a link start") };
+            let incoming = network.last_segment_for_link(network.get_link(start_link)).unwrap().h;
+            assert_eq!(Junction::reciprocal(incoming as u32), network.get_junc(*junc).borrow().exit_heading(*entry).unwrap());
+        }
+    }
+
+    #[rstest]
+    #[case(ExitOrdering::Clockwise, vec![(2, 1)])]
+    #[case(ExitOrdering::CounterClockwise, vec![(2, 3)])]
+    fn test_evaluate_route_exit_ordering(#[case] ordering:ExitOrdering, #[case] expected:Vec<(u32, usize)>) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        network.set_exit_ordering(ordering);
+        let route = Route::parse("1 -1.825 200.0 1 Exit:1 Count:1");
+        assert_eq!(expected, network.evaluate_route_simple(&route));
+    }
+
+    #[rstest]
+    #[case(false, None, None)]
+    #[case(true, Some(180), Some(180))]
+    fn test_reciprocal_exits(#[case] reciprocal_exits:bool, #[case] from_3:Option<u32>, #[case] from_2:Option<u32>) {
+        let dbfile = "data/tests/LoadFromDB/forwardonly.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let options = NetworkImportOptions { reciprocal_exits, ..NetworkImportOptions::default() };
+        let network = Network::from_with_options(&connection, options);
+        // Forward routing works either way.
+        assert_eq!(Some(0), network.route(1, 1, 3, true).map(|hop| hop.exit()));
+        assert_eq!(from_3, network.route(3, 3, 1, true).map(|hop| hop.exit()));
+        assert_eq!(from_2, network.route(2, 2, 1, true).map(|hop| hop.exit()));
+        if reciprocal_exits {
+            assert_eq!(vec![(2, 0), (1, 180)], network.get_junc(2).borrow().exits().collect::<Vec<(u16, u32)>>());
+        }
+    }
+
+    #[test]
+    fn test_recompute_exit_headings() {
+        let dbfile = "data/tests/LoadFromDB/crossroads.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        let exits = |network:&Network| -> Vec<Vec<(u16, u32)>> { network.junctions().map(|junc| junc.borrow().exits().collect()).collect() };
+        let loaded = exits(&network);
+        let heading = |network:&Network| network.route(2, 2, 3, true).map(|hop| hop.exit());
+        let routed = heading(&network);
+        // Stale headings are replaced and the exits put back in heading order.
+        for junc in network.junctions() {
+            for exit in junc.borrow_mut().links.iter_mut() {
+                exit.exit = (exit.exit + 45) % 360;
+            }
+        }
+        network.rebuild_routing();
+        assert_ne!(loaded, exits(&network));
+        assert_ne!(routed, heading(&network));
+        network.recompute_exit_headings();
+        assert_eq!(loaded, exits(&network));
+        // Routing picks up the new headings too.
+        assert_eq!(routed, heading(&network));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 0)])]
+    // Straight on from link 1 onto link 2 is banned, so the route bears left instead.
+    #[case("data/tests/LoadFromDB/restricted.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/restricted.db", "1 -1.825 200.0 1 Relative:BearLeft Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/restricted.db", "1 -1.825 200.0 1 Compass:North Count:1", vec![])]
+    #[case("data/tests/LoadFromDB/restricted.db", "1 -1.825 200.0 1 Exit:1 Count:1", vec![])]
+    fn test_turn_restrictions(#[case] dbfile:&str, #[case] input:&str, #[case] expected:Vec<(u32, usize)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let route = Route::parse(input);
+        assert_eq!(expected, Network::from(&connection).evaluate_route_simple(&route));
+        assert_eq!(expected, Network::from_optimized(&connection).evaluate_route_simple(&route));
+    }
+
+    #[test]
+    fn test_turn_restriction_gateway() {
+        let connection = Connection::open("data/tests/LoadFromDB/restricted.db").unwrap();
+        assert_eq!(vec![(2, 1, 2)], TurnRestrictionGateway::new(&connection).find_all().unwrap());
+        let junc = Network::from(&connection).get_junc(2);
+        let junc = junc.borrow();
+        assert!(junc.is_turn_restricted(1, 0));
+        assert!(!junc.is_turn_restricted(1, 2));
+        assert!(!junc.is_turn_restricted(0, 1));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", 1, 3, 3, vec![vec![1, 2, 3]])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 1, 3, 1, vec![])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 4, 4, 3, vec![vec![4]])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 1, 9, 3, vec![])]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 5, 3, vec![vec![1, 2, 3, 5], vec![1, 4, 3, 5]])]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 5, 2, vec![])]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 3, 3, vec![vec![1, 2, 3], vec![1, 4, 3]])]
+    // Reaching junction 4 from 3 means travelling one-way link 3 backwards.
+    #[case("data/tests/LoadFromDB/oneway.db", 3, 4, 3, vec![])]
+    #[case("data/tests/LoadFromDB/oneway.db", 4, 3, 3, vec![vec![4, 2, 3]])]
+    fn test_all_paths(#[case] dbfile:&str, #[case] from:u32, #[case] to:u32, #[case] max_hops:usize, #[case] expected:Vec<Vec<u32>>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let mut paths = network.all_paths(from, to, max_hops);
+        paths.sort();
+        assert_eq!(expected, paths);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", vec![1, 4, 5, 6])]
+    #[case("data/tests/LoadFromDB/crossroads.db", vec![1, 3, 4, 5])]
+    #[case("data/tests/LoadFromDB/nojunctions.db", vec![])]
+    fn test_dead_ends(#[case] dbfile:&str, #[case] expected:Vec<u32>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(expected, network.dead_ends());
+        for junc in network.junctions() {
+            let junc = junc.borrow();
+            assert_eq!(junc.num_links(), junc.degree());
+        }
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", vec![(2, 1)], Some(vec![1, 2, 4]))]
+    // Link 3 only runs from junction 4 to junction 2, so turning left onto it at 2 is refused.
+    #[case("data/tests/LoadFromDB/oneway.db", vec![], None)]
+    fn test_one_way_links(#[case] dbfile:&str, #[case] expected:Vec<(u32, usize)>, #[case] path:Option<Vec<u32>>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(expected, network.evaluate_route_simple(&Route::parse("1 -1.825 200.0 1 Relative:Left Count:1")));
+        assert_eq!(path, network.shortest_path(1, 4));
+        assert_eq!(path, network.fewest_turns_path(1, 4));
+        assert_eq!(path.is_some(), network.route(2, 2, 4, true).is_some());
+        // The permitted direction is unaffected.
+        assert_eq!(Some(vec![4, 2, 1]), network.shortest_path(4, 1));
+        assert!(network.route(4, 4, 1, true).is_some());
+        assert_eq!(vec![(2, 0)], network.evaluate_route_simple(&Route::parse("3 -1.825 200.0 1 Relative:Left Count:1")));
+    }
+
+    #[test]
+    fn test_sync_network_shared_between_threads() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let shared = Arc::new(SyncNetwork::from(&connection));
+        let expected = network.route(2, 2, 6, true).map(|hop| (hop.exit, hop.next_hop));
+        assert!(expected.is_some());
+        let handles:Vec<_> = (0..2).map(|_| {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || shared.route(2, 2, 6, true).map(|hop| (hop.exit, hop.next_hop)))
+        }).collect();
+        for handle in handles {
+            assert_eq!(expected, handle.join().expect("routing thread panicked"));
+        }
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "3 1.825 200.0 -1 Heading:180 Count:2")]
+    #[case("data/tests/LoadFromDB/crossroads.db", "\"Main St\" 0.0 0.0 1 Relative:Left Count:1")]
+    fn test_sync_network_matches_network(#[case] dbfile:&str, #[case] input:&str) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let sync_network = SyncNetwork::from_network(&network);
+        let route = Route::parse(input);
+        assert_eq!(network.num_links(), sync_network.num_links());
+        assert_eq!(network.num_junctions(), sync_network.num_junctions());
+        assert_eq!(network.evaluate_route(&route), sync_network.evaluate_route(&route));
+        assert_eq!(network.neighbors(2), sync_network.neighbors(2));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twolinks.db")]
+    #[case("data/tests/LoadFromDB/fivelinks.db")]
+    fn test_route_matches_hop_scan(#[case] dbfile:&str) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let num_junctions = network.num_junctions() as u32;
+        for junc_id in 1..=num_junctions {
+            for src_junc in 1..=num_junctions {
+                for dest_junc in 1..=num_junctions {
+                    for to_dest in [true, false] {
+                        let target = if to_dest { dest_junc } else { src_junc };
+                        let scanned = network.routing.borrow().hops.iter()
+                            .find(|hop| hop.junction == junc_id && hop.dest_junc == target)
+                            .copied();
+                        let actual = network.route(junc_id, src_junc, dest_junc, to_dest);
+                        assert_eq!(scanned.map(|hop| (hop.exit, hop.next_hop, hop.destination)), actual.map(|hop| (hop.exit, hop.next_hop, hop.destination)),
+                            "route({}, {}, {}, {})", junc_id, src_junc, dest_junc, to_dest);
+                    }
+                }
+            }
+        }
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 2, 1)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 1)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 5, 6)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 6, 3)]
+    fn test_all_pairs_routing_covers_spanning_tree_gaps(#[case] dbfile:&str, #[case] junc_id:u32, #[case] dest_junc:u32) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        network.routing.borrow_mut().clear();
+        network.build_routes();
+        assert!(network.route(junc_id, junc_id, dest_junc, true).is_none());
+        network.build_all_pairs_routing();
+        assert!(network.route(junc_id, junc_id, dest_junc, true).is_some());
+    }
+
+    #[rstest]
+    #[case(90, 270)]
+    #[case(270, 90)]
+    #[case(0, 180)]
+    #[case(180, 0)]
+    #[case(360, 180)]
+    #[case(360+45, 45+180)]
+    fn test_reciprocal_exit(#[case] entry:u32, #[case] reciprocal: u32) {
+        assert_eq!(reciprocal, Junction::reciprocal(entry))
+    }
+
+    #[rstest]
+    #[case(0, 0)]
+    #[case(-1, 359)]
+    #[case(720, 0)]
+    #[case(-720, 0)]
+    #[case(90, 90)]
+    #[case(0, 0)]
+    #[case(-45, 360-45)]
+    fn test_normalise_exit(#[case] input:i32, #[case] normalised:u32) {
+        assert_eq!(normalised, Junction::normalise_exit(input));
+    }
+
+    #[rstest]
+    #[case("1 -1.825 200.0 1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case(" 1  -1.825  200.0 1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Compass:North Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Compass(CompassDirection::North), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Exit:2 Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Exit(2), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Heading:90 Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Heading(90.0), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Always", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Always } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Right Distance:500.0", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Right), count:TurnMultiplicity::Distance(500.0) } ]})]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Relative:Right Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Relative(TurnDirection::Right), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 -1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:-1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("1 -1.825 200.0 1 2", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:2, patterns:vec![]})]
+    #[case("\"Main St\" 0.0 10.0 1 Relative:Left Count:1", Route {start:RouteStart::Place(String::from("Main St")), offset:0.0, distance:10.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Left), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("  \"depot\" 0.0 0.0 -1", Route {start:RouteStart::Place(String::from("depot")), offset:0.0, distance:0.0, trav_dir:-1, lane:0, patterns:vec![]})]
+    #[case("1\t-1.825\t200.0\t1\tRelative:Straight\tCount:1\n", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("1 \t -1.825\n200.0  1\tRelative:Left \t Count:1 \t\r\n", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Left), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:1\t-1\r\n", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:-1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("1\t-1.825\t200.0\t1\t2\n", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:2, patterns:vec![]})]
+    #[case("\"Main St\"\t0.0\t10.0\t1\tRelative:Left\tCount:1\n", Route {start:RouteStart::Place(String::from("Main St")), offset:0.0, distance:10.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Left), count:TurnMultiplicity::Count(1) } ]})]
+    #[case("\n\t\"depot\"\n0.0 0.0\t-1 \n", Route {start:RouteStart::Place(String::from("depot")), offset:0.0, distance:0.0, trav_dir:-1, lane:0, patterns:vec![]})]
+    fn test_parse_route(#[case] input: &str, #[case] route:Route) {
+        let actual = Route::parse(input);
+        assert_eq!(route, actual);
+        assert_eq!(Ok(&actual), Route::try_parse(input).as_ref());
+        assert_eq!(Ok(route), Route::try_parse(&actual.to_string()));
+    }
+
+    #[rstest]
+    #[case("1 -1.825 200.0 1 Relative:Right Distance:500.0", "1 -1.825 200 1 Relative:Right Distance:500")]
+    #[case("1 -1.825 200.0 1 Compass:NorthEast Always Exit:2 Count:3 -1", "1 -1.825 200 1 Compass:NorthEast Always Exit:2 Count:3 -1")]
+    #[case("\"Main St\" 0.0 0.5 -1 Heading:90 Count:1", "\"Main St\" 0 0.5 -1 Heading:90 Count:1")]
+    fn test_display_route(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(expected, Route::parse(input).to_string());
+    }
+
+    #[rstest]
+    #[case("", RouteParseError::MissingStartLink)]
+    #[case("x -1.825 200.0 1", RouteParseError::MissingStartLink)]
+    #[case("\"Main St 0.0 0.0 1", RouteParseError::UnterminatedPlace)]
+    #[case("1 abc 200.0 1", RouteParseError::BadFloat { field:"offset", value:"abc".to_string() })]
+    #[case("1 -1.825 xyz 1", RouteParseError::BadFloat { field:"distance", value:"xyz".to_string() })]
+    #[case("1 -1.825 200.0 forwards", RouteParseError::BadTravDir { value:"forwards".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Sideways Count:1", RouteParseError::BadPattern { token:"Relative:Sideways Count:1".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:x", RouteParseError::BadPattern { token:"Relative:Left Count:x".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Left Distance:NaN", RouteParseError::BadPattern { token:"Relative:Left Distance:NaN".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Left Distance:inf", RouteParseError::BadPattern { token:"Relative:Left Distance:inf".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Left Distance:-10", RouteParseError::BadPattern { token:"Relative:Left Distance:-10".to_string() })]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:1 Compass:North", RouteParseError::BadPattern { token:"Compass:North".to_string() })]
+    fn test_try_parse_route_errors(#[case] input: &str, #[case] expected:RouteParseError) {
+        assert_eq!(Err(expected), Route::try_parse(input));
+    }
+
+    #[rstest]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:1", vec![])]
+    #[case("1 -1.825", vec!["expected distance at position 8, found end of input"])]
+    #[case("1 -1.825 ", vec!["expected distance at position 9, found end of input"])]
+    #[case("1", vec!["expected offset at position 1, found end of input"])]
+    #[case("", vec!["expected start link at position 0, found end of input"])]
+    #[case("  x -1.825 200.0 1", vec!["expected start link at position 2, found 'x'"])]
+    #[case("1 -1.825 abc 1", vec!["expected distance at position 9, found 'abc'"])]
+    #[case("1\t-1.825\t\tabc\n1\n", vec!["expected distance at position 10, found 'abc'"])]
+    #[case("1\t-1.825\r\n", vec!["expected distance at position 10, found end of input"])]
+    #[case("1 abc xyz forwards", vec!["expected offset at position 2, found 'abc'", "expected distance at position 6, found 'xyz'", "expected travel direction at position 10, found 'forwards'"])]
+    #[case("\"Main St 0.0", vec!["expected closing quote at position 12, found end of input", "expected offset at position 12, found end of input"])]
+    #[case("1 -1.825 200.0 1 Relative:Sideways Count:1 Relative:Left Count:1", vec!["expected turn pattern at position 17, found 'Relative:Sideways Count:1'"])]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:1 Compass:North", vec!["expected turn pattern at position 39, found 'Compass:North'"])]
+    fn test_parse_with_diagnostics(#[case] input:&str, #[case] expected:Vec<&str>) {
+        let (route, diagnostics) = Route::parse_with_diagnostics(input);
+        assert_eq!(Route::parse(input), route);
+        assert_eq!(expected, diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect::<Vec<String>>());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_logical_address_serde_round_trip() {
+        let address = LogicalAddress::new(Identifier::new(2, 10, 2, -1), Mask::new(true, true, true, true));
+        let json = serde_json::to_string(&address).expect("failed to serialize address");
+        assert_eq!("\"2.10.2.-1/1.1.1.1\"", json);
+        let actual:LogicalAddress = serde_json::from_str(&json).expect("failed to deserialize address");
+        assert_eq!(address, actual);
+        assert_eq!("\"0.0.1.0\"", serde_json::to_string(&Mask::new(false, false, true, false)).unwrap());
+        assert!(serde_json::from_str::<Identifier>("\"-1.2.3.4\"").is_err());
+        assert!(serde_json::from_str::<Mask>("\"anything\"").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[rstest]
+    #[case("1 -1.825 250.0 1 Relative:Left Count:2 Compass:North Always 2")]
+    #[case("\"Main St\" 0.0 10.0 -1 Exit:-2 Distance:100 Heading:22.5 Count:1")]
+    fn test_route_serde_round_trip(#[case] input:&str) {
+        let route = Route::try_parse(input).expect("failed to parse route");
+        let json = serde_json::to_string(&route).expect("failed to serialize route");
+        let actual:Route = serde_json::from_str(&json).expect("failed to deserialize route");
+        assert_eq!(route, actual);
+        assert_eq!(input.parse::<Route>().map(|route| route.to_string()), Ok(actual.to_string()));
+    }
+
+    #[rstest]
+    #[case("1 -1.825 xyz 1", Route {start:RouteStart::Link(1), offset:-1.825, distance:0.0, trav_dir:1, lane:0, patterns:vec![]})]
+    #[case("1 -1.825 200.0 1 Relative:Sideways Count:1 Relative:Left Count:1", Route {start:RouteStart::Link(1), offset:-1.825, distance:200.0, trav_dir:1, lane:0, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Left), count:TurnMultiplicity::Count(1) } ]})]
+    fn test_parse_route_is_lossy(#[case] input: &str, #[case] route:Route) {
+        assert_eq!(route, Route::parse(input));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twolinks.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/twolinks.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Count:2", vec![(2, 0), (3,0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Right Count:1", vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:UTurn Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always", vec![(2, 0), (3,0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:North Always", vec![(2, 0), (3,0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:West Always", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:East Always", vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:South Always", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Always", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Exit:2 Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Exit:1 Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Exit:-1 Count:1", vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:0", vec![])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:0 Exit:-1 Count:1", vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:0 Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:90 Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:270 Count:1", vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:180 Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Heading:315 Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Heading:22.5 Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Heading:300.5 Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/twolinks.db", "2 1.825 200.0 -1 Heading:180 Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "3 1.825 200.0 -1 Heading:180 Count:1", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "3 1.825 200.0 -1 Heading:180 Count:2", vec![(3, 1), (2, 2)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 200.0 -1 Compass:North Always", vec![(2, 0), (3, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 200.0 -1 Heading:0 Always", vec![(2, 0), (3, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Distance:10.0", vec![(2, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:UTurn Distance:300.0", vec![(2, 0), (3, 1)])]
+    // Junction 3 has no exit to the left.
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Distance:300.0", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Distance:300.0", vec![(2, 0), (3, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Distance:10.0 Relative:UTurn Count:1", vec![(2, 0), (3, 1)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 100.0 -1 Compass:North Distance:50.0", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Relative:Left Count:1 Exit:0 Count:1", vec![(2, 1), (4, 0)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Relative:BearLeft Count:1", vec![(2, 0)])]
+    #[case("data/tests/LoadFromDB/onelink.db", "1 -1.825 200.0 1 Relative:UTurn Always", vec![(2, 0), (1, 0)])]
+    #[case("data/tests/LoadFromDB/ring.db", "1 -1.825 200.0 1 Relative:Straight Always", vec![(2, 0), (1, 0)])]
+    #[case("data/tests/LoadFromDB/ring.db", "1 -1.825 200.0 1 Relative:Straight Always Relative:UTurn Count:1", vec![(2, 0), (1, 0), (2, 1)])]
+    #[case("data/tests/LoadFromDB/ring.db", "1 -1.825 200.0 1 Relative:Straight Count:5", vec![(2, 0), (1, 0), (2, 0), (1, 0), (2, 0)])]
+    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Relative:BearRight Count:1", vec![(2, 2)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "6 -1.825 200.0 1 Relative:Left Count:1", vec![])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "0 -1.825 200.0 1 Relative:Left Count:1", vec![])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "999 -1.825 200.0 1 Relative:Left Count:1", vec![])]
+    #[case("data/tests/LoadFromDB/crossroads.db", "\"Main St\" 0.0 0.0 1 Relative:Left Count:1 Exit:0 Count:1", vec![(2, 1), (4, 0)])]
+    #[case("data/tests/LoadFromDB/crossroads.db", "\"Nowhere\" 0.0 0.0 1 Relative:Left Count:1", vec![])]
+    #[case("data/tests/LoadFromDB/crossroads.db", "\"Unknown\" 0.0 0.0 1 Relative:Left Count:1", vec![])]
+    fn test_evaluate_route(#[case] dbfile: &str, #[case] input: &str, #[case] expected:Vec<(u32, usize)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        let actual = network.evaluate_route_simple(&route);
+        assert_eq!(expected, actual);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/ring.db", "1 -1.825 200.0 1 Relative:Straight Count:100", 3, vec![(2, 0, 0), (1, 0, 0), (2, 0, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always", 5, vec![(2, 0, 0), (3, 0, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always", 0, vec![])]
+    fn test_evaluate_route_limited(#[case] dbfile: &str, #[case] input: &str, #[case] max_steps:usize, #[case] expected:Vec<(u32, usize, i16)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        assert_eq!(expected, network.evaluate_route_limited(&route, max_steps));
+    }
+
+    #[test]
+    fn test_evaluate_routes() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let routes = vec![
+            Route::parse("1 -1.825 200.0 1 Relative:Straight Always"),
+            Route::parse("1 -1.825 200.0 1 Relative:Left Count:1"),
+            Route::parse("3 1.825 200.0 -1 Heading:180 Count:2")
+        ];
+        let actual = network.evaluate_routes(&routes);
+        assert_eq!(routes.len(), actual.len());
+        for (route, steps) in routes.iter().zip(&actual) {
+            assert_eq!(&network.evaluate_route_simple(route), steps, "{}", route);
+        }
+        assert!(network.evaluate_routes(&[]).is_empty());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Count:2")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "3 1.825 200.0 -1 Heading:180 Count:2")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Distance:10.0 Relative:UTurn Count:1")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "9 -1.825 200.0 1 Relative:Straight Always")]
+    fn test_route_steps_matches_evaluate_route(#[case] dbfile: &str, #[case] input: &str) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        let expected:Vec<(u32, usize)> = network.evaluate_route(&route).into_iter().map(|(junc, exit, _)| (junc, exit)).collect();
+        let mut steps = network.route_steps(&route);
+        for step in &expected {
+            assert_eq!(Some(*step), steps.next());
+        }
+        assert_eq!(None, steps.next());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Count:2", vec![(2, 0), (3, 0)], 52.0 + 252.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)], 52.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "3 1.825 200.0 -1 Heading:180 Count:2", vec![(3, 1), (2, 2)], 200.0 + 252.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "9 1.825 200.0 -1 Heading:180 Count:2", vec![], 0.0)]
+    fn test_evaluate_route_with_distance(#[case] dbfile: &str, #[case] input: &str, #[case] expected:Vec<(u32, usize)>, #[case] distance:f64) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        let (steps, actual) = network.evaluate_route_with_distance(&route);
+        assert_eq!(expected, steps);
+        assert!((distance - actual).abs() < 1e-9, "{} != {}", distance, actual);
+    }
+
+    #[rstest]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:2", vec![(2, 0), (3, 0)], 52.0 + 252.0, vec![(2, TurnDirection::Straight), (3, TurnDirection::Straight)], vec![])]
+    #[case("1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)], 52.0, vec![(2, TurnDirection::Left)], vec![])]
+    #[case("9 1.825 200.0 -1 Heading:180 Count:2", vec![], 0.0, vec![], vec!["route start 9 not found"])]
+    #[case("1 -1.825 xyz 1", vec![], 0.0, vec![], vec!["invalid distance xyz"])]
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:3", vec![(2, 0), (3, 0)], 52.0 + 252.0, vec![(2, TurnDirection::Straight), (3, TurnDirection::Straight)], vec!["Relative:Straight Count:3 stopped after 2 of 3 turns: no exit that way at junction 4"])]
+    // Running out of road is how an Always pattern ends.
+    #[case("1 -1.825 200.0 1 Relative:Straight Always", vec![(2, 0), (3, 0)], 52.0 + 252.0, vec![(2, TurnDirection::Straight), (3, TurnDirection::Straight)], vec![])]
+    // The largest count is still a count, and falling short of it is reported.
+    #[case("1 -1.825 200.0 1 Relative:Straight Count:4294967295", vec![(2, 0), (3, 0)], 52.0 + 252.0, vec![(2, TurnDirection::Straight), (3, TurnDirection::Straight)], vec!["Relative:Straight Count:4294967295 stopped after 2 of 4294967295 turns: no exit that way at junction 4"])]
+    fn test_run_route(#[case] input:&str, #[case] steps:Vec<(u32, usize)>, #[case] distance:f64, #[case] instructions:Vec<(u32, TurnDirection)>, #[case] errors:Vec<&str>) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let report = network.run_route(input);
+        assert_eq!(steps, report.steps);
+        assert!((distance - report.distance).abs() < 1e-9, "{} != {}", distance, report.distance);
+        assert_eq!(instructions, report.instructions);
+        assert_eq!(errors, report.errors);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_route_report_serde() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let report = network.run_route("1 -1.825 200.0 1 Relative:Left Count:1");
+        let json = serde_json::to_string(&report).expect("failed to serialize report");
+        assert_eq!(r#"{"steps":[[2,1]],"distance":52.0,"instructions":[[2,"Left"]],"errors":[]}"#, json);
+        let actual:RouteReport = serde_json::from_str(&json).expect("failed to deserialize report");
+        assert_eq!(report, actual);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always", vec![(2, 0, 0), (3, 0, 0)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always 2", vec![(2, 0, 2), (3, 0, 2)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 200.0 -1 Compass:North Always -1", vec![(2, 0, -1), (3, 0, -1)])]
+    fn test_evaluate_route_lanes(#[case] dbfile: &str, #[case] input: &str, #[case] expected:Vec<(u32, usize, i16)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        assert_eq!(expected, network.evaluate_route(&route));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Relative:Left Count:1", "1 -1.825 200 1 Relative:Left Count:1")]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Relative:Right Count:1", "1 -1.825 200 1 Relative:Right Count:1")]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Compass:West Always", "1 -1.825 200 1 Relative:Left Count:1")]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Heading:180 Count:1", "1 -1.825 200 1 Relative:UTurn Count:1")]
+    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Count:2", "1 -1.825 200 1 Relative:Straight Count:2")]
+    #[case("data/tests/LoadFromDB/crossroads.db", "1 -1.825 200.0 1 Relative:Left Count:1 -1", "1 -1.825 200 1 Relative:Left Count:1 -1")]
+    fn test_describe_route(#[case] dbfile: &str, #[case] input: &str, #[case] expected: &str) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let route = Route::parse(input);
+        let steps = network.evaluate_route_simple(&route);
+        let description = route.describe(&network, &steps);
+        assert_eq!(expected, description);
+        let reparsed = Route::parse(&description);
+        assert_eq!(steps, network.evaluate_route_simple(&reparsed));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twolinks.db", vec![(1, Some(1), Some(2)), (2, Some(2), Some(3))], vec![1, 2, 3])]
+    #[case("data/tests/LoadFromDB/crossroads.db", vec![(1, Some(1), Some(2)), (2, Some(2), Some(3)), (3, Some(4), Some(2)), (4, Some(5), Some(2))], vec![1, 2, 3, 4, 5])]
+    fn test_iterate_network(#[case] dbfile:&str, #[case] expected_links:Vec<(u16, Option<u32>, Option<u32>)>, #[case] expected_juncs:Vec<u32>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let links:Vec<(u16, Option<u32>, Option<u32>)> = network.links().map(|link| (link.id(), link.origin(), link.destination())).collect();
+        assert_eq!(expected_links, links);
+        let juncs:Vec<u32> = network.junctions().map(|junc| junc.borrow().id()).collect();
+        assert_eq!(expected_juncs, juncs);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, vec![(2, 0), (3, 90), (1, 180), (4, 270)])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 4, vec![(3, 270)])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, vec![(3, 0), (2, 180)])]
+    fn test_junction_exits(#[case] dbfile:&str, #[case] junc_id:u32, #[case] expected:Vec<(u16, u32)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let junc = network.get_junc(junc_id);
+        let junc = junc.borrow();
+        assert_eq!(junc_id, junc.id());
+        assert_eq!(expected, junc.exits().collect::<Vec<_>>());
+        for (index, (_, heading)) in expected.iter().enumerate() {
+            assert_eq!(Some(*heading), junc.exit_heading(index));
+        }
+        assert_eq!(None, junc.exit_heading(expected.len()));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twolinks.db", 0, false)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 1, true)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 2, true)]
+    #[case("data/tests/LoadFromDB/twolinks.db", 3, false)]
+    fn test_checked_lookups(#[case] dbfile:&str, #[case] id:u16, #[case] exists:bool) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(exists, network.get_link_checked(id).is_some());
+        // twolinks has one more junction than it has links.
+        assert_eq!(exists || id == 3, network.get_junc_checked(id as u32).is_some());
+    }
+
+    #[rstest]
+    #[case(2, 3, 2, Some(3))]
+    #[case(2, 3, 3, Some(2))]
+    #[case(2, 3, 4, None)]
+    #[case(4, 2, 2, Some(4))]
+    fn test_link_other_end(#[case] origin:u32, #[case] destination:u32, #[case] junc:u32, #[case] expected:Option<u32>) {
+        let link = Link::from_query(1, origin, destination, None, 1, false);
+        assert_eq!(expected, link.other_end(junc));
+    }
+
+    #[rstest]
+    #[case("Relative:Straight", Turn::Relative(TurnDirection::Straight))]
+    #[case("Relative:BearLeft", Turn::Relative(TurnDirection::BearLeft))]
+    #[case("Relative:BearRight", Turn::Relative(TurnDirection::BearRight))]
+    #[case("Compass:North", Turn::Compass(CompassDirection::North))]
+    #[case("Compass:NorthEast", Turn::Compass(CompassDirection::NorthEast))]
+    #[case("Compass:East", Turn::Compass(CompassDirection::East))]
+    #[case("Compass:SouthEast", Turn::Compass(CompassDirection::SouthEast))]
+    #[case("Compass:South", Turn::Compass(CompassDirection::South))]
+    #[case("Compass:SouthWest", Turn::Compass(CompassDirection::SouthWest))]
+    #[case("Compass:West", Turn::Compass(CompassDirection::West))]
+    #[case("Compass:NorthWest", Turn::Compass(CompassDirection::NorthWest))]
+    #[case("Heading:90", Turn::Heading(90.0))]
+    #[case("Heading:22.5", Turn::Heading(22.5))]
+    fn test_parse_turn(#[case] input: &str, #[case] turn:Turn) {
+        let actual = input.parse::<Turn>();
+        assert_eq!(turn, actual.unwrap());
+    }
+
+    #[rstest]
+    #[case("Count:1", TurnMultiplicity::Count(1))]
+    #[case("Always", TurnMultiplicity::Always)]
+    #[case("Distance:500.0", TurnMultiplicity::Distance(500.0))]
+    #[case("Distance:250", TurnMultiplicity::Distance(250.0))]
+    fn test_parse_turn_multiplicity(#[case] input: &str, #[case] value:TurnMultiplicity) {
+        let actual: TurnMultiplicity = input.parse().unwrap();
+        assert_eq!(value, actual);
+    }
+
+    #[rstest]
+    #[case("Count:x", "invalid turn count Count:x")]
+    #[case("Count:-1", "invalid turn count Count:-1")]
+    #[case("Distance:x", "invalid turn distance Distance:x")]
+    #[case("Distance:NaN", "invalid turn distance Distance:NaN")]
+    #[case("Distance:inf", "invalid turn distance Distance:inf")]
+    #[case("Distance:-10", "invalid turn distance Distance:-10")]
+    fn test_parse_turn_multiplicity_invalid(#[case] input: &str, #[case] error:&str) {
+        assert_eq!(Err(error.to_string()), input.parse::<TurnMultiplicity>());
+    }
+
+    #[rstest]
+    #[case("Relative:Straight Count:1", TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } )]
+    #[case("Compass:North Count:1", TurningPattern { turn:Turn::Compass(CompassDirection::North), count:TurnMultiplicity::Count(1) } )]
+    #[case("Exit:1 Count:1", TurningPattern { turn:Turn::Exit(1), count:TurnMultiplicity::Count(1) } )]
+    #[case("Exit:-2 Count:0", TurningPattern { turn:Turn::Exit(-2), count:TurnMultiplicity::Count(0) } )]
+    #[case("Heading:90 Count:1", TurningPattern { turn:Turn::Heading(90.0), count:TurnMultiplicity::Count(1) } )]
+    #[case("Heading:22.5 Distance:250", TurningPattern { turn:Turn::Heading(22.5), count:TurnMultiplicity::Distance(250.0) } )]
+    fn test_parse_turning_pattern(#[case] input: &str, #[case] value:TurningPattern) {
+        let actual : TurningPattern = input.parse().unwrap();
+        assert_eq!(value, actual);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 2)]
+    #[case("data/tests/LoadFromDB/nojunctions.db", 0)]
+    fn test_spanning_tree_num_nodes(#[case] dbfile: &str, #[case] num_nodes:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(num_nodes, network.spanning_tree.deref().borrow().num_nodes());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 6, 5, vec![1, 2, 3, 4, 5, 6])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 3, vec![1, 2, 3, 4])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 0, vec![1])]
+    fn test_spanning_tree_depth_and_path(#[case] dbfile: &str, #[case] junc_id:u32, #[case] depth:usize, #[case] path:Vec<u32>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let found:RefCell<Option<Rc<RefCell<SpanningNode>>>> = RefCell::new(None);
+        SpanningNode::depth_first_traversal(network.spanning_tree.clone(), &|node:Rc<RefCell<SpanningNode>>| {
+            if node.borrow().value.upgrade().is_some_and(|junc| junc.borrow().id == junc_id) {
+                *found.borrow_mut() = Some(node.clone());
+            }
+        });
+        let node = found.into_inner().unwrap();
+        assert_eq!(depth, node.borrow().depth());
+        assert_eq!(path, SpanningNode::path_to_root(&node));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, vec![(1, 1), (3, 2), (4, 3), (5, 4)])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 4, vec![(2, 3)])]
+    #[case("data/tests/LoadFromDB/crossroads.db", 6, vec![])]
+    fn test_neighbors(#[case] dbfile: &str, #[case] junc_id:u32, #[case] neighbors:Vec<(u32, u16)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(neighbors, network.neighbors(junc_id));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twocomponents.db", vec![vec![1, 2], vec![3, 4, 5]], false)]
+    #[case("data/tests/LoadFromDB/crossroads.db", vec![vec![1, 2, 3, 4, 5]], true)]
+    #[case("data/tests/LoadFromDB/nojunctions.db", vec![], true)]
+    fn test_connected_components(#[case] dbfile: &str, #[case] components:Vec<Vec<u32>>, #[case] connected:bool) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(components, network.connected_components());
+        assert_eq!(connected, network.is_connected());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/broken.db", vec![
+        NetworkWarning::DanglingLinkEndpoint { link:2, junction:9 },
+        NetworkWarning::EmptyLink { link:3 },
+        NetworkWarning::DanglingExit { junction:1, link:8 },
+        NetworkWarning::OrphanTile { tile:3 },
+        NetworkWarning::OrphanTile { tile:4 },
+        NetworkWarning::UnreachableJunction { junction:3 },
+        NetworkWarning::UnreachableJunction { junction:4 },
+    ])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", vec![])]
+    fn test_validate(#[case] dbfile: &str, #[case] warnings:Vec<NetworkWarning>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(warnings, network.validate());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/places.db", "depot", Some((1, 2.0, 10.0, 0.5)))]
+    #[case("data/tests/LoadFromDB/places.db", "beyond", None)]
+    #[case("data/tests/LoadFromDB/places.db", "nowhere", None)]
+    fn test_resolve_place(#[case] dbfile: &str, #[case] name:&str, #[case] expected:Option<(u16, f64, f64, f64)>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let place = network.find_place(name);
+        assert!(place.is_some());
+        let actual = network.resolve_place(place.unwrap());
+        assert_eq!(expected.is_some(), actual.is_some());
+        if let (Some(actual), Some((link, offset, distance, loft))) = (actual, expected) {
+            assert_eq!(LogicalAddress::for_link(link), actual.addr);
+            assert_eq!(offset, actual.offset);
+            assert_eq!(distance, actual.distance);
+            assert_eq!(loft, actual.loft);
+        }
+        let beyond = Place::new("beyond", 1, 0.0, network.link_length(network.get_link(1)) + 1.0, 0.0);
+        assert!(network.resolve_place(&beyond).is_none());
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/roads.db", RoadID::new(1, 0), vec![1, 2, 3])]
+    #[case("data/tests/LoadFromDB/roads.db", RoadID::new(2, 0), vec![4])]
+    #[case("data/tests/LoadFromDB/roads.db", RoadID::new(3, 0), vec![])]
+    #[case("data/tests/LoadFromDB/fivelinks.db", RoadID::new(1, 0), vec![])]
+    fn test_links_for_road(#[case] dbfile: &str, #[case] road:RoadID, #[case] links:Vec<u16>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let actual:Vec<u16> = network.links_for_road(road).iter().map(|link| link.id()).collect();
+        assert_eq!(links, actual);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/lanes.db", 1, 2)]
+    #[case("data/tests/LoadFromDB/lanes.db", 2, 1)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 1, 1)]
+    fn test_link_lane_count(#[case] dbfile:&str, #[case] link_id:u16, #[case] lane_count:i16) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(lane_count, network.get_link(link_id).lane_count());
     }
 
     #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 1)]
-    #[case("data/tests/LoadFromDB/onelink.db", 1)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 2)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 2)]
-    fn test_create_network_from_db_links(#[case] dbfile:&str, #[case] num_links:usize) {
+    #[case("data/tests/LoadFromDB/lanes.db", 2, 1, 1, 0)]
+    #[case("data/tests/LoadFromDB/lanes.db", 2, 1, 2, 2)]
+    #[case("data/tests/LoadFromDB/lanes.db", 2, 1, 3, usize::MAX)]
+    fn test_find_exit_for_lane(#[case] dbfile:&str, #[case] junc_id:u32, #[case] entry_index:usize, #[case] lane:i16, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
-        assert_eq!(num_links, network.num_links());
+        let junc = &network.get_junc(junc_id).borrow().clone();
+        assert_eq!(exit_index, junc.find_exit_for_lane(entry_index, lane));
     }
 
     #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 2, 1, 1)]
-    #[case("data/tests/LoadFromDB/onelink.db", 2, 2, 1)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 3, 2, 2)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 3, 3, 1)]
-    fn test_create_network_from_db_junctions(#[case]dbfile:&str, #[case] num_juncs:usize, #[case] junc_id:u32, #[case] num_links:usize) {
-        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
-        let mut network = Network::from(&connection);
-        assert_eq!(num_juncs, network.num_junctions());
-        assert_eq!(num_links, network.get_junc_mut(junc_id).borrow().num_links());
+    #[case(2, 2)]
+    // Lanes with no exit of their own take the one fed by any lane, never the entry.
+    #[case(1, 1)]
+    #[case(0, 1)]
+    fn test_find_exit_for_any_lane(#[case] lane:i16, #[case] exit_index:usize) {
+        let mut junc = Junction::new(1);
+        junc.add_link(1, 0, 0);
+        junc.add_link(2, 90, 0);
+        junc.add_link(3, 180, 2);
+        assert_eq!(exit_index, junc.find_exit_for_lane(0, lane));
     }
 
     #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 2)]
-    fn test_create_network_from_db_tiles(#[case] dbfile:&str, #[case] num_tiles:usize) {
+    #[case("data/tests/LoadFromDB/twolinks.db", vec!["digraph network {", "    1 [label=\"1\"];", "    2 [label=\"2\"];", "    3 [label=\"3\"];", "    1 -> 2 [label=\"1 (0)\"];", "    2 -> 3 [label=\"2 (0)\"];", "}"])]
+    #[case("data/tests/LoadFromDB/broken.db", vec!["    2 -> 9 [label=\"2 (0)\"];", "    3 -> 4 [label=\"3 (0)\"];"])]
+    fn test_to_dot(#[case] dbfile:&str, #[case] lines:Vec<&str>) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
-        assert_eq!(num_tiles, network.num_tiles());
+        let dot = network.to_dot();
+        let actual:Vec<&str> = dot.lines().collect();
+        for line in lines {
+            assert!(actual.contains(&line), "missing {} in {}", line, dot);
+        }
     }
 
-    #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 2)]
-    fn test_create_network_from_db_segments(#[case] dbfile:&str, #[case] num_segments:usize) {
+    #[test]
+    fn test_to_geojson() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
-        assert_eq!(num_segments, network.num_segments());
+        let json:serde_json::Value = serde_json::from_str(&network.to_geojson()).expect("invalid GeoJSON");
+        assert_eq!("FeatureCollection", json["type"]);
+        let features = json["features"].as_array().expect("features should be an array");
+        assert_eq!(network.num_links() + network.num_junctions(), features.len());
+        let link = features.iter().find(|feature| feature["properties"]["kind"] == "link").expect("no link features");
+        assert_eq!("LineString", link["geometry"]["type"]);
+        assert!(link["geometry"]["coordinates"].as_array().is_some_and(|coords| coords.len() >= 2));
     }
 
-    #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 1, 1, 2, true, true, 0)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 1, 1, 2, true, true, 0)]
-    #[case("data/tests/LoadFromDB/twolinks.db", 1, 1, 3, true, true, 0)]
-    fn test_routing(#[case] dbfile:&str, #[case] junc_id:u32, #[case] source_junc:u32, #[case] dest_junc: u32, #[case] to_dest:bool, #[case] exists:bool, #[case] next_exit:u32) {
+    #[test]
+    fn test_to_geojson_not_finite() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        let first = network.link_segments[&1][0];
+        network.segments[first].x = f64::NAN;
+        let json:serde_json::Value = serde_json::from_str(&network.to_geojson()).expect("invalid GeoJSON");
+        let geometry = |kind:&str, id:u64| json["features"].as_array().unwrap().iter()
+            .find(|feature| feature["properties"]["kind"] == kind && feature["properties"]["id"] == id)
+            .map(|feature| feature["geometry"].clone());
+        // Link 1 and junction 1, which is positioned by link 1's start, can't be written.
+        assert_eq!(Some(serde_json::Value::Null), geometry("link", 1));
+        assert_eq!(Some(serde_json::Value::Null), geometry("junction", 1));
+        assert_eq!(Some("LineString"), geometry("link", 2).as_ref().and_then(|geometry| geometry["type"].as_str()));
+    }
+
+    #[cfg(feature = "geojson")]
+    #[test]
+    fn test_from_geojson_round_trip() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
+        let imported = Network::from_geojson(&network.to_geojson()).expect("failed to import GeoJSON");
+        assert_eq!(network.num_links(), imported.num_links());
+        assert_eq!(network.num_junctions(), imported.num_junctions());
+    }
 
-        let actual = network.route(junc_id, source_junc, dest_junc, to_dest);
-        assert_eq!(exists, actual.is_some());
-        if let Some(actual) = actual {
-            assert_eq!(dest_junc, actual.dest_junc);
-            assert_eq!(next_exit, actual.exit);
-        }
+    #[cfg(feature = "geojson")]
+    #[test]
+    fn test_from_geojson_connectivity() {
+        let json = r#"{"type":"FeatureCollection","features":[
+            {"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,0,0],[0,100,0]]}},
+            {"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,100,0],[-50,100,0],[-100,100,0]]}},
+            {"type":"Feature","geometry":{"type":"Point","coordinates":[0,0,0]}},
+            {"type":"Feature","geometry":{"type":"Point","coordinates":[0,100,0]}},
+            {"type":"Feature","geometry":{"type":"Point","coordinates":[-100,100,0]}}]}"#;
+        let network = Network::from_geojson(json).expect("failed to import GeoJSON");
+        assert_eq!(2, network.num_links());
+        assert_eq!(3, network.num_junctions());
+        assert_eq!((Some(1), Some(2)), (network.get_link(1).origin, network.get_link(1).destination));
+        assert_eq!((Some(2), Some(3)), (network.get_link(2).origin, network.get_link(2).destination));
+        assert_eq!(100.0, network.link_length(network.get_link(2)));
+        let junc = network.get_junc(2);
+        let exits:Vec<(u16, u32)> = junc.borrow().links.iter().map(|exit| (exit.link_id, exit.exit)).collect();
+        assert_eq!(vec![(2, 90), (1, 180)], exits);
+    }
 
+    #[cfg(feature = "geojson")]
+    #[rstest]
+    #[case(NetworkImportOptions::default(), None)]
+    #[case(NetworkImportOptions { snap_distance:0.0, ..NetworkImportOptions::default() }, None)]
+    #[case(NetworkImportOptions { snap_distance:0.01, ..NetworkImportOptions::default() }, Some(2))]
+    fn test_from_geojson_snap_distance(#[case] options:NetworkImportOptions, #[case] destination:Option<u32>) {
+        let json = r#"{"type":"FeatureCollection","features":[
+            {"type":"Feature","geometry":{"type":"LineString","coordinates":[[0,0,0],[0,100,0]]}},
+            {"type":"Feature","geometry":{"type":"Point","coordinates":[0,0,0]}},
+            {"type":"Feature","geometry":{"type":"Point","coordinates":[0.001,100.001,0]}}]}"#;
+        let network = Network::from_geojson_with_options(json, options).expect("failed to import GeoJSON");
+        assert_eq!(Some(1), network.get_link(1).origin);
+        assert_eq!(destination, network.get_link(1).destination);
     }
 
     #[rstest]
-    #[case(90, 270)]
-    #[case(270, 90)]
-    #[case(0, 180)]
-    #[case(180, 0)]
-    #[case(360, 180)]
-    #[case(360+45, 45+180)]
-    fn test_reciprocal_exit(#[case] entry:u32, #[case] reciprocal: u32) {
-        assert_eq!(reciprocal, Junction::reciprocal(entry))
+    #[case(0.0, 90, 1)]
+    #[case(1e-6, 90, 1)]
+    #[case(2.0, 91, 1)]
+    #[case(0.5, 91, usize::MAX)]
+    #[case(2.0, 359, 0)]
+    fn test_find_exit_by_heading_tolerance(#[case] heading_tolerance:f64, #[case] exit_heading:u32, #[case] exit_index:usize) {
+        let dbfile = "data/tests/LoadFromDB/crossroads.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        network.set_import_options(NetworkImportOptions { heading_tolerance, ..NetworkImportOptions::default() });
+        let to = &network.get_junc(2).borrow().clone();
+        assert_eq!(exit_index, network.find_exit_by_heading(to, exit_heading));
     }
 
+    #[cfg(feature = "geojson")]
     #[rstest]
-    #[case(0, 0)]
-    #[case(-1, 359)]
-    #[case(720, 0)]
-    #[case(-720, 0)]
-    #[case(90, 90)]
-    #[case(0, 0)]
-    #[case(-45, 360-45)]
-    fn test_normalise_exit(#[case] input:i32, #[case] normalised:u32) {
-        assert_eq!(normalised, Junction::normalise_exit(input));
+    #[case("not json", ImportError::BadJson { reason:String::new() })]
+    #[case("{\"type\":\"Feature\"}", ImportError::NotFeatureCollection)]
+    #[case("{\"type\":\"FeatureCollection\",\"features\":[{\"type\":\"Feature\",\"geometry\":{\"type\":\"LineString\",\"coordinates\":[[0,0]]}}]}", ImportError::BadFeature { index:0, reason:String::from("LineString needs at least two positions") })]
+    #[case("{\"type\":\"FeatureCollection\",\"features\":[{\"type\":\"Feature\",\"geometry\":{\"type\":\"Polygon\",\"coordinates\":[]}}]}", ImportError::BadFeature { index:0, reason:String::from("unsupported geometry type") })]
+    fn test_from_geojson_errors(#[case] json:&str, #[case] expected:ImportError) {
+        match (Network::from_geojson(json), expected) {
+            (Err(ImportError::BadJson { .. }), ImportError::BadJson { .. }) => {}
+            (Err(actual), expected) => assert_eq!(expected, actual),
+            (Ok(_), expected) => panic!("expected {}", expected)
+        }
+    }
+
+    #[cfg(feature = "geojson")]
+    #[test]
+    fn test_from_geojson_too_many_segments() {
+        let coordinates:Vec<String> = (0..=u16::MAX as u32 + 1).map(|y| format!("[0,{}]", y)).collect();
+        let json = format!(r#"{{"type":"FeatureCollection","features":[
+            {{"type":"Feature","geometry":{{"type":"Point","coordinates":[0,0]}}}},
+            {{"type":"Feature","geometry":{{"type":"LineString","coordinates":[{}]}}}}]}}"#, coordinates.join(","));
+        assert_eq!(Some(ImportError::BadFeature { index:1, reason:String::from("too many segments") }), Network::from_geojson(&json).err());
     }
 
+    #[cfg(feature = "wkt")]
     #[rstest]
-    #[case("1 -1.825 200.0 1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case(" 1  -1.825  200.0 1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Count:1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Compass:North Count:1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Compass(CompassDirection::North), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Exit:2 Count:1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Exit(2), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Heading:90 Count:1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Heading(90), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Always", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Always } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    #[case("1 -1.825 200.0 1 Relative:Straight Count:1 Relative:Right Count:1", Route {start_link:1, offset:-1.825, distance:200.0, trav_dir:1, patterns:vec![TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) }, TurningPattern { turn:Turn::Relative(TurnDirection::Right), count:TurnMultiplicity::Count(1) } ]})] //TurningPattern {turn:Turn::Relative(TurnDirection::STRAIGHT), count:TurnMultiplicity::Once}] })]
-    fn test_parse_route(#[case] input: &str, #[case] route:Route) {
-        let actual = Route::parse(input);
-        assert_eq!(route, actual);
+    #[case("POINT (1 2)", false, vec![(1.0, 2.0, 0.0)])]
+    #[case("point z(1 2 3)", false, vec![(1.0, 2.0, 3.0)])]
+    #[case("LINESTRING (0 0, 0 126.5, -10 126.5)", true, vec![(0.0, 0.0, 0.0), (0.0, 126.5, 0.0), (-10.0, 126.5, 0.0)])]
+    #[case("LINESTRING Z (0 0 1, 3 4 1)", true, vec![(0.0, 0.0, 1.0), (3.0, 4.0, 1.0)])]
+    fn test_parse_wkt(#[case] text:&str, #[case] is_line:bool, #[case] expected:Vec<(f64, f64, f64)>) {
+        let (actual_is_line, positions) = SegmentGateway::parse_wkt(text).expect("failed to parse geometry");
+        assert_eq!(is_line, actual_is_line);
+        assert_eq!(expected, positions.iter().map(|p| (p.x, p.y, p.z)).collect::<Vec<_>>());
     }
 
+    #[cfg(feature = "wkt")]
     #[rstest]
-    #[case("data/tests/LoadFromDB/twolinks.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 0)])]
-    #[case("data/tests/LoadFromDB/twolinks.db", "1 -1.825 200.0 1 Relative:Straight Count:1", vec![(2, 0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Count:2", vec![(2, 0), (3,0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Right Count:1", vec![(2, 3)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:UTurn Count:1", vec![(2, 2)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Straight Always", vec![(2, 0), (3,0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:North Always", vec![(2, 0), (3,0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:West Always", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:East Always", vec![(2, 3)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Compass:South Always", vec![(2, 2)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Relative:Left Always", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Exit:2 Count:1", vec![(2, 0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Exit:1 Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:0 Count:1", vec![(2, 0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:90 Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:270 Count:1", vec![(2, 3)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "1 -1.825 200.0 1 Heading:180 Count:1", vec![(2, 2)])]
-    #[case("data/tests/LoadFromDB/yjunction.db", "1 -1.825 200.0 1 Heading:315 Count:1", vec![(2, 2)])]
-    #[case("data/tests/LoadFromDB/twolinks.db", "2 1.825 200.0 -1 Heading:180 Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/yjunction.db", "3 1.825 200.0 -1 Heading:180 Count:1", vec![(2, 1)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "3 1.825 200.0 -1 Heading:180 Count:2", vec![(3, 1), (2, 2)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 200.0 -1 Compass:North Always", vec![(2, 0), (3, 0)])]
-    #[case("data/tests/LoadFromDB/fivelinks.db", "4 1.825 200.0 -1 Heading:0 Always", vec![(2, 0), (3, 0)])]
-    fn test_evaluate_route(#[case] dbfile: &str, #[case] input: &str, #[case] expected:Vec<(u32, usize)>) {
+    #[case("LINESTRING (0 0)")]
+    #[case("POINT (1 2, 3 4)")]
+    #[case("POINT M (1 2)")]
+    #[case("POLYGON ((0 0, 1 0, 1 1, 0 0))")]
+    #[case("POINT (1 x)")]
+    #[case("POINT (1 2")]
+    fn test_parse_wkt_invalid(#[case] text:&str) {
+        assert!(SegmentGateway::parse_wkt(text).is_none());
+    }
+
+    #[cfg(feature = "wkt")]
+    #[test]
+    fn test_find_all_wkt() {
+        let dbfile = "data/tests/LoadFromDB/wkt.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let segments = SegmentGateway::new(&connection).find_all_wkt().expect("failed to read segments");
+        assert_eq!(vec![(1, 1), (2, 1), (3, 2)], segments.iter().map(|segment| (segment.id, segment.tile)).collect::<Vec<_>>());
+        let actual:Vec<(f64, f64, f64, f64)> = segments.iter().map(|segment| (segment.x, segment.y, segment.h, segment.length)).collect();
+        assert_eq!(vec![(0.0, 0.0, 0.0, 126.0), (0.0, 126.0, 0.0, 126.0), (0.0, 252.0, 0.0, 252.0)], actual);
+    }
+
+    #[cfg(feature = "wkt")]
+    #[test]
+    fn test_load_wkt() {
+        let dbfile = "data/tests/LoadFromDB/wkt.db";
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
-        let route = Route::parse(input);
-        let actual = network.evaluate_route(&route);
-        assert_eq!(expected, actual);
+        assert!(network.load_warnings().is_empty());
+        assert_eq!(3, network.num_segments());
+        assert_eq!(504.0, network.link_length(network.get_link(1)));
+        assert_eq!(3, Network::try_from(&connection).expect("failed to load").num_segments());
     }
 
-    #[rstest]
-    #[case("Relative:Straight", Turn::Relative(TurnDirection::Straight))]
-    #[case("Compass:North", Turn::Compass(CompassDirection::North))]
-    #[case("Compass:NorthEast", Turn::Compass(CompassDirection::NorthEast))]
-    #[case("Compass:East", Turn::Compass(CompassDirection::East))]
-    #[case("Compass:SouthEast", Turn::Compass(CompassDirection::SouthEast))]
-    #[case("Compass:South", Turn::Compass(CompassDirection::South))]
-    #[case("Compass:SouthWest", Turn::Compass(CompassDirection::SouthWest))]
-    #[case("Compass:West", Turn::Compass(CompassDirection::West))]
-    #[case("Compass:NorthWest", Turn::Compass(CompassDirection::NorthWest))]
-    fn test_parse_turn(#[case] input: &str, #[case] turn:Turn) {
-        let actual = input.parse::<Turn>();
-        assert_eq!(turn, actual.unwrap());
+    #[cfg(feature = "wkt")]
+    #[test]
+    fn test_find_all_wkt_too_many_segments() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute("CREATE TABLE segments (id integer primary key, tile_id integer, geometry text)", []).unwrap();
+        let positions:Vec<String> = (0..=u16::MAX as u32 + 1).map(|y| format!("0 {}", y)).collect();
+        connection.execute("INSERT INTO segments (tile_id, geometry) VALUES (1, ?1)", [format!("LINESTRING ({})", positions.join(", "))]).unwrap();
+        assert!(SegmentGateway::new(&connection).find_all().is_err());
     }
 
-    #[rstest]
-    #[case("Count:1", TurnMultiplicity::Count(1))]
-    #[case("Always", TurnMultiplicity::Always)]
-    fn test_parse_turn_multiplicity(#[case] input: &str, #[case] value:TurnMultiplicity) {
-        let actual: TurnMultiplicity = input.parse().unwrap();
-        assert_eq!(value, actual);
+    #[test]
+    fn test_load_bad_row() {
+        let dbfile = "data/tests/LoadFromDB/badrow.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        match Network::try_from(&connection).err() {
+            Some(ImportError::BadRow { table, .. }) => assert_eq!("segments", table),
+            other => panic!("expected a bad row in segments, got {:?}", other)
+        }
+        // The lenient load leaves out the table it couldn't read, says so, and keeps the rest.
+        let network = Network::from(&connection);
+        assert_eq!(1, network.num_links());
+        assert_eq!(2, network.num_tiles());
+        assert_eq!(0, network.num_segments());
+        let tables:Vec<&str> = network.load_warnings().iter().map(|warning| match warning {
+            NetworkWarning::UnreadableTable { table, .. } => *table,
+            warning => panic!("unexpected warning {}", warning)
+        }).collect();
+        assert_eq!(vec!["segments"], tables);
+        assert_eq!(network.load_warnings(), &network.validate()[..1]);
+        // Missing tables aren't warned about.
+        assert!(Network::from(&Connection::open("data/tests/LoadFromDB/fivelinks.db").unwrap()).load_warnings().is_empty());
     }
 
-    #[rstest]
-    #[case("Relative:Straight Count:1", TurningPattern { turn:Turn::Relative(TurnDirection::Straight), count:TurnMultiplicity::Count(1) } )]
-    #[case("Compass:North Count:1", TurningPattern { turn:Turn::Compass(CompassDirection::North), count:TurnMultiplicity::Count(1) } )]
-    #[case("Exit:1 Count:1", TurningPattern { turn:Turn::Exit(1), count:TurnMultiplicity::Count(1) } )]
-    #[case("Heading:90 Count:1", TurningPattern { turn:Turn::Heading(90), count:TurnMultiplicity::Count(1) } )]
-    fn test_parse_turning_pattern(#[case] input: &str, #[case] value:TurningPattern) {
-        let actual : TurningPattern = input.parse().unwrap();
-        assert_eq!(value, value);
+    #[test]
+    fn test_load_not_a_database() {
+        let dbfile = "data/tests/LoadFromDB/notadb.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        // A database that can't be read isn't a database with no tables.
+        assert!(matches!(Network::try_from(&connection), Err(ImportError::BadRow { table:"links", .. })));
+        let network = Network::from(&connection);
+        assert_eq!(0, network.num_links());
+        assert!(!network.load_warnings().is_empty());
+    }
+
+    // The schema of renamed.db, which holds fivelinks.db under other names.
+    fn renamed_schema() -> SchemaMapping {
+        let mut schema = SchemaMapping::new();
+        schema.map_table("links", "roads");
+        schema.map_table("junctions", "nodes");
+        schema.map_table("junctions_links", "node_roads");
+        schema.map_table("tiles", "road_tiles");
+        schema.map_table("segments", "road_segments");
+        schema.map_column("links", "id", "road_id");
+        schema.map_column("links", "origin", "from_node");
+        schema.map_column("links", "destination", "to_node");
+        schema.map_column("junctions", "id", "node_id");
+        schema.map_column("junctions_links", "junc_id", "node_id");
+        schema.map_column("junctions_links", "link_id", "road_id");
+        schema.map_column("tiles", "link_id", "road_id");
+        schema.map_column("segments", "tile_id", "tile");
+        schema
+    }
+
+    #[test]
+    fn test_from_with_schema() {
+        let schema = renamed_schema();
+        assert_eq!("roads", schema.table("links"));
+        assert_eq!("places", schema.table("places"));
+        let expected = Network::from(&Connection::open("data/tests/LoadFromDB/fivelinks.db").unwrap());
+        let dbfile = "data/tests/LoadFromDB/renamed.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        assert_eq!(0, Network::from(&connection).num_links());
+        let actual = Network::from_with_schema(&connection, &schema);
+        let links = |network:&Network| -> Vec<(u16, Option<u32>, Option<u32>, f64)> {
+            network.links().map(|link| (link.id(), link.origin(), link.destination(), network.link_length(link))).collect()
+        };
+        assert_eq!(links(&expected), links(&actual));
+        let exits = |network:&Network| -> Vec<(u32, u16, u32)> {
+            network.junctions().flat_map(|junc| {
+                let junc = junc.borrow();
+                junc.links.iter().map(|exit| (junc.id, exit.link_id, exit.exit)).collect::<Vec<_>>()
+            }).collect()
+        };
+        assert_eq!(exits(&expected), exits(&actual));
+        let route = Route::parse("1 -1.825 200.0 1 Relative:Straight Count:2");
+        assert_eq!(expected.evaluate_route_simple(&route), actual.evaluate_route_simple(&route));
     }
+
     #[rstest]
-    #[case("data/tests/LoadFromDB/onelink.db", 2)]
-    fn test_spanning_tree_num_nodes(#[case] dbfile: &str, #[case] num_nodes:usize) {
+    #[case("data/tests/LoadFromDB/fivelinks.db")]
+    #[case("data/tests/LoadFromDB/places.db")]
+    #[case("data/tests/LoadFromDB/restricted.db")]
+    fn test_try_from_matches_from(#[case] dbfile:&str) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
-        let network = Network::from(&connection);
-        assert_eq!(num_nodes, network.spanning_tree.deref().borrow().num_nodes());
+        let expected = Network::from(&connection);
+        let actual = Network::try_from(&connection).unwrap_or_else(|e| panic!("failed to load {}: {}", dbfile, e));
+        assert_eq!(expected.num_links(), actual.num_links());
+        assert_eq!(expected.num_junctions(), actual.num_junctions());
+        assert_eq!(expected.num_tiles(), actual.num_tiles());
+        assert_eq!(expected.num_segments(), actual.num_segments());
+        assert_eq!(expected.places.len(), actual.places.len());
+    }
+
+    #[test]
+    fn test_spanning_tree_breadth_first_traversal() {
+        let juncs:Vec<Rc<RefCell<Junction>>> = (1..=5).map(|id| Rc::new(RefCell::new(Junction::new(id)))).collect();
+        let root = Rc::new(RefCell::new(SpanningNode::new(Weak::new(), Rc::downgrade(&juncs[0]))));
+        let add_child = |parent:&Rc<RefCell<SpanningNode>>, junc:&Rc<RefCell<Junction>>| {
+            let child = Rc::new(RefCell::new(SpanningNode::new(Rc::downgrade(parent), Rc::downgrade(junc))));
+            parent.borrow_mut().children.push(child.clone());
+            child
+        };
+        let left = add_child(&root, &juncs[1]);
+        let right = add_child(&root, &juncs[2]);
+        add_child(&left, &juncs[3]);
+        add_child(&right, &juncs[4]);
+        let order:RefCell<Vec<u32>> = RefCell::new(vec![]);
+        let record = |node:Rc<RefCell<SpanningNode>>| {
+            if let Some(junc) = node.borrow().value.upgrade() {
+                order.borrow_mut().push(junc.borrow().id);
+            }
+        };
+        SpanningNode::breadth_first_traversal(root.clone(), &record);
+        assert_eq!(vec![1, 2, 3, 4, 5], *order.borrow());
+        order.borrow_mut().clear();
+        SpanningNode::depth_first_traversal(root.clone(), &record);
+        assert_eq!(vec![1, 2, 4, 3, 5], *order.borrow());
+    }
+
+    #[test]
+    fn test_spanning_tree_of_empty_network() {
+        let mut network = Network::empty();
+        network.build_spanning_tree();
+        network.build_routes();
+        network.build_all_pairs_routing();
+        assert_eq!(0, network.spanning_tree.deref().borrow().num_nodes());
+        assert!(network.route(1, 1, 2, true).is_none());
     }
 
     #[rstest]
@@ -1761,6 +5981,10 @@ mod tests {
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 90, 1)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 180, 2)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 270, 3)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 45, usize::MAX)]
+    // A dead end has fewer exits than the network has links.
+    #[case("data/tests/LoadFromDB/crossroads.db", 3, 180, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 3, 90, usize::MAX)]
     fn test_find_exit_by_heading(#[case] dbfile:&str, #[case] to_id:u32, #[case] exit_heading:u32, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
@@ -1770,13 +5994,40 @@ mod tests {
         assert_eq!(exit_index, actual);
     }
 
+    #[rstest]
+    #[case(0.0, 0.0)]
+    #[case(359.5, 359.5)]
+    #[case(360.0, 0.0)]
+    #[case(720.0 + 22.5, 22.5)]
+    #[case(-90.0, 270.0)]
+    #[case(-720.0, 0.0)]
+    #[case(-1e-20, 0.0)]
+    fn test_heading_normalises(#[case] degrees:f64, #[case] normalised:f64) {
+        assert_eq!(normalised, Heading::new(degrees).degrees());
+    }
+
+    #[rstest]
+    #[case(0.0, 180.0, 0, 0.0)]
+    #[case(-45.0, 135.0, 0, 45.0)]
+    #[case(450.0, 270.0, 1, 90.0)]
+    #[case(180.0, 0.0, 1, 180.0)]
+    #[case(270.0, 90.0, 0, 90.0)]
+    fn test_heading_methods(#[case] degrees:f64, #[case] reciprocal:f64, #[case] hemisphere:u32, #[case] delta_from_north:f64) {
+        let heading = Heading::new(degrees);
+        assert_eq!(reciprocal, heading.reciprocal().degrees());
+        assert_eq!(hemisphere, heading.hemisphere());
+        assert_eq!(delta_from_north, Heading::new(0.0).circular_delta(heading));
+    }
+
     #[rstest]
     #[case(0.0, 180.0)]
     #[case(90.0, 270.0)]
     #[case(180.0, 0.0)]
     #[case(270.0, 90.0)]
     fn test_find_reciprocal_heading(#[case] heading:f64, #[case] reciprocal:f64) {
-        assert_eq!(reciprocal, find_reciprocal_heading(heading));
+        #[allow(deprecated)]
+        let actual = find_reciprocal_heading(heading);
+        assert_eq!(reciprocal, actual);
     }
 
     #[rstest]
@@ -1786,6 +6037,8 @@ mod tests {
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 180.0, 0)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 270.0, 1)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 90.0, 3)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 178.0, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 182.0, 0)]
     fn test_find_closest_entry(#[case] dbfile: &str, #[case] junc_id:u32, #[case] heading: f64, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
@@ -1793,6 +6046,37 @@ mod tests {
         assert_eq!(exit_index, junc.find_entry(heading))
     }
 
+    #[rstest]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 350.0, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 359.5, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 10.0, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 80.0, 1)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 320.0, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 300.0, 3)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 315.0, 3)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, 350.0, 0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, 190.0, 1)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, 275.0, 0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 0.0, usize::MAX)]
+    fn test_find_exit_from_heading(#[case] dbfile: &str, #[case] junc_id:u32, #[case] heading: f64, #[case] exit_index:usize) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let junc = &network.get_junc(junc_id).borrow().clone();
+        assert_eq!(exit_index, junc.find_exit_from_heading(heading))
+    }
+
+    #[rstest]
+    #[case(0.0, 0.0, 0.0)]
+    #[case(359.0, 1.0, 2.0)]
+    #[case(1.0, 359.0, 2.0)]
+    #[case(350.0, 180.0, 170.0)]
+    #[case(90.0, 270.0, 180.0)]
+    fn test_heading_difference(#[case] a:f64, #[case] b:f64, #[case] expected:f64) {
+        #[allow(deprecated)]
+        let actual = heading_difference(a, b);
+        assert_eq!(expected, actual);
+    }
+
     #[rstest]
     #[case("data/tests/LoadFromDB/twolinks.db", 2, CompassDirection::North, 0)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::North, 0)]
@@ -1801,9 +6085,18 @@ mod tests {
     #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::East, 3)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::West, 1)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::South, 2)]
+    // Intercardinal directions lie halfway between two crossroads exits, and ties go to the later exit.
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::SouthEast, 3)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::SouthWest, 2)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, CompassDirection::NorthWest, 1)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::North, 0)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::NorthEast, 2)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::East, 2)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::SouthEast, 1)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::South, 1)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::SouthWest, 1)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::West, usize::MAX)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, CompassDirection::NorthWest, 0)]
     fn test_find_exit_from_compass(#[case] dbfile: &str, #[case] junc_id:u32, #[case] dir:CompassDirection, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
@@ -1811,6 +6104,17 @@ mod tests {
         assert_eq!(exit_index, junc.find_exit_from_compass(dir));
     }
 
+    #[test]
+    fn test_compass_headings_are_evenly_spaced() {
+        // Clockwise round the compass, which is decreasing heading.
+        let directions = [CompassDirection::North, CompassDirection::NorthEast, CompassDirection::East, CompassDirection::SouthEast,
+            CompassDirection::South, CompassDirection::SouthWest, CompassDirection::West, CompassDirection::NorthWest];
+        for i in 0..directions.len() {
+            let next = directions[(i + 1) % directions.len()];
+            assert_eq!(Junction::normalise_exit(directions[i].heading() as i32 - 45), next.heading());
+        }
+    }
+
     #[rstest]
     #[case("data/tests/LoadFromDB/twolinks.db", 2, 1, 1, 0)]
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 2, 1, 1)]
@@ -1818,7 +6122,10 @@ mod tests {
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 3, 2, 1)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, 1, 0)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, 2, 2)]
-    fn test_relative_exit(#[case] dbfile:&str, #[case] junc_id:u32, #[case] entry_index:usize, #[case] relative_exit:usize, #[case] exit_index:usize) {
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 2, -1, 3)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 2, -2, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 0, -5, 1)]
+    fn test_relative_exit(#[case] dbfile:&str, #[case] junc_id:u32, #[case] entry_index:usize, #[case] relative_exit:i32, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
         let junc = &network.get_junc(junc_id).borrow().clone();
@@ -1839,6 +6146,11 @@ mod tests {
     #[case("data/tests/LoadFromDB/crossroads.db", 2, 3, TurnDirection::UTurn, 3)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, TurnDirection::Straight, 0)]
     #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, TurnDirection::Right, 2)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, TurnDirection::BearLeft, 0)]
+    #[case("data/tests/LoadFromDB/yjunction.db", 2, 1, TurnDirection::BearRight, 2)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 2, TurnDirection::BearLeft, 0)]
+    #[case("data/tests/LoadFromDB/crossroads.db", 2, 2, TurnDirection::BearRight, 0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 0, TurnDirection::BearLeft, usize::MAX)]
     fn test_find_exit_from_turn_direction(#[case] dbfile:&str, #[case] junc_id:u32, #[case] entry_index:usize, #[case] turn_dir:TurnDirection, #[case] exit_index:usize) {
         let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
         let network = Network::from(&connection);
@@ -1855,7 +6167,9 @@ mod tests {
     #[case(360, 0)]
     #[case(90, 1)]
     fn test_hemisphere(#[case] angle: u32, #[case] hemi:u32) {
-        assert_eq!(hemi, hemisphere(angle))
+        #[allow(deprecated)]
+        let actual = hemisphere(angle);
+        assert_eq!(hemi, actual)
     }
 
     #[rstest]
@@ -1879,4 +6193,266 @@ mod tests {
         let network = Network::from(&connection);
         assert_eq!(heading, network.last_segment_for_link(network.get_link(link_id)).unwrap().h);
     }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twolinks.db")]
+    #[case("data/tests/LoadFromDB/fivelinks.db")]
+    #[case("data/tests/LoadFromDB/crossroads.db")]
+    #[case("data/tests/LoadFromDB/lanes.db")]
+    #[case("data/tests/LoadFromDB/broken.db")]
+    #[case("data/tests/LoadFromDB/nojunctions.db")]
+    fn test_from_optimized_matches_from(#[case] dbfile:&str) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let expected = Network::from(&connection);
+        let actual = Network::from_optimized(&connection);
+        assert_eq!(expected.num_links(), actual.num_links());
+        assert_eq!(expected.num_junctions(), actual.num_junctions());
+        let exits = |network:&Network| -> Vec<(u32, u16, u32, i16)> {
+            network.junctions().flat_map(|junc| {
+                let junc = junc.borrow();
+                junc.links.iter().map(|exit| (junc.id, exit.link_id, exit.exit, exit.lane)).collect::<Vec<_>>()
+            }).collect()
+        };
+        assert_eq!(exits(&expected), exits(&actual));
+    }
+
+    #[test]
+    fn test_segment_index_matches_scan() {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        for link in &network.links {
+            let scanned:Vec<u16> = network.tiles.iter()
+                .filter(|tile| tile.link == link.id)
+                .flat_map(|tile| network.segments.iter().filter(move |segment| segment.tile == tile.id))
+                .map(|segment| segment.id)
+                .collect();
+            assert_eq!(scanned.first().copied(), network.first_segment_for_link(link).map(|segment| segment.id), "link {}", link.id);
+            assert_eq!(scanned.last().copied(), network.last_segment_for_link(link).map(|segment| segment.id), "link {}", link.id);
+            assert_eq!(scanned, network.segments_for_link(link).iter().map(|segment| segment.id).collect::<Vec<u16>>());
+        }
+    }
+
+    #[test]
+    fn test_segments_in_seq_order() {
+        let dbfile = "data/tests/LoadFromDB/sequenced.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let link = network.get_link(4);
+        assert_eq!(vec![4, 7, 5], network.segments_for_link(link).iter().map(|segment| segment.id).collect::<Vec<u16>>());
+        let first = network.first_segment_for_link(link).unwrap();
+        assert_eq!((-300.0, 0.0), (first.x, first.y));
+        let end = Network::segment_end(network.last_segment_for_link(link).unwrap());
+        assert!(end.x.abs() < 1e-9 && (end.y - 2000.0).abs() < 1e-9, "ends at ({}, {})", end.x, end.y);
+        assert_eq!(2300.0, network.link_length(link));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 1, 504.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 504.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 252.0)]
+    #[case("data/tests/LoadFromDB/nolength.db", 1, 0.0)]
+    fn test_link_length(#[case] dbfile:&str, #[case] link_id:u16, #[case] length:f64) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(length, network.link_length(network.get_link(link_id)));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/onelink.db", 1, 0.0, 0.0)]
+    #[case("data/tests/LoadFromDB/onelink.db", 2, 0.0, 504.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 5, -266.0, 266.0)]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 6, 518.0, 266.0)]
+    fn test_junction_position(#[case] dbfile:&str, #[case] junc_id:u32, #[case] x:f64, #[case] y:f64) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        let actual = network.junction_position(junc_id).unwrap();
+        assert!(f64::abs(x - actual.x) < 1e-9);
+        assert!(f64::abs(y - actual.y) < 1e-9);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 4, Some(vec![1, 2, 3, 4]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 6, Some(vec![1, 2, 6]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 5, 6, Some(vec![5, 2, 6]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 4, 1, Some(vec![4, 3, 2, 1]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 3, 3, Some(vec![3]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 7, None)]
+    fn test_a_star_agrees_with_shortest_path(#[case] dbfile:&str, #[case] from:u32, #[case] to:u32, #[case] expected:Option<Vec<u32>>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(expected, network.shortest_path(from, to));
+        assert_eq!(expected, network.a_star(from, to));
+    }
+
+    #[test]
+    fn test_remove_link() {
+        let dbfile = "data/tests/LoadFromDB/crossroads.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        let straight_on = Route::parse("1 -1.825 200.0 1 Relative:Straight Count:1");
+        assert_eq!(vec![(2, 0)], network.evaluate_route_simple(&straight_on));
+        assert!(network.route(2, 2, 3, true).is_some());
+        let closed:Vec<(u16, InertialCoord)> = network.segments_for_link(network.get_link(2)).into_iter()
+            .map(|segment| (segment.tile, Network::segment_end(segment)))
+            .collect();
+        assert!(!closed.is_empty());
+        for (tile, point) in &closed {
+            assert_eq!(Some(*tile), network.nearest_segment(point).map(|(tile, _)| tile));
+        }
+        // Closing the northern arm leaves nowhere to go straight on, and no way to junction 3.
+        network.remove_link(2);
+        // Nor anything left of the road to measure or match onto.
+        assert_eq!(0.0, network.link_length(network.get_link(2)));
+        for (tile, point) in &closed {
+            assert_ne!(Some(*tile), network.nearest_segment(point).map(|(tile, _)| tile));
+            assert!(network.map_match(std::slice::from_ref(point)).iter().all(|(matched, _)| matched != tile));
+        }
+        assert_eq!(Vec::<(u32, usize)>::new(), network.evaluate_route_simple(&straight_on));
+        assert!(network.route(2, 2, 3, true).is_none());
+        assert_eq!(None, network.shortest_path(1, 3));
+        assert_eq!(vec![(3, 90), (1, 180), (4, 270)], network.get_junc(2).borrow().exits().collect::<Vec<(u16, u32)>>());
+        assert_eq!(Vec::<(u16, u32)>::new(), network.get_junc(3).borrow().exits().collect::<Vec<(u16, u32)>>());
+        assert_eq!(None, network.get_link(2).other_end(2));
+        // The rest still routes.
+        assert!(network.route(1, 1, 4, true).is_some());
+        network.remove_link(9);
+    }
+
+    #[rstest]
+    #[case((-20.0, 250.0), (20.0, 300.0), vec![1, 2, 3, 4, 5])]
+    #[case((200.0, 200.0), (300.0, 300.0), vec![5])]
+    #[case((-1.0, -1.0), (1.0, 1.0), vec![1])]
+    // Only where link 1 arrives at junction 2, and where link 4 arrives at junction 5.
+    #[case((-1.0, 250.0), (1.0, 255.0), vec![1])]
+    #[case((-300.0, 260.0), (-250.0, 270.0), vec![4])]
+    #[case((f64::NEG_INFINITY, f64::NEG_INFINITY), (f64::INFINITY, f64::INFINITY), vec![1, 2, 3, 4, 5])]
+    #[case((1000.0, 1000.0), (2000.0, 2000.0), vec![])]
+    fn test_from_region(#[case] min:(f64, f64), #[case] max:(f64, f64), #[case] expected:Vec<u16>) {
+        let dbfile = "data/tests/LoadFromDB/fivelinks.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let region = Network::from_region(&connection, InertialCoord::new(min.0, min.1, 0.0), InertialCoord::new(max.0, max.1, 0.0)).expect("failed to load region");
+        // The same region cut out of a full load.
+        let mut full = Network::from(&connection);
+        let inside = |point:InertialCoord| (min.0..=max.0).contains(&point.x) && (min.1..=max.1).contains(&point.y);
+        let outside:Vec<u16> = full.links()
+            .filter(|link| {
+                let segments = full.segments_for_link(link);
+                !segments.iter().any(|segment| inside(InertialCoord::new(segment.x, segment.y, segment.z)) || inside(Network::segment_end(segment)))
+            })
+            .map(|link| link.id)
+            .collect();
+        for link in outside {
+            full.remove_link(link);
+        }
+        let attached = |network:&Network| -> Vec<u16> {
+            network.links().filter(|link| link.origin().is_some() || link.destination().is_some()).map(|link| link.id()).collect()
+        };
+        assert_eq!(expected, attached(&region));
+        assert_eq!(expected, attached(&full));
+        assert_eq!(expected.iter().map(|id| full.segments_for_link(full.get_link(*id)).len()).sum::<usize>(), region.num_segments());
+        assert_eq!(full.num_segments(), region.num_segments());
+        for id in 1..=full.num_links() as u16 {
+            // Links past the last one in the region have no slot there.
+            let summary = |network:&Network| network.get_link_checked(id)
+                .filter(|link| link.origin().is_some() || link.destination().is_some())
+                .map(|link| (link.origin(), link.destination(), network.link_length(link)));
+            assert_eq!(summary(&full), summary(&region), "link {}", id);
+        }
+        for id in 1..=full.num_junctions() as u32 {
+            let exits = |network:&Network| network.get_junc_checked(id).map(|junc| junc.borrow().exits().collect::<Vec<(u16, u32)>>()).unwrap_or_default();
+            assert_eq!(exits(&full), exits(&region), "junction {}", id);
+        }
+    }
+
+    #[rstest]
+    #[case((-1.0, 250.0), (1.0, 255.0))]
+    #[case((-20.0, 250.0), (20.0, 300.0))]
+    fn test_from_region_with_schema(#[case] min:(f64, f64), #[case] max:(f64, f64)) {
+        let (min, max) = (InertialCoord::new(min.0, min.1, 0.0), InertialCoord::new(max.0, max.1, 0.0));
+        let expected = Network::from_region(&Connection::open("data/tests/LoadFromDB/fivelinks.db").unwrap(), InertialCoord::new(min.x, min.y, 0.0), InertialCoord::new(max.x, max.y, 0.0)).expect("failed to load region");
+        let dbfile = "data/tests/LoadFromDB/renamed.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let actual = Network::from_region_with_schema(&connection, &renamed_schema(), min, max).expect("failed to load region");
+        let links = |network:&Network| -> Vec<(u16, Option<u32>, Option<u32>, f64)> {
+            network.links().map(|link| (link.id(), link.origin(), link.destination(), network.link_length(link))).collect()
+        };
+        assert_eq!(links(&expected), links(&actual));
+        assert_eq!(expected.num_segments(), actual.num_segments());
+    }
+
+    #[test]
+    fn test_from_region_unreadable() {
+        let connection = Connection::open("data/tests/LoadFromDB/notadb.db").unwrap();
+        let everywhere = || (InertialCoord::new(f64::NEG_INFINITY, f64::NEG_INFINITY, 0.0), InertialCoord::new(f64::INFINITY, f64::INFINITY, 0.0));
+        let (min, max) = everywhere();
+        assert!(Network::from_region(&connection, min, max).is_err());
+        let (min, max) = everywhere();
+        let connection = Connection::open("data/tests/LoadFromDB/badrow.db").unwrap();
+        assert!(matches!(Network::from_region(&connection, min, max), Err(ImportError::BadRow { table:"segments", .. })));
+    }
+
+    #[test]
+    fn test_remove_link_detour() {
+        let dbfile = "data/tests/LoadFromDB/twopaths.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        assert_eq!(Some(vec![1, 4, 3, 5]), network.shortest_path(1, 5));
+        let before = network.route(1, 1, 5, true).map(|hop| hop.exit());
+        network.remove_link(3);
+        assert_eq!(Some(vec![1, 2, 3, 5]), network.shortest_path(1, 5));
+        let after = network.route(1, 1, 5, true).map(|hop| hop.exit());
+        assert!(after.is_some());
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_remove_junction() {
+        let dbfile = "data/tests/LoadFromDB/crossroads.db";
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        network.remove_junction(2);
+        for link in network.links() {
+            assert_eq!((None, None), (link.origin(), link.destination()));
+        }
+        for junc in network.junctions() {
+            assert_eq!(0, junc.borrow().degree());
+        }
+        assert!(network.route(1, 1, 3, true).is_none());
+        assert_eq!(None, network.shortest_path(1, 3));
+        network.remove_junction(9);
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 5, 3, Some(vec![1, 4, 3, 5]), Some(vec![1, 2, 3, 5]))]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 5, 2, Some(vec![1, 4, 3, 5]), Some(vec![1, 4, 3, 5]))]
+    // With only one way through, a costly link is still taken.
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 4, 2, Some(vec![1, 2, 3, 4]), Some(vec![1, 2, 3, 4]))]
+    fn test_link_cost(#[case] dbfile:&str, #[case] from:u32, #[case] to:u32, #[case] congested:u16, #[case] before:Option<Vec<u32>>, #[case] after:Option<Vec<u32>>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let mut network = Network::from(&connection);
+        assert_eq!(before, network.shortest_path(from, to));
+        network.set_link_cost(congested, 10.0);
+        assert_eq!(10.0, network.get_link(congested).cost_multiplier());
+        assert_eq!(after, network.shortest_path(from, to));
+        assert_eq!(after, network.a_star(from, to));
+        // Cheap links don't stop A* finding the shortest path.
+        network.set_link_cost(congested, 1.0);
+        network.set_link_cost(1, 0.1);
+        assert_eq!(network.shortest_path(from, to), network.a_star(from, to));
+    }
+
+    #[rstest]
+    #[case("data/tests/LoadFromDB/twopaths.db", 1, 5, Some(vec![1, 4, 3, 5]), Some(vec![1, 2, 3, 5]))]
+    #[case("data/tests/LoadFromDB/twopaths.db", 5, 1, Some(vec![5, 3, 4, 1]), Some(vec![5, 3, 2, 1]))]
+    #[case("data/tests/LoadFromDB/twopaths.db", 4, 2, Some(vec![4, 1, 2]), Some(vec![4, 1, 2]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 4, Some(vec![1, 2, 3, 4]), Some(vec![1, 2, 3, 4]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 1, Some(vec![1]), Some(vec![1]))]
+    #[case("data/tests/LoadFromDB/fivelinks.db", 1, 7, None, None)]
+    fn test_fewest_turns_path(#[case] dbfile:&str, #[case] from:u32, #[case] to:u32, #[case] shortest:Option<Vec<u32>>, #[case] expected:Option<Vec<u32>>) {
+        let connection = Connection::open(dbfile).unwrap_or_else(|e| panic!("failed to open {}: {}", dbfile, e));
+        let network = Network::from(&connection);
+        assert_eq!(shortest, network.shortest_path(from, to));
+        assert_eq!(expected, network.fewest_turns_path(from, to));
+    }
 }